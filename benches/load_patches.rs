@@ -0,0 +1,64 @@
+// Compares loading a synthetic 100k-patch repository on one thread against loading it on all
+// available threads. Run with `cargo bench --bench load_patches 2>/dev/null` (loading logs every
+// patch to stderr).
+use i1::*;
+use std::time::{Duration, Instant};
+
+const N_PATCHES: usize = 100_000;
+const N_BRANCHES: usize = 64;
+
+fn generate(dir: &std::path::Path) {
+  let patch_dir = dir.join(TotalState::get_patch_dir());
+  if patch_dir.exists() && std::fs::read_dir(&patch_dir).unwrap().count() == N_PATCHES {
+    return;
+  }
+  let _ = std::fs::remove_dir_all(&patch_dir);
+  let mut state = TotalState::new();
+  state.add();
+  let root = state.commit().unwrap().1.target_commit;
+  // independent branches off a common root, so that their patches can be processed concurrently
+  for _ in 0..N_BRANCHES {
+    state.checkout(&root).unwrap();
+    for _ in 0..(N_PATCHES - 1) / N_BRANCHES {
+      state.add();
+      state.commit().unwrap();
+    }
+  }
+  while std::fs::read_dir(&patch_dir).unwrap().count() < N_PATCHES {
+    state.add();
+    state.commit().unwrap();
+  }
+}
+
+fn load(n_threads: usize) -> Duration {
+  let pool = rayon::ThreadPoolBuilder::new()
+    .num_threads(n_threads)
+    .build()
+    .unwrap();
+  let start = Instant::now();
+  let state = pool.install(TotalState::new);
+  let elapsed = start.elapsed();
+  assert_eq!(state.commits().count(), N_PATCHES);
+  elapsed
+}
+
+fn main() {
+  let dir = std::env::temp_dir().join("i1_bench_load_patches");
+  std::fs::create_dir_all(&dir).unwrap();
+  std::env::set_current_dir(&dir).unwrap();
+  generate(&dir);
+
+  let n_threads = std::thread::available_parallelism()
+    .map(|n| n.get())
+    .unwrap_or(1);
+  let sequential = load(1);
+  let parallel = load(n_threads);
+  println!("load {} patches, 1 thread:   {:?}", N_PATCHES, sequential);
+  println!(
+    "load {} patches, {} threads: {:?} ({:.2}x)",
+    N_PATCHES,
+    n_threads,
+    parallel,
+    sequential.as_secs_f64() / parallel.as_secs_f64()
+  );
+}
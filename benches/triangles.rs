@@ -0,0 +1,103 @@
+// Compares Generic Join against nested loops on a triangle query over synthetic random graphs:
+// vertices and edges are elements of sorts V and E, with homs `src, tgt : E -> V`. Run with
+// `cargo bench --bench triangles 2>/dev/null`.
+use i1::*;
+use std::time::{Duration, Instant};
+use tinyvec::TinyVec;
+
+// (vertices, edges)
+const GRAPHS: [(usize, usize); 3] = [(100, 1_000), (500, 2_500), (1_000, 5_000)];
+
+const TRIANGLES: &str =
+  "src(e1) = ?a, tgt(e1) = ?b, src(e2) = ?b, tgt(e2) = ?c, src(e3) = ?c, tgt(e3) = ?a";
+
+// xorshift64, so that the graphs are the same from run to run
+struct Rng(u64);
+
+impl Rng {
+  fn below(&mut self, n: usize) -> usize {
+    self.0 ^= self.0 << 13;
+    self.0 ^= self.0 >> 7;
+    self.0 ^= self.0 << 17;
+    (self.0 % n as u64) as usize
+  }
+}
+
+// A state with a random graph committed in a single patch, checked out.
+fn generate(n_vertices: usize, n_edges: usize) -> TotalState {
+  let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+  let mut patch = Patch {
+    target_commit: Uuid::now_v7(),
+    source_commits: TinyVec::new(),
+    ..Default::default()
+  };
+  let add = |patch: &mut Patch, name: Option<&str>, kind: AdditionKind| {
+    let uuid = Uuid::now_v7();
+    patch.universe_patch.additions.insert(uuid);
+    patch.addition_kinds.insert(uuid, kind);
+    if let Some(name) = name {
+      patch
+        .context_patch
+        .additions
+        .insert(vec![name.to_string()], uuid);
+    }
+    uuid
+  };
+  let v = add(&mut patch, Some("V"), AdditionKind::NewSort);
+  let e = add(&mut patch, Some("E"), AdditionKind::NewSort);
+  let hom = AdditionKind::NewHom {
+    domain: e,
+    codomain: v,
+    partial: false,
+    free: false,
+  };
+  let src = add(&mut patch, Some("src"), hom.clone());
+  let tgt = add(&mut patch, Some("tgt"), hom);
+  let vertices: Vec<Uuid> = (0..n_vertices)
+    .map(|_| add(&mut patch, None, AdditionKind::NewEntity(v)))
+    .collect();
+  for _ in 0..n_edges {
+    let edge = add(&mut patch, None, AdditionKind::NewEntity(e));
+    let hom_patch = &mut patch.hom_patch;
+    hom_patch
+      .additions
+      .insert((src, edge), vertices[rng.below(n_vertices)]);
+    hom_patch
+      .additions
+      .insert((tgt, edge), vertices[rng.below(n_vertices)]);
+  }
+  let commit = patch.target_commit;
+  let mut state = TotalState::default();
+  state.index_patch(Uuid::now_v7(), patch);
+  state.process_all_patches();
+  state.checkout(&commit).unwrap();
+  state
+}
+
+fn time(state: &mut TotalState, strategy: JoinStrategy) -> (usize, Duration) {
+  let start = Instant::now();
+  let result = state.query_with(TRIANGLES, strategy).unwrap();
+  (result.rows.len(), start.elapsed())
+}
+
+fn main() {
+  let dir = std::env::temp_dir().join("i1_bench_triangles");
+  std::fs::create_dir_all(&dir).unwrap();
+  std::env::set_current_dir(&dir).unwrap();
+
+  for (n_vertices, n_edges) in GRAPHS {
+    let mut state = generate(n_vertices, n_edges);
+    let (rows, nested_loops) = time(&mut state, JoinStrategy::NestedLoops);
+    let (generic_join_rows, generic_join) = time(&mut state, JoinStrategy::GenericJoin);
+    assert_eq!(rows, generic_join_rows);
+    println!(
+      "{} vertices, {} edges, {} rows: nested loops {:?}, generic join {:?} ({:.1}x)",
+      n_vertices,
+      n_edges,
+      rows,
+      nested_loops,
+      generic_join,
+      nested_loops.as_secs_f64() / generic_join.as_secs_f64()
+    );
+  }
+}
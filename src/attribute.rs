@@ -0,0 +1,213 @@
+use crate::id::*;
+use base64ct::{Base64UrlUnpadded, Encoding};
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use rkyv::{Archive, Deserialize, Serialize};
+
+// The primitive types: built-in elements of the meta-sort S1, each with a fixed UUID (version 8,
+// with "S1" in its first bytes) so that every installation agrees on them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PrimitiveType {
+  String,
+  I64,
+  F64,
+  Bool,
+  Bytes,
+  Timestamp,
+}
+
+impl PrimitiveType {
+  pub const ALL: [PrimitiveType; 6] = [
+    PrimitiveType::String,
+    PrimitiveType::I64,
+    PrimitiveType::F64,
+    PrimitiveType::Bool,
+    PrimitiveType::Bytes,
+    PrimitiveType::Timestamp,
+  ];
+
+  pub fn uuid(&self) -> Uuid {
+    Uuid::from_u128(0x5331_0000_0000_8000_8000_0000_0000_0000 | (*self as u128 + 1))
+  }
+
+  pub fn from_uuid(uuid: &Uuid) -> Option<Self> {
+    Self::ALL.into_iter().find(|t| t.uuid() == *uuid)
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      PrimitiveType::String => "String",
+      PrimitiveType::I64 => "I64",
+      PrimitiveType::F64 => "F64",
+      PrimitiveType::Bool => "Bool",
+      PrimitiveType::Bytes => "Bytes",
+      PrimitiveType::Timestamp => "Timestamp",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Self> {
+    Self::ALL
+      .into_iter()
+      .find(|t| t.name().eq_ignore_ascii_case(name))
+  }
+
+  // Parse a value of this type from its textual form (as printed by `AttrValue`'s `Display`):
+  // bytes are base64url, and timestamps RFC 3339.
+  pub fn parse(&self, text: &str) -> Result<AttrValue, String> {
+    let invalid =
+      |e: &dyn std::fmt::Display| format!("Invalid {}: {:?} ({})", self.name(), text, e);
+    match self {
+      PrimitiveType::String => Ok(AttrValue::String(text.to_string())),
+      PrimitiveType::I64 => text.parse().map(AttrValue::I64).map_err(|e| invalid(&e)),
+      PrimitiveType::F64 => text.parse().map(AttrValue::F64).map_err(|e| invalid(&e)),
+      PrimitiveType::Bool => text.parse().map(AttrValue::Bool).map_err(|e| invalid(&e)),
+      PrimitiveType::Bytes => Base64UrlUnpadded::decode_vec(text)
+        .map(AttrValue::Bytes)
+        .map_err(|e| invalid(&e)),
+      PrimitiveType::Timestamp => DateTime::parse_from_rfc3339(text)
+        .map(|t| AttrValue::Timestamp(t.timestamp_micros()))
+        .map_err(|e| invalid(&e)),
+    }
+  }
+}
+
+// A value of an attribute. Timestamps are microseconds since the Unix epoch, in UTC. Values of
+// types registered by downstream crates are kept as bytes (see `TypeRegistry`).
+#[derive(Clone, Debug, PartialEq, PartialOrd, Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+pub enum AttrValue {
+  String(String),
+  I64(i64),
+  F64(f64),
+  Bool(bool),
+  Bytes(Vec<u8>),
+  Timestamp(i64),
+  Custom { attr_type: Uuid, bytes: Vec<u8> },
+}
+
+impl AttrValue {
+  pub fn primitive_type(&self) -> Option<PrimitiveType> {
+    match self {
+      AttrValue::String(_) => Some(PrimitiveType::String),
+      AttrValue::I64(_) => Some(PrimitiveType::I64),
+      AttrValue::F64(_) => Some(PrimitiveType::F64),
+      AttrValue::Bool(_) => Some(PrimitiveType::Bool),
+      AttrValue::Bytes(_) => Some(PrimitiveType::Bytes),
+      AttrValue::Timestamp(_) => Some(PrimitiveType::Timestamp),
+      AttrValue::Custom { .. } => None,
+    }
+  }
+
+  // The UUID of the type (element of S1) that this value belongs to.
+  pub fn type_uuid(&self) -> Uuid {
+    match self {
+      AttrValue::Custom { attr_type, .. } => *attr_type,
+      _ => self.primitive_type().unwrap().uuid(),
+    }
+  }
+
+  // Numbers and booleans as themselves, and everything else as its textual form.
+  pub fn to_json(&self) -> serde_json::Value {
+    match self {
+      AttrValue::I64(value) => serde_json::json!(value),
+      AttrValue::F64(value) => serde_json::json!(value),
+      AttrValue::Bool(value) => serde_json::json!(value),
+      _ => serde_json::Value::String(self.to_string()),
+    }
+  }
+}
+
+impl std::fmt::Display for AttrValue {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      AttrValue::String(value) => write!(f, "{}", value),
+      AttrValue::I64(value) => write!(f, "{}", value),
+      AttrValue::F64(value) => write!(f, "{}", value),
+      AttrValue::Bool(value) => write!(f, "{}", value),
+      AttrValue::Bytes(value) => write!(f, "{}", Base64UrlUnpadded::encode_string(value)),
+      AttrValue::Timestamp(micros) => match NaiveDateTime::from_timestamp_micros(*micros) {
+        Some(t) => {
+          let t = DateTime::<Utc>::from_utc(t, Utc);
+          write!(f, "{}", t.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+        }
+        None => write!(f, "{}us", micros),
+      },
+      // (displayed properly by the registry of the type)
+      AttrValue::Custom { attr_type, bytes } => write!(
+        f,
+        "{}:{}",
+        attr_type.as_base64url(),
+        Base64UrlUnpadded::encode_string(bytes)
+      ),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn values_round_trip_through_their_textual_form() {
+    let values = [
+      (PrimitiveType::String, "a string, with spaces"),
+      (PrimitiveType::I64, "-9223372036854775808"),
+      (PrimitiveType::F64, "2.5"),
+      (PrimitiveType::Bool, "true"),
+      (PrimitiveType::Bytes, "AP-_aGk"),
+      (PrimitiveType::Bytes, ""),
+      (PrimitiveType::Timestamp, "2024-02-29T12:34:56.789012Z"),
+      (PrimitiveType::Timestamp, "1969-12-31T23:59:59Z"),
+    ];
+    for (primitive_type, text) in values {
+      let value = primitive_type.parse(text).unwrap();
+      assert_eq!(value.primitive_type(), Some(primitive_type));
+      assert_eq!(value.type_uuid(), primitive_type.uuid());
+      assert_eq!(value.to_string(), text);
+      assert_eq!(primitive_type.parse(&value.to_string()), Ok(value));
+    }
+    assert_eq!(
+      PrimitiveType::Bytes.parse("AP-_aGk"),
+      Ok(AttrValue::Bytes(vec![0x00, 0xff, 0xbf, 0x68, 0x69]))
+    );
+    // (timestamps in other offsets are kept in UTC)
+    assert_eq!(
+      PrimitiveType::Timestamp.parse("1970-01-01T01:00:00.000001+01:00"),
+      Ok(AttrValue::Timestamp(1))
+    );
+  }
+
+  #[test]
+  fn invalid_text_is_rejected() {
+    let invalid = [
+      (PrimitiveType::I64, "1.5"),
+      (PrimitiveType::I64, "9223372036854775808"),
+      (PrimitiveType::F64, "one"),
+      (PrimitiveType::Bool, "yes"),
+      (PrimitiveType::Bytes, "not base64!"),
+      // (base64url, not base64)
+      (PrimitiveType::Bytes, "AP+/aGk"),
+      (PrimitiveType::Timestamp, "2024-02-30T00:00:00Z"),
+      (PrimitiveType::Timestamp, "2024-02-29 12:00"),
+    ];
+    for (primitive_type, text) in invalid {
+      let message = primitive_type.parse(text).unwrap_err();
+      assert!(message.starts_with(&format!("Invalid {}", primitive_type.name())));
+    }
+  }
+
+  #[test]
+  fn primitive_types_have_fixed_uuids_and_names() {
+    for primitive_type in PrimitiveType::ALL {
+      assert_eq!(
+        PrimitiveType::from_uuid(&primitive_type.uuid()),
+        Some(primitive_type)
+      );
+      let name = primitive_type.name().to_lowercase();
+      assert_eq!(PrimitiveType::from_name(&name), Some(primitive_type));
+    }
+    assert_eq!(
+      PrimitiveType::I64.uuid().to_string(),
+      "53310000-0000-8000-8000-000000000002"
+    );
+  }
+}
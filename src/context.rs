@@ -1,9 +1,12 @@
 use crate::id::*;
 use std::collections::HashMap;
+use std::sync::Arc;
 
-#[derive(Debug, Clone)]
+// Nodes are shared between versions and copied on write, so a naming change copies only the
+// nodes along its path.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Context {
-  Node(HashMap<String, Context>),
+  Node(Arc<HashMap<String, Context>>),
   Leaf(Luid),
 }
 
@@ -15,18 +18,146 @@ impl Context {
         Some(_) => None,
       },
       Context::Node(map) => {
-        let map = map;
-        let key = path.next()?;
-        let key = key.to_string();
+        // (a name that is also a prefix of other names is kept under the empty key)
+        let key = path.next().map(|key| key.to_string()).unwrap_or_default();
         let context = map.get(&key)?;
         context.get(path)
       }
     }
   }
+
+  // Name `luid` by `path`, replacing whatever was named by it before.
+  pub fn insert(&mut self, path: &[String], luid: Luid) {
+    let Some((key, rest)) = path.split_first() else {
+      match self {
+        Context::Node(map) if !map.is_empty() => {
+          Arc::make_mut(map).insert(String::new(), Context::Leaf(luid));
+        }
+        _ => *self = Context::Leaf(luid),
+      }
+      return;
+    };
+    if let Context::Leaf(own) = *self {
+      *self = Context::Node(Arc::new(HashMap::from([(
+        String::new(),
+        Context::Leaf(own),
+      )])));
+    }
+    let Context::Node(map) = self else {
+      unreachable!()
+    };
+    let child = Arc::make_mut(map)
+      .entry(key.clone())
+      .or_insert_with(Context::default);
+    child.insert(rest, luid);
+  }
+
+  // Remove the naming `path`, if there is one.
+  pub fn remove(&mut self, path: &[String]) -> bool {
+    let Context::Node(map) = self else {
+      return false;
+    };
+    let key = path.first().cloned().unwrap_or_default();
+    let Some(child) = map.get(&key) else {
+      return false;
+    };
+    match (child, path.len()) {
+      (Context::Leaf(_), 0 | 1) => {
+        Arc::make_mut(map).remove(&key);
+        true
+      }
+      (Context::Leaf(_), _) | (Context::Node(_), 0) => false,
+      (Context::Node(_), _) => {
+        let map = Arc::make_mut(map);
+        let removed = map.get_mut(&key).unwrap().remove(&path[1..]);
+        if let Some(Context::Node(child_map)) = map.get(&key) {
+          if child_map.is_empty() {
+            map.remove(&key);
+          }
+        }
+        removed
+      }
+    }
+  }
+
+  // Merge the namings of `other` into `self`; where the two disagree, `self` wins.
+  pub fn union_with(&mut self, other: &Context) {
+    match (&mut *self, other) {
+      (Context::Node(map), Context::Node(other_map)) => {
+        if Arc::ptr_eq(map, other_map) {
+          return;
+        }
+        for (key, other_context) in other_map.iter() {
+          match (map.get(key), other_context) {
+            (None, _) => {
+              Arc::make_mut(map).insert(key.clone(), other_context.clone());
+            }
+            (Some(Context::Leaf(_)), Context::Leaf(_)) => {}
+            (Some(_), _) => {
+              Arc::make_mut(map)
+                .get_mut(key)
+                .unwrap()
+                .union_with(other_context);
+            }
+          }
+        }
+      }
+      (Context::Node(map), Context::Leaf(luid)) => {
+        if !map.contains_key("") {
+          Arc::make_mut(map).insert(String::new(), Context::Leaf(*luid));
+        }
+      }
+      (Context::Leaf(own), Context::Node(_)) => {
+        let own = *own;
+        *self = other.clone();
+        self.insert(&[], own);
+      }
+      (Context::Leaf(_), Context::Leaf(_)) => {}
+    }
+  }
+
+  // All the namings, ordered by name.
+  pub fn namings(&self) -> Vec<(Vec<String>, Luid)> {
+    let mut namings = Vec::new();
+    let mut stack = vec![(Vec::new(), self)];
+    while let Some((path, context)) = stack.pop() {
+      match context {
+        Context::Leaf(luid) => namings.push((path, *luid)),
+        Context::Node(map) => {
+          for (key, child) in map.iter() {
+            let mut child_path = path.clone();
+            if !key.is_empty() {
+              child_path.push(key.clone());
+            }
+            stack.push((child_path, child));
+          }
+        }
+      }
+    }
+    namings.sort();
+    namings
+  }
+
+  // Approximate number of bytes this context occupies in memory, excluding `self`, with shared
+  // nodes apportioned among their owners.
+  pub fn heap_size(&self) -> usize {
+    match self {
+      Context::Leaf(_) => 0,
+      Context::Node(map) => {
+        map
+          .iter()
+          .map(|(key, context)| {
+            key.capacity() + std::mem::size_of::<(String, Context)>() + context.heap_size()
+          })
+          .sum::<usize>()
+          / Arc::strong_count(map)
+      }
+    }
+  }
 }
 
 impl Default for Context {
   fn default() -> Self {
-    Context::Node(HashMap::new())
+    Context::Node(Arc::new(HashMap::new()))
   }
 }
@@ -38,3 +38,17 @@ impl FromBase64Url for Uuid {
     Uuid::from_slice(bytes.as_slice()).map_err(|e| format!("Error decoding UUID format: {}", e))
   }
 }
+
+// The UUID of the term `hom(arg)` (see `AdditionKind::NewTerm`): a hash of the two UUIDs (FNV-1a,
+// marked as a custom UUID), so that a term is the same element wherever it is formed.
+pub fn term_uuid(hom: &Uuid, arg: &Uuid) -> Uuid {
+  let mut hash: u128 = 0x6c62272e07bb014262b821756295c58d;
+  for byte in hom.as_bytes().iter().chain(arg.as_bytes().iter()) {
+    hash ^= *byte as u128;
+    hash = hash.wrapping_mul(0x0000000001000000000000000000013b);
+  }
+  uuid::Builder::from_bytes(hash.to_be_bytes())
+    .with_variant(uuid::Variant::RFC4122)
+    .with_version(uuid::Version::Custom)
+    .into_uuid()
+}
@@ -1,20 +1,69 @@
 use std::path::PathBuf;
 
+mod attribute;
+pub use attribute::{AttrValue, PrimitiveType};
 mod context;
 pub use context::Context;
 mod id;
-pub use id::{AsBase64Url, FromBase64Url, Luid, Slid, Uuid, Vlid};
+pub use id::{term_uuid, AsBase64Url, FromBase64Url, Luid, Slid, Uuid, Vlid};
 mod patch;
-pub use patch::UuidSetPatch;
+pub use patch::{
+  AdditionKind, AttrPatch, ContextPatch, HomPatch, Patch, Rule, RuleAtom, RuleTerm, UuidSetPatch,
+};
+mod query;
+pub use query::{
+  Atom, Bound, Delta, Derived, JoinStrategy, Query, QueryError, QueryResult, QueryValue,
+  ResolvedAtom, ResolvedRule, Term,
+};
+mod shared_treemap;
+pub use shared_treemap::SharedTreemap;
 mod state;
-pub use state::TotalState;
+pub use state::{
+  CherryPickError, CommitError, Conflict, ExportError, ExportFormat, GraphEvent, ImportError,
+  ImportOptions, ImportSummary, MergeError, MergeOutcome, RebaseOutcome, SchemaError, TotalState,
+  VersionCache, Violation,
+};
+mod type_registry;
+pub use type_registry::{AttrType, RkyvAttrValue, RkyvType, TypeError, TypeRegistry};
+mod union_find;
+pub use union_find::UnionFind;
 mod version;
-pub use version::Version;
+pub use version::{Attr, Column, Hom, Version};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CheckoutError {
+  // no such commit, or one whose patches could not be processed
+  NoVersion,
+  // checking out would discard the changes in the working patch
+  WorkingPatchNotEmpty,
+}
+
+impl std::fmt::Display for CheckoutError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      CheckoutError::NoVersion => write!(f, "it has no version"),
+      CheckoutError::WorkingPatchNotEmpty => write!(
+        f,
+        "the working patch is not empty; commit before checking out"
+      ),
+    }
+  }
+}
 
 impl TotalState {
   pub fn new() -> Self {
-    let mut state = Self::default();
+    Self::with_types(TypeRegistry::default())
+  }
+
+  // Load the state, understanding attribute values of the custom types in `types` as well as the
+  // primitive ones. Patches that use any other type are skipped (with their descendants).
+  pub fn with_types(types: TypeRegistry) -> Self {
+    let mut state = Self {
+      types,
+      ..Self::default()
+    };
     state.load_all_patches();
+    state.load_working_patch();
     state
   }
 
@@ -31,27 +80,33 @@ impl TotalState {
       .map(|luid| self.universe.get_index(*luid).unwrap())
   }
 
-  pub fn checkout(&mut self, commit: &Uuid) -> Option<()> {
-    let commit_luid = self.universe.get_index_of(commit)?;
+  pub fn checkout(&mut self, commit: &Uuid) -> Result<(), CheckoutError> {
+    let commit_luid = self
+      .universe
+      .get_index_of(commit)
+      .ok_or(CheckoutError::NoVersion)?;
     self.checkout_luid(commit_luid)
   }
 
-  pub fn checkout_luid(&mut self, commit: Luid) -> Option<()> {
+  // Move HEAD to a commit, making its version the working state. Nothing changes if there is no
+  // such commit, or if the working patch has changes that this would discard.
+  pub fn checkout_luid(&mut self, commit: Luid) -> Result<(), CheckoutError> {
+    self.version(commit).ok_or(CheckoutError::NoVersion)?;
     if !self.working_patch.is_empty() {
-      eprintln!("Error: checkout while working patch is not empty. Commit before checking out.");
+      return Err(CheckoutError::WorkingPatchNotEmpty);
     }
     self.working_patch.clear();
     self
       .working_patch
       .source_commits
       .push(*self.universe.get_index(commit).unwrap());
-    let version = self.version_cache.get(&commit)?;
+    let version = self.version(commit).ok_or(CheckoutError::NoVersion)?;
     self.working_state = version
       .version_universe
       .iter()
       .map(|x| x as usize)
       .collect();
-    Some(())
+    Ok(())
   }
 
   pub fn heads(&self) -> impl Iterator<Item = &Uuid> {
@@ -68,6 +123,30 @@ impl TotalState {
     uuid
   }
 
+  // Message to be recorded with the next commit.
+  pub fn set_message(&mut self, message: &str) {
+    self.working_patch.message = message.to_string();
+  }
+
+  // Name an element in the working patch, replacing whatever was named by `path` before.
+  pub fn name(&mut self, uuid: &Uuid, path: &[String]) {
+    let context_patch = &mut self.working_patch.context_patch;
+    context_patch.deletions.remove(path);
+    context_patch.additions.insert(path.to_vec(), *uuid);
+  }
+
+  // Find an element of the working state by its UUID (in base64url) or else by its name, with the
+  // parts of the name separated by `/`.
+  pub fn lookup(&mut self, text: &str) -> Option<Uuid> {
+    if let Ok(uuid) = Uuid::from_base64url(text) {
+      let luid = self.universe.get_index_of(&uuid)?;
+      return self.working_state.contains(&luid).then_some(uuid);
+    }
+    let version = self.working_version();
+    let luid = version.ctx.get(text.split('/'))?;
+    Some(*self.universe.get_index(luid).unwrap())
+  }
+
   pub fn count(&self) -> usize {
     self.working_state.len()
   }
@@ -79,3 +158,55 @@ impl TotalState {
       .map(move |luid| self.universe.get_index(*luid).unwrap())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn checking_out_a_commit_without_a_version_changes_nothing() {
+    let mut state = TotalState::default();
+    state.add();
+    let commit = state.commit_in_memory();
+    // (a patch from a commit that no patch leads to)
+    let orphan = Uuid::now_v7();
+    let patch = Patch {
+      target_commit: orphan,
+      source_commits: [Uuid::now_v7()].into_iter().collect(),
+      ..Default::default()
+    };
+    state.index_patch(Uuid::now_v7(), patch);
+    let x = state.add();
+    assert_eq!(state.checkout(&orphan), Err(CheckoutError::NoVersion));
+    assert!(state.list().any(|uuid| *uuid == x));
+    // (and the commit that does have one isn't checked out over x either)
+    assert_eq!(
+      state.checkout(&commit),
+      Err(CheckoutError::WorkingPatchNotEmpty)
+    );
+    assert!(state.list().any(|uuid| *uuid == x));
+  }
+
+  #[test]
+  fn checking_out_refuses_to_discard_the_working_patch() {
+    let mut state = TotalState::default();
+    let root = state.commit_in_memory();
+    let x = state.add();
+    state.commit_in_memory();
+    let y = state.add();
+    assert_eq!(
+      state.checkout(&root),
+      Err(CheckoutError::WorkingPatchNotEmpty)
+    );
+    assert!(state.working_patch.universe_patch.additions.contains(&y));
+    let working_state: Vec<Uuid> = state.list().copied().collect();
+    assert_eq!(working_state.len(), 2);
+    assert!(working_state.contains(&x) && working_state.contains(&y));
+    // once committed, HEAD can move
+    let head = state.commit_in_memory();
+    assert_eq!(state.checkout(&root), Ok(()));
+    assert_eq!(state.list().count(), 0);
+    assert_eq!(state.checkout(&head), Ok(()));
+    assert_eq!(state.list().count(), 2);
+  }
+}
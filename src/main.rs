@@ -1,10 +1,106 @@
 use i1::*;
 
-use reedline_repl_rs::clap::{self, builder::TypedValueParser, Arg, Command};
-use reedline_repl_rs::{Error, Repl};
+use reedline_repl_rs::clap::{
+  self, builder::TypedValueParser, error::ErrorKind, Arg, ArgAction, ArgMatches, Command,
+};
+use reedline_repl_rs::{Callback, Repl};
+use serde_json::json;
 use std::sync::{Arc, RwLock};
 use uuid::Uuid;
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+  Text,
+  Json,
+}
+
+struct Session {
+  state: Arc<RwLock<TotalState>>,
+  format: Format,
+}
+
+#[derive(Debug)]
+enum CommandError {
+  Repl(reedline_repl_rs::Error),
+  // a command that ran but failed, with its rendered output
+  Failed(String),
+}
+
+impl From<reedline_repl_rs::Error> for CommandError {
+  fn from(e: reedline_repl_rs::Error) -> Self {
+    CommandError::Repl(e)
+  }
+}
+
+impl std::fmt::Display for CommandError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      CommandError::Repl(e) => write!(f, "{}", e),
+      CommandError::Failed(output) => write!(f, "{}", output),
+    }
+  }
+}
+
+type CommandResult = Result<Option<String>, CommandError>;
+type Handler = Callback<Session, CommandError>;
+
+impl Session {
+  // Render the result of a command either for humans or as a JSON object, for other tools.
+  fn output(
+    &self,
+    text: impl FnOnce() -> String,
+    json: impl FnOnce() -> serde_json::Value,
+  ) -> CommandResult {
+    Ok(Some(self.render(text, json)))
+  }
+
+  fn error(&self, error: impl ToString) -> CommandResult {
+    let message = error.to_string();
+    Err(CommandError::Failed(
+      self.render(|| message.clone(), || json!({ "error": message })),
+    ))
+  }
+
+  fn render(
+    &self,
+    text: impl FnOnce() -> String,
+    json: impl FnOnce() -> serde_json::Value,
+  ) -> String {
+    match self.format {
+      Format::Text => text(),
+      Format::Json => json().to_string(),
+    }
+  }
+}
+
+fn base64url_list<'a>(uuids: impl Iterator<Item = &'a Uuid>) -> Vec<String> {
+  uuids.map(|uuid| uuid.as_base64url()).collect()
+}
+
+fn bullet_list(uuids: &[String]) -> String {
+  uuids
+    .iter()
+    .map(|uuid| format!("* {}", uuid))
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+fn parse_uuid(
+  cmd: &Command,
+  arg: Option<&Arg>,
+  value: &std::ffi::OsStr,
+) -> Result<(String, Uuid), clap::Error> {
+  let inner =
+    reedline_repl_rs::clap::builder::StringValueParser::default().parse(cmd, arg, value.into())?;
+  let uuid = Uuid::from_base64url(&inner).map_err(|_| {
+    clap::Error::raw(
+      clap::error::ErrorKind::InvalidValue,
+      format!("Invalid UUID: {}\n", inner),
+    )
+  })?;
+  Ok((inner, uuid))
+}
+
 #[derive(Clone)]
 struct CommitUuidParser {
   state: Arc<RwLock<TotalState>>,
@@ -26,17 +122,7 @@ impl TypedValueParser for CommitUuidParser {
     arg: Option<&Arg>,
     value: &std::ffi::OsStr,
   ) -> Result<Self::Value, clap::Error> {
-    let inner = reedline_repl_rs::clap::builder::StringValueParser::default().parse(
-      cmd,
-      arg,
-      value.into(),
-    )?;
-    let uuid = Uuid::from_base64url(&inner).map_err(|_| {
-      clap::Error::raw(
-        clap::error::ErrorKind::InvalidValue,
-        format!("Invalid UUID: {}", inner),
-      )
-    })?;
+    let (inner, uuid) = parse_uuid(cmd, arg, value)?;
     let state = self.state.read().unwrap();
     if state
       .universe
@@ -48,7 +134,7 @@ impl TypedValueParser for CommitUuidParser {
     } else {
       Err(clap::Error::raw(
         clap::error::ErrorKind::InvalidValue,
-        format!("Unknown commit: {}", inner),
+        format!("Unknown commit: {}\n", inner),
       ))
     }
   }
@@ -61,118 +147,1363 @@ impl TypedValueParser for CommitUuidParser {
   }
 }
 
-fn main() {
-  let state = Arc::new(RwLock::new(TotalState::new()));
-  let mut repl: Repl<_, Error> = Repl::new(state.clone())
-    .with_name("uuid_set")
-    .with_partial_completions(true)
-    .with_command(Command::new("add"), |_, state| {
-      let mut state = state.write().unwrap();
-      let new_uuid = state.add();
-      Ok(Some(format!(
-        "Created new entity {} in working set",
-        new_uuid.as_base64url()
-      )))
+#[derive(Clone)]
+struct PatchUuidParser {
+  state: Arc<RwLock<TotalState>>,
+}
+
+impl From<&Arc<RwLock<TotalState>>> for PatchUuidParser {
+  fn from(state: &Arc<RwLock<TotalState>>) -> Self {
+    Self {
+      state: state.clone(),
+    }
+  }
+}
+
+impl TypedValueParser for PatchUuidParser {
+  type Value = Uuid;
+  fn parse_ref(
+    &self,
+    cmd: &Command,
+    arg: Option<&Arg>,
+    value: &std::ffi::OsStr,
+  ) -> Result<Self::Value, clap::Error> {
+    let (inner, uuid) = parse_uuid(cmd, arg, value)?;
+    let state = self.state.read().unwrap();
+    if state
+      .universe
+      .get_index_of(&uuid)
+      .map(|luid| state.patches.contains_key(&luid))
+      .unwrap_or(false)
+    {
+      Ok(uuid)
+    } else {
+      Err(clap::Error::raw(
+        clap::error::ErrorKind::InvalidValue,
+        format!("Unknown patch: {}\n", inner),
+      ))
+    }
+  }
+  fn possible_values(&self) -> Option<Box<dyn Iterator<Item = clap::builder::PossibleValue> + '_>> {
+    let state = self.state.read().unwrap();
+    let patches: Vec<String> = state
+      .patches
+      .keys()
+      .map(|luid| state.universe.get_index(*luid).unwrap().as_base64url())
+      .collect();
+    Some(Box::new(
+      patches.into_iter().map(clap::builder::PossibleValue::new),
+    ))
+  }
+}
+
+// A type of attribute values, by name: a primitive type or one registered with the state's
+// `TypeRegistry`.
+#[derive(Clone)]
+struct AttrTypeParser {
+  state: Arc<RwLock<TotalState>>,
+}
+
+impl From<&Arc<RwLock<TotalState>>> for AttrTypeParser {
+  fn from(state: &Arc<RwLock<TotalState>>) -> Self {
+    Self {
+      state: state.clone(),
+    }
+  }
+}
+
+impl TypedValueParser for AttrTypeParser {
+  type Value = Uuid;
+  fn parse_ref(
+    &self,
+    _cmd: &Command,
+    _arg: Option<&Arg>,
+    value: &std::ffi::OsStr,
+  ) -> Result<Self::Value, clap::Error> {
+    let name = value.to_string_lossy();
+    let state = self.state.read().unwrap();
+    state.types.lookup_name(&name).ok_or_else(|| {
+      clap::Error::raw(
+        clap::error::ErrorKind::InvalidValue,
+        format!(
+          "Unknown type: {} (known types: {})\n",
+          name,
+          state.types.names().join(", ")
+        ),
+      )
     })
-    .with_command(
-      Command::new("checkout").arg(
-        Arg::new("uuid")
-          .required(true)
-          .index(1)
-          .value_parser(CommitUuidParser::from(&state)),
-      ),
-      |matches, state| {
-        let mut state = state.write().unwrap();
-        let uuid = matches.get_one::<Uuid>("uuid").unwrap();
-        state.checkout(uuid);
-        Ok(Some(format!("Checked out commit {}", uuid.as_base64url())))
-      },
-    )
-    .with_command(Command::new("commit"), |_, state| {
-      let mut state = state.write().unwrap();
-      let (patch_id, patch) = state.commit();
-      Ok(Some(format!(
+  }
+  fn possible_values(&self) -> Option<Box<dyn Iterator<Item = clap::builder::PossibleValue> + '_>> {
+    let state = self.state.read().unwrap();
+    Some(Box::new(
+      state
+        .types
+        .names()
+        .into_iter()
+        .map(clap::builder::PossibleValue::new),
+    ))
+  }
+}
+
+fn add(_: ArgMatches, session: &mut Session) -> CommandResult {
+  let new_uuid = session.state.write().unwrap().add().as_base64url();
+  session.output(
+    || format!("Created new entity {} in working set", new_uuid),
+    || json!({ "entity": new_uuid }),
+  )
+}
+
+fn checkout(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let uuid = matches.get_one::<Uuid>("uuid").unwrap();
+  // (such as a commit whose patches could not be processed, or with changes in the working patch)
+  if let Err(e) = session.state.write().unwrap().checkout(uuid) {
+    return session.error(format!(
+      "Can't check out commit {}: {}",
+      uuid.as_base64url(),
+      e
+    ));
+  }
+  let uuid = uuid.as_base64url();
+  session.output(
+    || format!("Checked out commit {}", uuid),
+    || json!({ "commit": uuid }),
+  )
+}
+
+fn commit(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let mut state = session.state.write().unwrap();
+  if let Some(message) = matches.get_one::<String>("message") {
+    state.set_message(message);
+  }
+  let (patch_id, patch) = match state.commit() {
+    Ok(committed) => committed,
+    Err(e) => return session.error(e),
+  };
+  let patch_id = patch_id.as_base64url();
+  let sources = base64url_list(patch.source_commits.iter());
+  let target = patch.target_commit.as_base64url();
+  let message = patch.message.clone();
+  session.output(
+    || {
+      format!(
         "Saved new patch {} from [{}] to {}",
-        patch_id.as_base64url(),
-        patch
-          .source_commits
-          .iter()
-          .map(|id| id.as_base64url())
-          .collect::<Vec<_>>()
-          .join(", "),
-        patch.target_commit.as_base64url(),
-      )))
-    })
-    .with_command(Command::new("graph"), |_, state| {
-      let state = state.read().unwrap();
+        patch_id,
+        sources.join(", "),
+        target
+      )
+    },
+    || json!({ "patch": patch_id, "sources": sources, "target": target, "message": message }),
+  )
+}
+
+fn graph(_: ArgMatches, session: &mut Session) -> CommandResult {
+  let state = session.state.read().unwrap();
+  session.output(
+    || {
       let graph = state.graph();
-      Ok(Some(
-        metro::to_string(
-          graph
+      metro::to_string(
+        graph
+          .iter()
+          .map(|e| e.into())
+          .collect::<Vec<metro::Event>>()
+          .as_slice(),
+      )
+      .unwrap()
+      .trim_end_matches('\n')
+      .to_string()
+    },
+    || {
+      // newest first, like the drawing
+      let mut commits: Vec<_> = state.commits.iter().collect();
+      commits.sort_by_key(|(commit_luid, _)| {
+        std::cmp::Reverse((state.generation(**commit_luid), **commit_luid))
+      });
+      let uuid = |luid: &Luid| state.universe.get_index(*luid).unwrap().as_base64url();
+      json!({
+        "commits": commits
+          .iter()
+          .map(|(commit_luid, incoming)| {
+            json!({
+              "commit": uuid(commit_luid),
+              "generation": state.generation(**commit_luid),
+              "patches": incoming
+                .iter()
+                .map(|(sources, patch_luid)| {
+                  json!({
+                    "patch": uuid(patch_luid),
+                    "sources": sources.iter().map(uuid).collect::<Vec<_>>(),
+                  })
+                })
+                .collect::<Vec<_>>(),
+            })
+          })
+          .collect::<Vec<_>>(),
+      })
+    },
+  )
+}
+
+fn load(_: ArgMatches, session: &mut Session) -> CommandResult {
+  let mut state = session.state.write().unwrap();
+  state.load_all_patches();
+  match session.format {
+    Format::Text => Ok(None),
+    Format::Json => session.output(String::new, || json!({ "commits": state.commits.len() })),
+  }
+}
+
+fn merge(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let uuids: Vec<Uuid> = matches.get_many::<Uuid>("uuid").unwrap().copied().collect();
+  let ff_only = matches.get_flag("ff-only");
+  let outcome = session.state.write().unwrap().merge(&uuids, ff_only);
+  match outcome {
+    Ok(MergeOutcome::UpToDate) => session.output(
+      || "Already up to date".to_string(),
+      || json!({ "outcome": "up_to_date" }),
+    ),
+    Ok(MergeOutcome::FastForward(uuid)) => {
+      let uuid = uuid.as_base64url();
+      session.output(
+        || format!("Fast-forwarded to {}", uuid),
+        || json!({ "outcome": "fast_forward", "commit": uuid }),
+      )
+    }
+    Ok(MergeOutcome::Merged { sources, deletions }) => {
+      let sources = base64url_list(sources.iter());
+      session.output(
+        || {
+          format!(
+            "Merged [{}] into working set ({} deletions); commit to record the merge",
+            sources.join(", "),
+            deletions
+          )
+        },
+        || json!({ "outcome": "merged", "sources": sources, "deletions": deletions }),
+      )
+    }
+    Err(e) => session.error(e),
+  }
+}
+
+fn merge_base(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let state = session.state.read().unwrap();
+  let uuid0 = matches.get_one::<Uuid>("uuid0").unwrap();
+  let uuid1 = matches.get_one::<Uuid>("uuid1").unwrap();
+  match state.merge_bases(uuid0, uuid1) {
+    Ok(merge_bases) => {
+      let merge_bases = base64url_list(
+        merge_bases
+          .iter()
+          .map(|luid| state.universe.get_index(*luid).unwrap()),
+      );
+      session.output(
+        || bullet_list(&merge_bases),
+        || json!({ "merge_bases": merge_bases }),
+      )
+    }
+    Err(e) => session.error(e),
+  }
+}
+
+fn cherry_pick(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let uuid = matches.get_one::<Uuid>("uuid").unwrap();
+  let result = session.state.write().unwrap().cherry_pick(uuid);
+  let uuid = uuid.as_base64url();
+  match result {
+    Ok(()) => session.output(
+      || format!("Applied patch {} to working set", uuid),
+      || json!({ "patch": uuid }),
+    ),
+    Err(e) => session.error(e),
+  }
+}
+
+fn rebase(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let onto = matches.get_one::<Uuid>("onto").unwrap();
+  let result = session.state.write().unwrap().rebase(onto);
+  match result {
+    Ok(outcome) => session.output(
+      || {
+        let mut lines: Vec<String> = outcome
+          .replayed
+          .iter()
+          .map(|(patch, new_patch)| {
+            format!(
+              "Replayed patch {} as {}",
+              patch.as_base64url(),
+              new_patch.as_base64url()
+            )
+          })
+          .collect();
+        lines.extend(outcome.skipped.iter().map(|(patch, conflicts)| {
+          format!(
+            "Skipped patch {}: {}",
+            patch.as_base64url(),
+            CherryPickError::Conflicts(conflicts.clone())
+          )
+        }));
+        lines.push(format!("HEAD is now {}", outcome.head.as_base64url()));
+        lines.join("\n")
+      },
+      || {
+        json!({
+          "head": outcome.head.as_base64url(),
+          "replayed": outcome
+            .replayed
+            .iter()
+            .map(|(patch, new_patch)| {
+              json!({ "patch": patch.as_base64url(), "new_patch": new_patch.as_base64url() })
+            })
+            .collect::<Vec<_>>(),
+          "skipped": outcome
+            .skipped
+            .iter()
+            .map(|(patch, conflicts)| {
+              json!({
+                "patch": patch.as_base64url(),
+                "conflicts": conflicts.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+              })
+            })
+            .collect::<Vec<_>>(),
+        })
+      },
+    ),
+    Err(e) => session.error(e),
+  }
+}
+
+fn revert_commit(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let uuid = matches.get_one::<Uuid>("uuid").unwrap();
+  let result = session.state.write().unwrap().revert_commit(uuid);
+  match result {
+    Ok(patch) => {
+      let patch = patch.as_base64url();
+      session.output(
+        || format!("Applied the inverse of patch {} to working set", patch),
+        || json!({ "commit": uuid.as_base64url(), "inverted_patch": patch }),
+      )
+    }
+    Err(e) => session.error(e),
+  }
+}
+
+fn import(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let path = matches.get_one::<String>("file").unwrap();
+  let mut options = ImportOptions::new(matches.get_one::<String>("sort").unwrap());
+  if let Some(column) = matches.get_one::<String>("name-column") {
+    options.name_column = column.clone();
+  }
+  if let Some(column) = matches.get_one::<String>("uuid-column") {
+    options.uuid_column = column.clone();
+  }
+  let input_format = match matches.get_one::<String>("as") {
+    Some(input_format) => input_format.as_str(),
+    None if path.ends_with(".csv") => "csv",
+    None
+      if [".json", ".jsonl", ".ndjson"]
+        .iter()
+        .any(|extension| path.ends_with(extension)) =>
+    {
+      "jsonl"
+    }
+    None => return session.error("Unknown input format; use --as csv or --as jsonl"),
+  };
+  let input: Box<dyn std::io::Read> = match path.as_str() {
+    "-" => Box::new(std::io::stdin()),
+    path => match std::fs::File::open(path) {
+      Ok(file) => Box::new(file),
+      Err(e) => return session.error(format!("Could not open {:?}: {}", path, e)),
+    },
+  };
+  let mut state = session.state.write().unwrap();
+  let result = match input_format {
+    "csv" => state.import_csv(input, &options),
+    _ => state.import_json_lines(std::io::BufReader::new(input), &options),
+  };
+  match result {
+    Ok(summary) => {
+      let sort = summary.sort.as_base64url();
+      session.output(
+        || {
+          format!(
+            "Imported {} new and {} existing entities into {}sort {} ({}), naming {} of them",
+            summary.created,
+            summary.reused,
+            if summary.new_sort { "new " } else { "" },
+            options.sort,
+            sort,
+            summary.named
+          )
+        },
+        || {
+          json!({
+            "sort": sort,
+            "new_sort": summary.new_sort,
+            "created": summary.created,
+            "reused": summary.reused,
+            "named": summary.named,
+          })
+        },
+      )
+    }
+    Err(e) => session.error(e),
+  }
+}
+
+fn export(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let commit = matches.get_one::<Uuid>("uuid").unwrap();
+  let format = match matches.get_one::<String>("as").unwrap().as_str() {
+    "csv" => ExportFormat::Csv,
+    "acset" => ExportFormat::Acset,
+    _ => ExportFormat::Json,
+  };
+  let mut state = session.state.write().unwrap();
+  match matches.get_one::<String>("output") {
+    // (the exported data is printed as it is, whatever the output format of the commands)
+    None => {
+      let mut data = Vec::new();
+      match state.export(commit, format, &mut data) {
+        Ok(()) => Ok(Some(
+          String::from_utf8(data).unwrap().trim_end().to_string(),
+        )),
+        Err(e) => session.error(e),
+      }
+    }
+    Some(path) => {
+      let file = match std::fs::File::create(path) {
+        Ok(file) => file,
+        Err(e) => return session.error(format!("Could not create {:?}: {}", path, e)),
+      };
+      match state.export(commit, format, std::io::BufWriter::new(file)) {
+        Ok(()) => session.output(
+          || format!("Exported {} to {}", commit.as_base64url(), path),
+          || json!({ "commit": commit.as_base64url(), "output": path }),
+        ),
+        Err(e) => session.error(e),
+      }
+    }
+  }
+}
+
+// An element of the working set, given by its UUID or its name, or a term `hom(arg)` (which is
+// left out of the working set if it isn't in it already).
+fn lookup(state: &mut TotalState, text: &str) -> Result<Uuid, String> {
+  resolve(state, text, false)
+}
+
+// As `lookup`, adding a term to the working set if need be, for the commands that give it values
+// or merge it.
+fn lookup_or_add(state: &mut TotalState, text: &str) -> Result<Uuid, String> {
+  resolve(state, text, true)
+}
+
+fn resolve(state: &mut TotalState, text: &str, add_terms: bool) -> Result<Uuid, String> {
+  if let Some(uuid) = state.lookup(text) {
+    return Ok(uuid);
+  }
+  match text.strip_suffix(')').and_then(|text| text.split_once('(')) {
+    Some((hom, arg)) => {
+      let hom = resolve(state, hom, add_terms)?;
+      let arg = resolve(state, arg, add_terms)?;
+      let term = match add_terms {
+        true => state.add_term(&hom, &arg),
+        false => state.term(&hom, &arg),
+      };
+      term.map_err(|e| e.to_string())
+    }
+    None => Err(format!("Unknown element: {}", text)),
+  }
+}
+
+fn name_path(matches: &ArgMatches) -> Option<Vec<String>> {
+  matches
+    .get_one::<String>("name")
+    .map(|name| name.split('/').map(|part| part.to_string()).collect())
+}
+
+fn hom(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let mut state = session.state.write().unwrap();
+  let result = (|| {
+    let domain = lookup(&mut state, matches.get_one::<String>("domain").unwrap())?;
+    let codomain = lookup(&mut state, matches.get_one::<String>("codomain").unwrap())?;
+    let hom = state
+      .add_hom(
+        &domain,
+        &codomain,
+        matches.get_flag("partial"),
+        matches.get_flag("free"),
+      )
+      .map_err(|e| e.to_string())?;
+    if let Some(path) = name_path(&matches) {
+      state.name(&hom, &path);
+    }
+    Ok::<_, String>(hom.as_base64url())
+  })();
+  match result {
+    Ok(hom) => session.output(
+      || format!("Created new hom {} in working set", hom),
+      || json!({ "hom": hom }),
+    ),
+    Err(e) => session.error(e),
+  }
+}
+
+fn attr(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let mut state = session.state.write().unwrap();
+  let attr_type = *matches.get_one::<Uuid>("type").unwrap();
+  let type_name = state.types.type_name(&attr_type);
+  let result = (|| {
+    let domain = lookup(&mut state, matches.get_one::<String>("domain").unwrap())?;
+    let attr = state
+      .add_attr(&domain, &attr_type)
+      .map_err(|e| e.to_string())?;
+    if let Some(path) = name_path(&matches) {
+      state.name(&attr, &path);
+    }
+    Ok::<_, String>(attr.as_base64url())
+  })();
+  match result {
+    Ok(attr) => session.output(
+      || {
+        format!(
+          "Created new {} attribute {} in working set",
+          type_name, attr
+        )
+      },
+      || json!({ "attr": attr, "type": type_name }),
+    ),
+    Err(e) => session.error(e),
+  }
+}
+
+// Set the value of an attribute, or of a hom (whose value is then another element), for an entity.
+fn set(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let mut state = session.state.write().unwrap();
+  let result = (|| {
+    let entity = lookup_or_add(&mut state, matches.get_one::<String>("entity").unwrap())?;
+    let function = lookup(&mut state, matches.get_one::<String>("function").unwrap())?;
+    let text = matches.get_one::<String>("value").unwrap();
+    if state.attr_type(&function).is_ok() {
+      let value = state
+        .parse_attr_value(&function, text)
+        .map_err(|e| e.to_string())?;
+      state
+        .set_attr_value(&function, &entity, value.clone())
+        .map_err(|e| e.to_string())?;
+      Ok::<_, String>((state.types.display(&value), state.types.to_json(&value)))
+    } else {
+      let value = lookup_or_add(&mut state, text)?;
+      state
+        .set_hom_value(&function, &entity, &value)
+        .map_err(|e| e.to_string())?;
+      let text = state
+        .describe_term(&value)
+        .unwrap_or_else(|| value.as_base64url());
+      Ok((text, json!(value.as_base64url())))
+    }
+  })();
+  match result {
+    Ok((text, value)) => session.output(
+      || {
+        format!(
+          "Set {} of {} to {}",
+          matches.get_one::<String>("function").unwrap(),
+          matches.get_one::<String>("entity").unwrap(),
+          text
+        )
+      },
+      || json!({ "value": value }),
+    ),
+    Err(e) => session.error(e),
+  }
+}
+
+fn get(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let mut state = session.state.write().unwrap();
+  let result = (|| {
+    let entity = lookup(&mut state, matches.get_one::<String>("entity").unwrap())?;
+    let function = lookup(&mut state, matches.get_one::<String>("function").unwrap())?;
+    if state.attr_type(&function).is_ok() {
+      let value = state.attr_value(&function, &entity);
+      Ok::<_, String>(value.map(|(value, derived)| {
+        (
+          state.types.display(&value),
+          state.types.to_json(&value),
+          derived,
+        )
+      }))
+    } else {
+      // (a term is shown as such, as in `query`)
+      let value = state.hom_value(&function, &entity);
+      Ok(
+        value.map(|(value, derived)| match state.describe_term(&value) {
+          Some(term) => (
+            term.clone(),
+            json!({ "term": term, "uuid": value.as_base64url() }),
+            derived,
+          ),
+          None => (value.as_base64url(), json!(value.as_base64url()), derived),
+        }),
+      )
+    }
+  })();
+  match result {
+    // (a value that the rules derive is labelled as such)
+    Ok(value) => session.output(
+      || match &value {
+        Some((text, _, false)) => text.clone(),
+        Some((text, _, true)) => format!("{} (derived)", text),
+        None => "(no value)".to_string(),
+      },
+      || match &value {
+        Some((_, value, derived)) => json!({ "value": value, "derived": derived }),
+        None => json!({ "value": null }),
+      },
+    ),
+    Err(e) => session.error(e),
+  }
+}
+
+// Answer a conjunctive query; its words are joined back together, so it needn't be quoted.
+fn query(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let text = matches
+    .get_many::<String>("query")
+    .unwrap()
+    .cloned()
+    .collect::<Vec<_>>()
+    .join(" ");
+  let mut state = session.state.write().unwrap();
+  let result = match state.query(&text) {
+    Ok(result) => result,
+    Err(e) => return session.error(e),
+  };
+  // (terms, such as `f(x)`, are shown as such, with their UUIDs in JSON)
+  let display = |value: &QueryValue| match value {
+    QueryValue::Element(uuid) => match result.terms.get(uuid) {
+      Some(term) => term.clone(),
+      None => uuid.as_base64url(),
+    },
+    QueryValue::Value(value) => state.types.display(value),
+  };
+  let to_json = |value: &QueryValue| match value {
+    QueryValue::Element(uuid) => match result.terms.get(uuid) {
+      Some(term) => json!({ "term": term, "uuid": uuid.as_base64url() }),
+      None => json!(uuid.as_base64url()),
+    },
+    QueryValue::Value(value) => state.types.to_json(value),
+  };
+  session.output(
+    || {
+      let rows: Vec<String> = result
+        .rows
+        .iter()
+        .map(|row| {
+          result
+            .variables
             .iter()
-            .map(|e| e.into())
-            .collect::<Vec<metro::Event>>()
-            .as_slice(),
+            .zip(row.iter())
+            .map(|(var, value)| format!("?{} = {}", var, display(value)))
+            .collect::<Vec<_>>()
+            .join(", ")
+        })
+        .collect();
+      match rows.is_empty() {
+        true => "(no results)".to_string(),
+        false => bullet_list(&rows),
+      }
+    },
+    || {
+      let rows: Vec<Vec<serde_json::Value>> = result
+        .rows
+        .iter()
+        .map(|row| row.iter().map(to_json).collect())
+        .collect();
+      json!({ "variables": result.variables, "rows": rows })
+    },
+  )
+}
+
+fn rule(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let text = matches
+    .get_many::<String>("rule")
+    .unwrap()
+    .cloned()
+    .collect::<Vec<_>>()
+    .join(" ");
+  let mut state = session.state.write().unwrap();
+  let result = (|| {
+    let rule = state.add_rule(&text).map_err(|e| e.to_string())?;
+    if let Some(path) = name_path(&matches) {
+      state.name(&rule, &path);
+    }
+    Ok::<_, String>(rule.as_base64url())
+  })();
+  match result {
+    Ok(rule) => session.output(
+      || format!("Created new rule {} in working set", rule),
+      || json!({ "rule": rule }),
+    ),
+    Err(e) => session.error(e),
+  }
+}
+
+fn unset(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let mut state = session.state.write().unwrap();
+  let result = (|| {
+    let entity = lookup(&mut state, matches.get_one::<String>("entity").unwrap())?;
+    let function = lookup(&mut state, matches.get_one::<String>("function").unwrap())?;
+    state
+      .unset_value(&function, &entity)
+      .map_err(|e| e.to_string())
+  })();
+  match result {
+    Ok(()) => session.output(
+      || {
+        format!(
+          "Unset {} of {}",
+          matches.get_one::<String>("function").unwrap(),
+          matches.get_one::<String>("entity").unwrap()
         )
-        .unwrap()
-        .trim_end_matches('\n')
-        .to_string(),
-      ))
-    })
-    .with_command(Command::new("load"), |_, state| {
-      let mut state = state.write().unwrap();
-      state.load_all_patches();
-      Ok(None)
-    })
-    .with_command(
-      Command::new("merge").arg(
+      },
+      || json!({ "value": null }),
+    ),
+    Err(e) => session.error(e),
+  }
+}
+
+fn delete(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let mut state = session.state.write().unwrap();
+  let result = (|| {
+    let element = lookup(&mut state, matches.get_one::<String>("element").unwrap())?;
+    state.delete(&element).map_err(|e| e.to_string())?;
+    Ok::<_, String>(element.as_base64url())
+  })();
+  match result {
+    Ok(element) => session.output(
+      || format!("Deleted {} from working set", element),
+      || json!({ "deleted": element }),
+    ),
+    Err(e) => session.error(e),
+  }
+}
+
+fn merge_into(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let mut state = session.state.write().unwrap();
+  let result = (|| {
+    let element = lookup_or_add(&mut state, matches.get_one::<String>("element").unwrap())?;
+    let merged_into = lookup_or_add(&mut state, matches.get_one::<String>("into").unwrap())?;
+    state
+      .merge_into(&element, &merged_into)
+      .map_err(|e| e.to_string())?;
+    Ok::<_, String>((element.as_base64url(), merged_into.as_base64url()))
+  })();
+  match result {
+    Ok((element, merged_into)) => session.output(
+      || format!("Merged {} into {} in working set", element, merged_into),
+      || json!({ "merged": element, "into": merged_into }),
+    ),
+    Err(e) => session.error(e),
+  }
+}
+
+fn canonical(matches: ArgMatches, session: &mut Session) -> CommandResult {
+  let mut state = session.state.write().unwrap();
+  let result = (|| {
+    let text = matches.get_one::<String>("element").unwrap();
+    // (an element that has been merged away is only known by its UUID)
+    let element = match Uuid::from_base64url(text) {
+      Ok(uuid) => uuid,
+      Err(_) => lookup(&mut state, text)?,
+    };
+    let canonical = state
+      .canonical(&element)
+      .ok_or_else(|| format!("Unknown element: {}", text))?;
+    Ok::<_, String>((element.as_base64url(), canonical.as_base64url()))
+  })();
+  match result {
+    Ok((element, canonical)) => session.output(
+      || canonical.clone(),
+      || json!({ "element": element, "canonical": canonical }),
+    ),
+    Err(e) => session.error(e),
+  }
+}
+
+fn count(_: ArgMatches, session: &mut Session) -> CommandResult {
+  let count = session.state.read().unwrap().count();
+  session.output(
+    || format!("Entities: {:?}", count),
+    || json!({ "count": count }),
+  )
+}
+
+fn commits(_: ArgMatches, session: &mut Session) -> CommandResult {
+  let commits = base64url_list(session.state.read().unwrap().commits());
+  session.output(|| bullet_list(&commits), || json!({ "commits": commits }))
+}
+
+fn heads(_: ArgMatches, session: &mut Session) -> CommandResult {
+  let heads = base64url_list(session.state.read().unwrap().heads());
+  session.output(|| bullet_list(&heads), || json!({ "heads": heads }))
+}
+
+fn list(_: ArgMatches, session: &mut Session) -> CommandResult {
+  let entities = base64url_list(session.state.read().unwrap().list());
+  session.output(
+    || bullet_list(&entities),
+    || json!({ "entities": entities }),
+  )
+}
+
+fn command(command: Command, handler: Handler) -> (Command, Handler) {
+  (command, handler)
+}
+
+// Every command, available both in the REPL and as a subcommand of the binary.
+fn commands(state: &Arc<RwLock<TotalState>>) -> Vec<(Command, Handler)> {
+  vec![
+    command(
+      Command::new("add").about("Create a new entity in the working set"),
+      add,
+    ),
+    command(
+      Command::new("checkout").about("Check out a commit").arg(
         Arg::new("uuid")
           .required(true)
           .index(1)
-          .value_parser(CommitUuidParser::from(&state)),
+          .value_parser(CommitUuidParser::from(state)),
       ),
-      |matches, state| {
-        let mut state = state.write().unwrap();
-        let uuid = matches.get_one::<Uuid>("uuid").unwrap();
-        state
-          .merge(uuid)
-          .map(|_| Some(format!("Merged {} into working set", uuid.as_base64url())))
-          .or_else(|e| Ok(Some(e.to_string())))
+      checkout,
+    ),
+    command(
+      Command::new("commit")
+        .about("Record the working patch as a new commit")
+        .arg(
+          Arg::new("message")
+            .short('m')
+            .long("message")
+            .help("Message to record with the commit"),
+        ),
+      commit,
+    ),
+    command(Command::new("graph").about("Draw the commit graph"), graph),
+    command(
+      Command::new("load").about("Load all patches from disk"),
+      load,
+    ),
+    command(
+      Command::new("merge")
+        .about("Merge one or more commits into the working set")
+        .arg(
+          Arg::new("uuid")
+            .required(true)
+            .num_args(1..)
+            .value_parser(CommitUuidParser::from(state)),
+        )
+        .arg(
+          Arg::new("ff-only")
+            .long("ff-only")
+            .action(ArgAction::SetTrue)
+            .help("Refuse to merge unless HEAD can be fast-forwarded"),
+        ),
+      merge,
+    ),
+    command(
+      Command::new("merge-base")
+        .about("List the maximal common ancestors of two commits")
+        .arg(
+          Arg::new("uuid0")
+            .required(true)
+            .index(1)
+            .value_parser(CommitUuidParser::from(state)),
+        )
+        .arg(
+          Arg::new("uuid1")
+            .required(true)
+            .index(2)
+            .value_parser(CommitUuidParser::from(state)),
+        ),
+      merge_base,
+    ),
+    command(
+      Command::new("cherry-pick")
+        .about("Apply the changes of a patch to the working set")
+        .arg(
+          Arg::new("uuid")
+            .required(true)
+            .index(1)
+            .value_parser(PatchUuidParser::from(state)),
+        ),
+      cherry_pick,
+    ),
+    command(
+      Command::new("rebase")
+        .about("Replay the patches of the current branch onto another commit")
+        .arg(
+          Arg::new("onto")
+            .required(true)
+            .index(1)
+            .value_parser(CommitUuidParser::from(state)),
+        ),
+      rebase,
+    ),
+    command(
+      Command::new("revert-commit")
+        .about("Apply the inverse of a commit's patch to the working set")
+        .arg(
+          Arg::new("uuid")
+            .required(true)
+            .index(1)
+            .value_parser(CommitUuidParser::from(state)),
+        ),
+      revert_commit,
+    ),
+    command(
+      Command::new("import")
+        .about("Add entities from a CSV or JSON-lines file to the working set")
+        .arg(
+          Arg::new("file")
+            .required(true)
+            .index(1)
+            .help("File to import, or - for standard input"),
+        )
+        .arg(
+          Arg::new("sort")
+            .long("sort")
+            .required(true)
+            .help("Sort to add the entities to (created if it doesn't exist)"),
+        )
+        .arg(
+          Arg::new("as")
+            .long("as")
+            .value_parser(["csv", "jsonl"])
+            .help("Format of the file (by default, guessed from its extension)"),
+        )
+        .arg(
+          Arg::new("name-column")
+            .long("name-column")
+            .help("Column with the names of the entities [default: name]"),
+        )
+        .arg(
+          Arg::new("uuid-column")
+            .long("uuid-column")
+            .help("Column with the UUIDs of the entities [default: uuid]"),
+        ),
+      import,
+    ),
+    command(
+      Command::new("export")
+        .about("Write out the elements, sorts and names of a commit")
+        .arg(
+          Arg::new("uuid")
+            .required(true)
+            .index(1)
+            .value_parser(CommitUuidParser::from(state)),
+        )
+        .arg(
+          Arg::new("as")
+            .long("as")
+            .value_parser(["json", "csv", "acset"])
+            .default_value("json")
+            .help(
+              "Format to export to (acset is the JSON format of Catlab.jl's ACSets); not \
+               --format, which chooses how commands report their results",
+            ),
+        )
+        .arg(
+          Arg::new("output")
+            .long("output")
+            .short('o')
+            .value_name("FILE")
+            .help("File to write to, instead of printing"),
+        ),
+      export,
+    ),
+    command(
+      Command::new("hom")
+        .about("Declare a hom from one sort to another in the working set")
+        .arg(Arg::new("domain").required(true).index(1))
+        .arg(Arg::new("codomain").required(true).index(2))
+        .arg(
+          Arg::new("name")
+            .long("name")
+            .help("Name for the hom, with its parts separated by /"),
+        )
+        .arg(
+          Arg::new("partial")
+            .long("partial")
+            .action(ArgAction::SetTrue)
+            .help("Allow entities of the domain to have no value"),
+        )
+        .arg(
+          Arg::new("free")
+            .long("free")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("partial")
+            .help("Give entities of the domain with no value their term, such as f(x), instead"),
+        ),
+      hom,
+    ),
+    command(
+      Command::new("attr")
+        .about("Declare an attribute of the entities of a sort in the working set")
+        .arg(Arg::new("domain").required(true).index(1))
+        .arg(
+          Arg::new("type")
+            .required(true)
+            .index(2)
+            .value_parser(AttrTypeParser::from(state)),
+        )
+        .arg(
+          Arg::new("name")
+            .long("name")
+            .help("Name for the attribute, with its parts separated by /"),
+        ),
+      attr,
+    ),
+    command(
+      Command::new("set")
+        .about("Set the value of an attribute or hom for an entity")
+        .arg(Arg::new("entity").required(true).index(1))
+        .arg(Arg::new("function").required(true).index(2))
+        .arg(Arg::new("value").required(true).index(3)),
+      set,
+    ),
+    command(
+      Command::new("get")
+        .about("Get the value of an attribute or hom for an entity")
+        .arg(Arg::new("entity").required(true).index(1))
+        .arg(Arg::new("function").required(true).index(2)),
+      get,
+    ),
+    command(
+      Command::new("query")
+        .about("Find the bindings of variables that satisfy a conjunctive query")
+        .arg(
+          Arg::new("query")
+            .required(true)
+            .num_args(1..)
+            .trailing_var_arg(true)
+            .allow_hyphen_values(true)
+            .help("Atoms separated by commas, such as: ?x : Person, age(x) = 42, employer(x) = ?y"),
+        ),
+      query,
+    ),
+    command(
+      Command::new("rule")
+        .about("Add a rule, from whose premises its conclusion is derived, to the working set")
+        .arg(
+          Arg::new("name")
+            .long("name")
+            .help("Name for the rule, with its parts separated by /"),
+        )
+        .arg(
+          Arg::new("rule")
+            .required(true)
+            .num_args(1..)
+            .trailing_var_arg(true)
+            .allow_hyphen_values(true)
+            .help("Conclusion :- premises, such as: grandparent(x) = ?z :- parent(x) = ?y, parent(y) = ?z"),
+        ),
+      rule,
+    ),
+    command(
+      Command::new("unset")
+        .about("Remove the value of an attribute or hom for an entity")
+        .arg(Arg::new("entity").required(true).index(1))
+        .arg(Arg::new("function").required(true).index(2)),
+      unset,
+    ),
+    command(
+      Command::new("delete")
+        .about("Delete an element (an entity, sort, hom or attribute) from the working set")
+        .arg(Arg::new("element").required(true).index(1)),
+      delete,
+    ),
+    command(
+      Command::new("merge-into")
+        .about("Merge an element of the working set into another, such as a sort into a sort")
+        .arg(Arg::new("element").required(true).index(1))
+        .arg(Arg::new("into").required(true).index(2)),
+      merge_into,
+    ),
+    command(
+      Command::new("canonical")
+        .about("Show the element that an element has been merged into, directly or by congruence")
+        .arg(Arg::new("element").required(true).index(1)),
+      canonical,
+    ),
+    command(
+      Command::new("count").about("Count the entities in the working set"),
+      count,
+    ),
+    command(Command::new("commits").about("List all commits"), commits),
+    command(
+      Command::new("heads").about("List the commits without descendants"),
+      heads,
+    ),
+    command(
+      Command::new("list").about("List the entities in the working set"),
+      list,
+    ),
+  ]
+}
+
+// Run the command `name` once, printing its output. Returns whether it succeeded.
+fn run_command(
+  commands: &[(Command, Handler)],
+  name: &str,
+  matches: &ArgMatches,
+  session: &mut Session,
+) -> bool {
+  let (_, handler) = commands
+    .iter()
+    .find(|(command, _)| command.get_name() == name)
+    .unwrap();
+  match handler(matches.clone(), session) {
+    Ok(output) => {
+      session.state.read().unwrap().save_working_patch();
+      if let Some(output) = output {
+        println!("{}", output);
+      }
+      true
+    }
+    Err(e) => {
+      eprintln!("{}", e);
+      false
+    }
+  }
+}
+
+// Split a line of a script into words, like a shell would (but without escapes or expansions):
+// words are separated by whitespace, and quotes (single or double) group words together.
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+  let mut words = Vec::new();
+  let mut word: Option<String> = None;
+  let mut quote: Option<char> = None;
+  for c in line.chars() {
+    match quote {
+      Some(q) if c == q => quote = None,
+      Some(_) => word.get_or_insert_with(String::new).push(c),
+      None if c == '"' || c == '\'' => {
+        quote = Some(c);
+        word.get_or_insert_with(String::new);
+      }
+      None if c.is_whitespace() => words.extend(word.take()),
+      None => word.get_or_insert_with(String::new).push(c),
+    }
+  }
+  if let Some(q) = quote {
+    return Err(format!("Unterminated {} quote", q));
+  }
+  words.extend(word);
+  Ok(words)
+}
+
+// Run the commands in a script (one per line; blank lines and lines starting with `#` are
+// skipped), stopping at the first one that fails unless `keep_going`. Returns whether all of them
+// succeeded.
+fn run_script(
+  commands: &[(Command, Handler)],
+  script: &str,
+  echo: bool,
+  keep_going: bool,
+  session: &mut Session,
+) -> bool {
+  let parser = Command::new("i1")
+    .no_binary_name(true)
+    .subcommand_required(true)
+    .subcommands(commands.iter().map(|(command, _)| command.clone()));
+  let mut succeeded = true;
+  for (i, line) in script.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    if echo {
+      println!("> {}", line);
+    }
+    let ok = match split_words(line) {
+      Ok(words) => match parser.clone().try_get_matches_from(words) {
+        Ok(matches) => {
+          let (name, matches) = matches.subcommand().unwrap();
+          run_command(commands, name, matches, session)
+        }
+        Err(e) => match e.kind() {
+          clap::error::ErrorKind::DisplayHelp => {
+            print!("{}", e.render());
+            true
+          }
+          _ => {
+            eprint!("{}", e.render());
+            false
+          }
+        },
       },
+      Err(e) => {
+        eprintln!("{}", e);
+        false
+      }
+    };
+    if !ok {
+      eprintln!("Error: line {} of the script failed: {}", i + 1, line);
+      succeeded = false;
+      if !keep_going {
+        break;
+      }
+    }
+  }
+  succeeded
+}
+
+fn main() {
+  let state = Arc::new(RwLock::new(TotalState::new()));
+  let commands = commands(&state);
+  let mut cli = Command::new("i1")
+    .about("Version-controlled sets of UUIDs")
+    .arg(
+      Arg::new("format")
+        .long("format")
+        .global(true)
+        .value_parser(["text", "json"])
+        .default_value("text")
+        .help("Output format of the commands"),
     )
-    .with_command(Command::new("count"), |_, state| {
-      let state = state.read().unwrap();
-      Ok(Some(format!("Entities: {:?}", state.count())))
-    })
-    .with_command(Command::new("commits"), |_, state| {
-      let state = state.read().unwrap();
-      Ok(Some(
-        state
-          .commits()
-          .map(|uuid| format!("* {}", uuid.as_base64url()))
-          .collect::<Vec<_>>()
-          .join("\n"),
-      ))
-    })
-    .with_command(Command::new("heads"), |_, state| {
-      let state = state.read().unwrap();
-      Ok(Some(
-        state
-          .heads()
-          .map(|uuid| format!("* {}", uuid.as_base64url()))
-          .collect::<Vec<_>>()
-          .join("\n"),
-      ))
-    })
-    .with_command(Command::new("list"), |_, state| {
-      let state = state.read().unwrap();
-      Ok(Some(
-        state
-          .list()
-          .map(|uuid| format!("* {}", uuid.as_base64url()))
-          .collect::<Vec<_>>()
-          .join("\n"),
-      ))
+    .arg(
+      Arg::new("reachability-index")
+        .long("reachability-index")
+        .global(true)
+        .action(ArgAction::SetTrue)
+        .help("Index the ancestors of every commit, which speeds up merges and rebases in long histories"),
+    )
+    .arg(
+      Arg::new("script")
+        .long("script")
+        .value_name("FILE")
+        .help("Run the commands in FILE (or standard input, if FILE is -) and exit"),
+    )
+    .arg(
+      Arg::new("keep-going")
+        .long("keep-going")
+        .action(ArgAction::SetTrue)
+        .requires("script")
+        .help("Keep running the script after a command fails"),
+    )
+    .arg(
+      Arg::new("echo")
+        .long("echo")
+        .action(ArgAction::SetTrue)
+        .requires("script")
+        .help("Print each command of the script before running it"),
+    )
+    .subcommand(Command::new("repl").about("Start an interactive session (the default)"))
+    .subcommands(commands.iter().map(|(command, _)| command.clone()));
+  let args = cli.get_matches_mut();
+  // (only --script conflicts with subcommands; --format applies to them)
+  if args.contains_id("script") && args.subcommand().is_some() {
+    cli
+      .error(
+        ErrorKind::ArgumentConflict,
+        "--script can't be used with a subcommand",
+      )
+      .exit();
+  }
+  if args.get_flag("reachability-index") {
+    state.write().unwrap().enable_reachability_index();
+  }
+  let format_of = |matches: &ArgMatches| match matches.get_one::<String>("format").unwrap().as_str()
+  {
+    "json" => Format::Json,
+    _ => Format::Text,
+  };
+  let mut session = Session {
+    state: state.clone(),
+    format: format_of(&args),
+  };
+  if let Some(path) = args.get_one::<String>("script") {
+    let script = match path.as_str() {
+      "-" => std::io::read_to_string(std::io::stdin()),
+      path => std::fs::read_to_string(path),
+    };
+    let script = script.unwrap_or_else(|e| {
+      eprintln!("Error: could not read script {:?}: {}", path, e);
+      std::process::exit(1);
     });
-  let _ = repl.run();
+    let echo = args.get_flag("echo");
+    let keep_going = args.get_flag("keep-going");
+    if !run_script(&commands, &script, echo, keep_going, &mut session) {
+      std::process::exit(1);
+    }
+    return;
+  }
+  match args.subcommand() {
+    None | Some(("repl", _)) => {
+      let mut repl: Repl<_, CommandError> = Repl::new(session)
+        .with_name("uuid_set")
+        .with_partial_completions(true)
+        .with_on_after_command(|session| {
+          session.state.read().unwrap().save_working_patch();
+          Ok(None)
+        });
+      for (command, handler) in commands {
+        repl = repl.with_command(command, handler);
+      }
+      let _ = repl.run();
+    }
+    Some((name, matches)) => {
+      // run a single command and exit
+      session.format = format_of(matches);
+      if !run_command(&commands, name, matches, &mut session) {
+        std::process::exit(1);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn words(line: &str) -> Vec<String> {
+    split_words(line).unwrap()
+  }
+
+  #[test]
+  fn script_lines_split_into_words_like_a_shell() {
+    assert_eq!(
+      words("  set  Person/ada age\t36 "),
+      ["set", "Person/ada", "age", "36"]
+    );
+    assert_eq!(words(""), Vec::<String>::new());
+    // quotes group words, and can be empty or run into the rest of a word
+    assert_eq!(
+      words(r#"commit -m "two words" 'it''s'"#),
+      ["commit", "-m", "two words", "its"]
+    );
+    assert_eq!(words(r#"set x name """#), ["set", "x", "name", ""]);
+    assert_eq!(
+      words(r#"query "name(x) = 'Bob'""#),
+      ["query", "name(x) = 'Bob'"]
+    );
+    assert_eq!(words(r#"a"b c"d"#), ["ab cd"]);
+    // (there are no escapes: backslashes are kept as they are)
+    assert_eq!(
+      words(r#"set x name "a\" b"#),
+      ["set", "x", "name", "a\\", "b"]
+    );
+    // (and `#` starts a comment only at the start of a line, which `run_script` skips)
+    assert_eq!(words("set x name #1"), ["set", "x", "name", "#1"]);
+  }
+
+  #[test]
+  fn unterminated_quotes_are_errors() {
+    assert_eq!(
+      split_words(r#"commit -m "unfinished"#),
+      Err("Unterminated \" quote".to_string())
+    );
+    assert_eq!(
+      split_words("set x name 'a"),
+      Err("Unterminated ' quote".to_string())
+    );
+  }
 }
@@ -1,9 +1,13 @@
+// (the kinds of additions are all `New...`, as are the archived and resolver enums rkyv derives)
+#![allow(clippy::enum_variant_names)]
+
+use crate::attribute::*;
 use crate::id::*;
 use rkyv::{Archive, Deserialize, Serialize};
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use tinyvec::TinyVec;
 
-#[derive(Default, Archive, Deserialize, Serialize)]
+#[derive(Clone, Default, PartialEq, Archive, Deserialize, Serialize)]
 #[archive(check_bytes)]
 pub struct UuidSetPatch {
   pub deletions: BTreeSet<Uuid>,
@@ -24,30 +28,209 @@ impl UuidSetPatch {
 
 pub type UniversePatch = UuidSetPatch;
 
-#[derive(Archive, Deserialize, Serialize)]
+// The right-hand side of an equation in a rule: a variable (numbered from 0), an element, or a value
+// of an attribute.
+#[derive(Clone, Debug, PartialEq, Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+pub enum RuleTerm {
+  Var(u32),
+  Element(Uuid),
+  Value(AttrValue),
+}
+
+#[derive(Clone, Debug, PartialEq, Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+pub enum RuleAtom {
+  // `?x : sort`
+  Member {
+    var: u32,
+    sort: Uuid,
+  },
+  // `function(?x) = term`, for a hom or attribute
+  Equation {
+    function: Uuid,
+    arg: u32,
+    value: RuleTerm,
+  },
+}
+
+// A sequent `premises |- conclusion` (a Datalog rule): whenever the premises hold for some values
+// of the variables, the conclusion, an equation whose variables all appear in the premises, holds
+// too.
+#[derive(Clone, Debug, PartialEq, Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+pub struct Rule {
+  pub premises: Vec<RuleAtom>,
+  pub conclusion: RuleAtom,
+}
+
+impl Rule {
+  // UUIDs of the sorts, homs, attributes and elements that the rule mentions.
+  pub fn referenced_uuids(&self) -> Vec<Uuid> {
+    self
+      .premises
+      .iter()
+      .chain([&self.conclusion])
+      .flat_map(|atom| match atom {
+        RuleAtom::Member { sort, .. } => vec![*sort],
+        RuleAtom::Equation {
+          function,
+          value: RuleTerm::Element(element),
+          ..
+        } => vec![*function, *element],
+        RuleAtom::Equation { function, .. } => vec![*function],
+      })
+      .collect()
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Archive, Deserialize, Serialize)]
 #[archive(check_bytes)]
 pub enum AdditionKind {
   NewSort,
   NewEntity(Uuid),
+  // a function symbol from one sort to another (a morphism of the schema), which must have a value
+  // at every element of its domain unless it is partial or free; a free hom maps into a sort that
+  // need not normalize, and has its term (see `NewTerm`) as its value wherever it has no other
+  NewHom {
+    domain: Uuid,
+    codomain: Uuid,
+    partial: bool,
+    free: bool,
+  },
+  // an attribute of the entities of a sort, with values of a type (an element of S1)
+  NewAttr {
+    domain: Uuid,
+    attr_type: Uuid,
+  },
+  // a rule, from which the facts it derives follow in every version that has it (see
+  // `query::derive`)
+  NewRule(Rule),
+  // the term `hom(arg)`, an entity of the codomain of the hom that is its value at `arg` until it is
+  // equated with another entity (by merging it into that one, or by asserting that as the value);
+  // its UUID is `term_uuid(hom, arg)`, so the same term is the same element in every patch
+  NewTerm {
+    hom: Uuid,
+    arg: Uuid,
+  },
 }
 
-pub type AdditionKinds = Vec<AdditionKind>;
+// Kinds of the elements added by a patch (elements without one belong to no sort)
+pub type AdditionKinds = BTreeMap<Uuid, AdditionKind>;
 
-#[derive(Default, Archive, Deserialize, Serialize)]
+#[derive(Clone, Default, PartialEq, Archive, Deserialize, Serialize)]
 #[archive(check_bytes)]
 pub struct ContextPatch {
   pub deletions: BTreeSet<Vec<String>>,
   pub additions: HashMap<Vec<String>, Uuid>,
 }
 
+impl ContextPatch {
+  pub fn clear(&mut self) {
+    self.deletions.clear();
+    self.additions.clear();
+  }
+  pub fn is_empty(&self) -> bool {
+    self.deletions.is_empty() && self.additions.is_empty()
+  }
+}
+
+// Assertions `hom(element) = value`, keyed by (hom, element). Changing a value is a deletion
+// followed by an addition, as for namings.
+#[derive(Clone, Default, PartialEq, Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+pub struct HomPatch {
+  pub deletions: BTreeSet<(Uuid, Uuid)>,
+  pub additions: BTreeMap<(Uuid, Uuid), Uuid>,
+}
+
+impl HomPatch {
+  pub fn clear(&mut self) {
+    self.deletions.clear();
+    self.additions.clear();
+  }
+  pub fn is_empty(&self) -> bool {
+    self.deletions.is_empty() && self.additions.is_empty()
+  }
+}
+
+// Assertions `attr(element) = value`, keyed by (attribute, element), like `HomPatch`.
+#[derive(Clone, Default, PartialEq, Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+pub struct AttrPatch {
+  pub deletions: BTreeSet<(Uuid, Uuid)>,
+  pub additions: BTreeMap<(Uuid, Uuid), AttrValue>,
+}
+
+impl AttrPatch {
+  pub fn clear(&mut self) {
+    self.deletions.clear();
+    self.additions.clear();
+  }
+  pub fn is_empty(&self) -> bool {
+    self.deletions.is_empty() && self.additions.is_empty()
+  }
+}
+
+// Patch files begin with this magic number and the version of the format that follows it (the
+// archived `Patch`), which must be bumped whenever the layout of a `Patch` changes; files in other
+// formats are skipped when loading rather than misread. Files without the header are read as a
+// `LegacyPatch`.
+pub const PATCH_MAGIC: &[u8; 8] = b"i1patch\0";
+pub const PATCH_FORMAT_VERSION: u64 = 1;
+
+#[derive(Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+pub enum LegacyAdditionKind {
+  NewSort,
+  NewEntity(Uuid),
+}
+
+// The layout of a patch as written before patch files had a header, when patches only added,
+// deleted, merged and named elements.
 #[derive(Default, Archive, Deserialize, Serialize)]
 #[archive(check_bytes)]
+pub struct LegacyPatch {
+  pub target_commit: Uuid,
+  pub source_commits: TinyVec<[Uuid; 2]>,
+  pub universe_patch: UniversePatch,
+  pub addition_kinds: Vec<LegacyAdditionKind>,
+  pub context_patch: ContextPatch,
+}
+
+impl TryFrom<LegacyPatch> for Patch {
+  type Error = String;
+
+  // The kinds of additions were listed without the UUIDs they belong to (nothing recorded them), so
+  // a legacy patch that lists any can't be converted.
+  fn try_from(legacy: LegacyPatch) -> Result<Self, Self::Error> {
+    if !legacy.addition_kinds.is_empty() {
+      return Err(
+        "legacy patch lists kinds of additions, which can't be matched to its additions"
+          .to_string(),
+      );
+    }
+    Ok(Patch {
+      target_commit: legacy.target_commit,
+      source_commits: legacy.source_commits,
+      universe_patch: legacy.universe_patch,
+      context_patch: legacy.context_patch,
+      ..Default::default()
+    })
+  }
+}
+
+#[derive(Clone, Default, PartialEq, Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
 pub struct Patch {
   pub target_commit: Uuid,
   pub source_commits: TinyVec<[Uuid; 2]>,
   pub universe_patch: UniversePatch,
   pub addition_kinds: AdditionKinds,
   pub context_patch: ContextPatch,
+  pub hom_patch: HomPatch,
+  pub attr_patch: AttrPatch,
+  pub message: String,
 }
 
 impl Patch {
@@ -55,8 +238,60 @@ impl Patch {
     self.target_commit = Uuid::nil();
     self.source_commits.clear();
     self.universe_patch.clear();
+    self.addition_kinds.clear();
+    self.context_patch.clear();
+    self.hom_patch.clear();
+    self.attr_patch.clear();
+    self.message.clear();
+  }
+  // UUIDs of the elements that the patch adds, names or assigns (or retracts) hom or attribute
+  // values of, of the sorts it adds them to, and of what its rules and terms mention.
+  pub fn referenced_uuids(&self) -> impl Iterator<Item = Uuid> + '_ {
+    let sorts = self.addition_kinds.values().flat_map(|kind| match kind {
+      AdditionKind::NewEntity(sort) => vec![*sort],
+      AdditionKind::NewHom {
+        domain, codomain, ..
+      } => vec![*domain, *codomain],
+      AdditionKind::NewAttr { domain, .. } => vec![*domain],
+      AdditionKind::NewRule(rule) => rule.referenced_uuids(),
+      AdditionKind::NewTerm { hom, arg } => vec![*hom, *arg],
+      AdditionKind::NewSort => vec![],
+    });
+    let hom_values = self
+      .hom_patch
+      .additions
+      .iter()
+      .flat_map(|((hom, element), value)| [*hom, *element, *value])
+      .chain(
+        self
+          .hom_patch
+          .deletions
+          .iter()
+          .flat_map(|(hom, element)| [*hom, *element]),
+      );
+    let attr_values = self
+      .attr_patch
+      .additions
+      .keys()
+      .chain(self.attr_patch.deletions.iter())
+      .flat_map(|(attr, element)| [*attr, *element]);
+    self
+      .universe_patch
+      .additions
+      .iter()
+      .copied()
+      .chain(sorts)
+      .chain(self.context_patch.additions.values().copied())
+      .chain(hom_values)
+      .chain(attr_values)
   }
   pub fn is_empty(&self) -> bool {
+    // (a pending merge is not empty even if it doesn't delete anything)
     self.universe_patch.is_empty()
+      && self.addition_kinds.is_empty()
+      && self.context_patch.is_empty()
+      && self.hom_patch.is_empty()
+      && self.attr_patch.is_empty()
+      && self.source_commits.len() <= 1
   }
 }
@@ -0,0 +1,147 @@
+use crate::id::*;
+use crate::query::*;
+use crate::version::*;
+
+type Binding = Vec<Option<QueryValue<Luid>>>;
+
+// The pairs (element, value) of a hom or attribute that are consistent with a binding: just the
+// one at the argument, if it is bound, or else every assigned value in the column.
+fn function_values<'a>(
+  version: &'a Version,
+  atom: &'a ResolvedAtom,
+  binding: &Binding,
+) -> Box<dyn Iterator<Item = (Luid, QueryValue<Luid>)> + 'a> {
+  let (domain, arg) = match atom {
+    ResolvedAtom::Hom { hom, arg, .. } => (version.homs.get(hom).map(|hom| hom.domain), *arg),
+    ResolvedAtom::Attr { attr, arg, .. } => (version.attrs.get(attr).map(|attr| attr.domain), *arg),
+    ResolvedAtom::Member { .. } => unreachable!(),
+  };
+  let Some(elements) = domain.and_then(|domain| version.sort_elements(domain)) else {
+    return Box::new(std::iter::empty());
+  };
+  let value_at = move |slid: usize| -> Option<QueryValue<Luid>> {
+    match atom {
+      ResolvedAtom::Hom { hom, .. } => {
+        let value = version.homs.get(hom).unwrap().column[slid]?;
+        Some(QueryValue::Element(value))
+      }
+      ResolvedAtom::Attr { attr, .. } => {
+        let value = version.attrs.get(attr).unwrap().column[slid].clone()?;
+        Some(QueryValue::Value(value))
+      }
+      ResolvedAtom::Member { .. } => unreachable!(),
+    }
+  };
+  match &binding[arg] {
+    Some(QueryValue::Element(element)) => {
+      let element = *element;
+      if !elements.contains(element as u64) {
+        return Box::new(std::iter::empty());
+      }
+      let slid = elements.rank(element as u64) as usize - 1;
+      Box::new(value_at(slid).map(|value| (element, value)).into_iter())
+    }
+    Some(QueryValue::Value(_)) => Box::new(std::iter::empty()),
+    None => Box::new(
+      elements
+        .iter()
+        .enumerate()
+        .filter_map(move |(slid, element)| Some((element as Luid, value_at(slid)?))),
+    ),
+  }
+}
+
+// The right-hand side of a hom or attribute atom.
+fn value_side(atom: &ResolvedAtom) -> Bound<QueryValue<Luid>> {
+  match atom {
+    ResolvedAtom::Hom { value, .. } => match value {
+      Bound::Var(var) => Bound::Var(*var),
+      Bound::Const(value) => Bound::Const(QueryValue::Element(*value)),
+    },
+    ResolvedAtom::Attr { value, .. } => match value {
+      Bound::Var(var) => Bound::Var(*var),
+      Bound::Const(value) => Bound::Const(QueryValue::Value(value.clone())),
+    },
+    ResolvedAtom::Member { .. } => unreachable!(),
+  }
+}
+
+// Bind a variable (if it isn't already), or check that it is bound to `value`. Returns whether the
+// binding is consistent, and whether the variable was newly bound (to be unbound afterwards).
+fn unify(binding: &mut Binding, var: usize, value: QueryValue<Luid>) -> (bool, bool) {
+  match &binding[var] {
+    Some(bound) => (*bound == value, false),
+    None => {
+      binding[var] = Some(value);
+      (true, true)
+    }
+  }
+}
+
+fn extend(
+  plan: &Plan,
+  version: &Version,
+  i: usize,
+  binding: &mut Binding,
+  rows: &mut Vec<Vec<QueryValue<Luid>>>,
+) {
+  let Some(atom) = plan.atoms.get(i) else {
+    rows.push(binding.iter().map(|value| value.clone().unwrap()).collect());
+    return;
+  };
+  match atom {
+    ResolvedAtom::Member { var, sort } => {
+      let Some(elements) = version.sort_elements(*sort) else {
+        return;
+      };
+      match &binding[*var] {
+        Some(QueryValue::Element(element)) => {
+          if elements.contains(*element as u64) {
+            extend(plan, version, i + 1, binding, rows);
+          }
+        }
+        Some(QueryValue::Value(_)) => {}
+        None => {
+          for element in elements.iter() {
+            binding[*var] = Some(QueryValue::Element(element as Luid));
+            extend(plan, version, i + 1, binding, rows);
+          }
+          binding[*var] = None;
+        }
+      }
+    }
+    ResolvedAtom::Hom { arg, .. } | ResolvedAtom::Attr { arg, .. } => {
+      let (expected, value_var) = match value_side(atom) {
+        Bound::Const(value) => (Some(value), None),
+        Bound::Var(var) => (None, Some(var)),
+      };
+      let candidates = function_values(version, atom, binding)
+        .filter(|(_, value)| expected.as_ref().is_none_or(|expected| expected == value));
+      for (element, value) in candidates {
+        let (arg_ok, arg_new) = unify(binding, *arg, QueryValue::Element(element));
+        let (value_ok, value_new) = match (arg_ok, value_var) {
+          (true, Some(var)) => unify(binding, var, value),
+          (ok, _) => (ok, false),
+        };
+        if arg_ok && value_ok {
+          extend(plan, version, i + 1, binding, rows);
+        }
+        if value_new {
+          binding[value_var.unwrap()] = None;
+        }
+        if arg_new {
+          binding[*arg] = None;
+        }
+      }
+    }
+  }
+}
+
+// Evaluate a plan by nested loops: each atom in turn extends the bindings of the atoms before it,
+// looking up function values at bound arguments and scanning sorts and columns otherwise.
+pub fn execute(plan: &Plan, version: &Version) -> Vec<Vec<QueryValue<Luid>>> {
+  let mut rows = Vec::new();
+  let mut binding = vec![None; plan.variables.len()];
+  extend(plan, version, 0, &mut binding, &mut rows);
+  rows
+}
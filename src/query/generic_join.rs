@@ -0,0 +1,398 @@
+use crate::id::*;
+use crate::query::*;
+use crate::shared_treemap::SharedTreemap;
+use crate::version::*;
+use std::collections::HashMap;
+
+// An attribute value in a form that can be hashed: floats by their bits, with -0.0 as 0.0 (as
+// adding 0.0 gives). NaN, which equals nothing, has no key.
+#[derive(PartialEq, Eq, Hash)]
+enum ValueKey {
+  String(String),
+  I64(i64),
+  F64(u64),
+  Bool(bool),
+  Bytes(Vec<u8>),
+  Timestamp(i64),
+  Custom(Uuid, Vec<u8>),
+}
+
+impl ValueKey {
+  fn new(value: &AttrValue) -> Option<Self> {
+    Some(match value {
+      AttrValue::String(string) => ValueKey::String(string.clone()),
+      AttrValue::I64(i) => ValueKey::I64(*i),
+      AttrValue::F64(f) if f.is_nan() => return None,
+      AttrValue::F64(f) => ValueKey::F64((f + 0.0).to_bits()),
+      AttrValue::Bool(b) => ValueKey::Bool(*b),
+      AttrValue::Bytes(bytes) => ValueKey::Bytes(bytes.clone()),
+      AttrValue::Timestamp(t) => ValueKey::Timestamp(*t),
+      AttrValue::Custom { attr_type, bytes } => ValueKey::Custom(*attr_type, bytes.clone()),
+    })
+  }
+}
+
+// Attribute values numbered, so that variables bound to them can be joined on like elements.
+#[derive(Default)]
+pub(super) struct Values {
+  ids: HashMap<ValueKey, u64>,
+  values: Vec<AttrValue>,
+}
+
+impl Values {
+  // The number of a value; values without a key get a new number each time.
+  pub(super) fn intern(&mut self, value: &AttrValue) -> u64 {
+    let key = ValueKey::new(value);
+    if let Some(id) = key.as_ref().and_then(|key| self.ids.get(key)) {
+      return *id;
+    }
+    let id = self.values.len() as u64;
+    self.values.push(value.clone());
+    if let Some(key) = key {
+      self.ids.insert(key, id);
+    }
+    id
+  }
+
+  pub(super) fn get(&self, id: u64) -> &AttrValue {
+    &self.values[id as usize]
+  }
+}
+
+// A hom or attribute as a binary relation from elements to values, indexed both ways. Values are
+// elements for homs, and numbers from `Values` for attributes.
+#[derive(Default)]
+pub(super) struct Relation {
+  forward: HashMap<u64, u64>,
+  inverse: HashMap<u64, SharedTreemap>,
+  // the elements at which the function has a value
+  defined: SharedTreemap,
+  image: SharedTreemap,
+}
+
+impl Relation {
+  // The values of a hom or attribute in a version (none, if it is neither).
+  pub(super) fn of_function(version: &Version, function: Luid, values: &mut Values) -> Self {
+    let mut relation = Relation::default();
+    if let Some(hom) = version.homs.get(&function) {
+      let elements = version
+        .sort_elements(hom.domain)
+        .cloned()
+        .unwrap_or_default();
+      for (element, value) in elements.iter().zip(hom.column.iter()) {
+        if let Some(value) = value {
+          relation.insert(element, *value as u64);
+        }
+      }
+    } else if let Some(attr) = version.attrs.get(&function) {
+      let elements = version
+        .sort_elements(attr.domain)
+        .cloned()
+        .unwrap_or_default();
+      for (element, value) in elements.iter().zip(attr.column.iter()) {
+        if let Some(value) = value {
+          relation.insert(element, values.intern(value));
+        }
+      }
+    }
+    relation
+  }
+
+  // Add a pair, unless the element has a value already; returns whether it was added.
+  pub(super) fn insert(&mut self, element: u64, value: u64) -> bool {
+    if self.forward.contains_key(&element) {
+      return false;
+    }
+    self.forward.insert(element, value);
+    self.inverse.entry(value).or_default().insert(element);
+    self.defined.insert(element);
+    self.image.insert(value);
+    true
+  }
+
+  pub(super) fn is_empty(&self) -> bool {
+    self.forward.is_empty()
+  }
+}
+
+// What an atom is evaluated over: the elements of its sort, or the relation of its function.
+pub(super) enum Source<'a> {
+  Elements(&'a SharedTreemap),
+  Relation(&'a Relation),
+}
+
+pub(super) enum JoinAtom<'a> {
+  Member {
+    var: usize,
+    elements: &'a SharedTreemap,
+  },
+  Function {
+    relation: &'a Relation,
+    arg: usize,
+    value: Bound<u64>,
+  },
+}
+
+impl<'a> JoinAtom<'a> {
+  pub(super) fn new(atom: &ResolvedAtom, source: Source<'a>, values: &mut Values) -> Self {
+    match (atom, source) {
+      (ResolvedAtom::Member { var, .. }, Source::Elements(elements)) => JoinAtom::Member {
+        var: *var,
+        elements,
+      },
+      (ResolvedAtom::Hom { arg, value, .. }, Source::Relation(relation)) => JoinAtom::Function {
+        relation,
+        arg: *arg,
+        value: match value {
+          Bound::Var(var) => Bound::Var(*var),
+          Bound::Const(value) => Bound::Const(*value as u64),
+        },
+      },
+      (ResolvedAtom::Attr { arg, value, .. }, Source::Relation(relation)) => JoinAtom::Function {
+        relation,
+        arg: *arg,
+        value: match value {
+          Bound::Var(var) => Bound::Var(*var),
+          Bound::Const(value) => Bound::Const(values.intern(value)),
+        },
+      },
+      _ => unreachable!(),
+    }
+  }
+}
+
+// Which variables of some atoms stand for attribute values rather than elements, or `None` if one
+// stands for both (so that the atoms can't be satisfied together).
+pub(super) fn value_variables(atoms: &[ResolvedAtom], variables: usize) -> Option<Vec<bool>> {
+  let mut is_element = vec![false; variables];
+  let mut is_value = vec![false; variables];
+  for atom in atoms.iter() {
+    match atom {
+      ResolvedAtom::Member { var, .. } => is_element[*var] = true,
+      ResolvedAtom::Hom { arg, value, .. } => {
+        is_element[*arg] = true;
+        if let Bound::Var(var) = value {
+          is_element[*var] = true;
+        }
+      }
+      ResolvedAtom::Attr { arg, value, .. } => {
+        is_element[*arg] = true;
+        if let Bound::Var(var) = value {
+          is_value[*var] = true;
+        }
+      }
+    }
+  }
+  match (0..variables).any(|var| is_element[var] && is_value[var]) {
+    true => None,
+    false => Some(is_value),
+  }
+}
+
+pub(super) struct Join<'a> {
+  atoms: Vec<JoinAtom<'a>>,
+  // the atoms that each variable appears in
+  occurrences: Vec<Vec<usize>>,
+}
+
+impl<'a> Join<'a> {
+  pub(super) fn new(variables: usize, atoms: Vec<JoinAtom<'a>>) -> Self {
+    let mut occurrences = vec![Vec::new(); variables];
+    for (i, atom) in atoms.iter().enumerate() {
+      let (first, second) = match atom {
+        JoinAtom::Member { var, .. } => (*var, None),
+        JoinAtom::Function { arg, value, .. } => match value {
+          Bound::Var(var) if var != arg => (*arg, Some(*var)),
+          _ => (*arg, None),
+        },
+      };
+      occurrences[first].push(i);
+      if let Some(second) = second {
+        occurrences[second].push(i);
+      }
+    }
+    Join { atoms, occurrences }
+  }
+
+  // The values of `var` that an atom allows, given the variables bound so far.
+  fn candidates(&self, atom: &JoinAtom, var: usize, binding: &[Option<u64>]) -> SharedTreemap {
+    let (relation, arg, value) = match atom {
+      JoinAtom::Member { elements, .. } => return (*elements).clone(),
+      JoinAtom::Function {
+        relation,
+        arg,
+        value,
+      } => (relation, *arg, value),
+    };
+    if arg == var {
+      let value = match value {
+        Bound::Var(value) => binding[*value],
+        Bound::Const(value) => Some(*value),
+      };
+      return match value {
+        Some(value) => relation.inverse.get(&value).cloned().unwrap_or_default(),
+        None => relation.defined.clone(),
+      };
+    }
+    match binding[arg] {
+      Some(arg) => {
+        let mut value = SharedTreemap::new();
+        if let Some(v) = relation.forward.get(&arg) {
+          value.insert(*v);
+        }
+        value
+      }
+      None => relation.image.clone(),
+    }
+  }
+
+  // An estimate of the number of candidates for `var` given which variables are bound: the
+  // fewest that any one of its atoms allows, taking a function's average number of elements per
+  // value where its value is known.
+  fn estimate(&self, var: usize, bound: &[bool]) -> u64 {
+    let estimate = |atom: &JoinAtom| match atom {
+      JoinAtom::Member { elements, .. } => elements.len(),
+      JoinAtom::Function {
+        relation,
+        arg,
+        value,
+      } => {
+        let value_bound = match value {
+          Bound::Var(value) => *value != var && bound[*value],
+          Bound::Const(_) => true,
+        };
+        match (*arg == var, value_bound) {
+          (true, true) => relation.defined.len().div_ceil(relation.image.len().max(1)),
+          (true, false) => relation.defined.len(),
+          (false, _) if bound[*arg] => 1,
+          (false, _) => relation.image.len(),
+        }
+      }
+    };
+    self.occurrences[var]
+      .iter()
+      .map(|atom| estimate(&self.atoms[*atom]))
+      .min()
+      .unwrap()
+  }
+
+  // The order in which to bind the variables: at each step, the one with the fewest candidates
+  // expected given the variables before it.
+  fn order(&self) -> Vec<usize> {
+    let mut bound = vec![false; self.occurrences.len()];
+    let mut order = Vec::with_capacity(bound.len());
+    while order.len() < bound.len() {
+      let var = (0..bound.len())
+        .filter(|var| !bound[*var])
+        .min_by_key(|var| self.estimate(*var, &bound))
+        .unwrap();
+      bound[var] = true;
+      order.push(var);
+    }
+    order
+  }
+
+  fn extend(&self, order: &[usize], binding: &mut Vec<Option<u64>>, rows: &mut Vec<Vec<u64>>) {
+    let Some((&var, rest)) = order.split_first() else {
+      rows.push(binding.iter().map(|value| value.unwrap()).collect());
+      return;
+    };
+    let mut sets: Vec<SharedTreemap> = self.occurrences[var]
+      .iter()
+      .map(|atom| self.candidates(&self.atoms[*atom], var, binding))
+      .collect();
+    sets.sort_by_key(|set| set.len());
+    let mut candidates = sets[0].clone();
+    for set in sets[1..].iter() {
+      if candidates.is_empty() {
+        break;
+      }
+      candidates = candidates.intersection(set);
+    }
+    // (every candidate satisfies the atoms of `var` already, except for `f(?x) = ?x`, which only
+    // constrains `?x` to where `f` is defined)
+    let loops: Vec<&Relation> = self.occurrences[var]
+      .iter()
+      .filter_map(|atom| match &self.atoms[*atom] {
+        JoinAtom::Function {
+          relation,
+          arg,
+          value: Bound::Var(value),
+        } if *arg == var && *value == var => Some(*relation),
+        _ => None,
+      })
+      .collect();
+    for value in candidates.iter() {
+      if loops
+        .iter()
+        .all(|relation| relation.forward.get(&value) == Some(&value))
+      {
+        binding[var] = Some(value);
+        self.extend(rest, binding, rows);
+      }
+    }
+    binding[var] = None;
+  }
+
+  // Every binding of the variables (to elements, and to numbers of attribute values) that
+  // satisfies all of the atoms.
+  pub(super) fn rows(&self) -> Vec<Vec<u64>> {
+    let mut rows = Vec::new();
+    let mut binding = vec![None; self.occurrences.len()];
+    self.extend(&self.order(), &mut binding, &mut rows);
+    rows
+  }
+}
+
+// Evaluate a plan by Generic Join (Ngo, Ré and Rudra, "Skew strikes back"), the simplest of the
+// worst-case optimal joins that Free Join generalizes: variables are bound one at a time, each to
+// the intersection of what every atom it appears in allows given the variables before it. Each
+// hom and attribute of the query is indexed both ways first, by element and by value; the order
+// of the plan's atoms doesn't matter here.
+pub fn generic_join(plan: &Plan, version: &Version) -> Vec<Vec<QueryValue<Luid>>> {
+  let variables = plan.variables.len();
+  let Some(is_value) = value_variables(&plan.atoms, variables) else {
+    return Vec::new();
+  };
+  let mut values = Values::default();
+  let mut relations = HashMap::new();
+  for atom in plan.atoms.iter() {
+    if let ResolvedAtom::Hom { hom: function, .. } | ResolvedAtom::Attr { attr: function, .. } =
+      atom
+    {
+      relations
+        .entry(*function)
+        .or_insert_with(|| Relation::of_function(version, *function, &mut values));
+    }
+  }
+  let no_elements = SharedTreemap::new();
+  let atoms = plan
+    .atoms
+    .iter()
+    .map(|atom| {
+      let source = match atom {
+        ResolvedAtom::Member { sort, .. } => {
+          Source::Elements(version.sort_elements(*sort).unwrap_or(&no_elements))
+        }
+        ResolvedAtom::Hom { hom: function, .. } | ResolvedAtom::Attr { attr: function, .. } => {
+          Source::Relation(&relations[function])
+        }
+      };
+      JoinAtom::new(atom, source, &mut values)
+    })
+    .collect();
+  Join::new(variables, atoms)
+    .rows()
+    .into_iter()
+    .map(|row| {
+      row
+        .into_iter()
+        .enumerate()
+        .map(|(var, id)| match is_value[var] {
+          true => QueryValue::Value(values.get(id).clone()),
+          false => QueryValue::Element(id as Luid),
+        })
+        .collect()
+    })
+    .collect()
+}
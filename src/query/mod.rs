@@ -0,0 +1,171 @@
+// Conjunctive queries over the sorts, homs and attributes of a version, such as
+//
+//   ?x : Person, name(x) = "Bob", employer(x) = ?y
+//
+// A query is parsed (`parse`) into atoms with names in them, which `TotalState::query` resolves
+// against the working version; the resolved atoms are then evaluated by `generic_join`, a
+// worst-case optimal join that binds one variable at a time by intersecting sets of elements, or
+// else ordered by `plan` and evaluated by nested loops in `execute` (see `JoinStrategy`).
+//
+// Rules (`parse_rule`) are stored in patches, and the facts that they derive are computed with
+// every version by `derive`, by semi-naive evaluation on the same joins.
+
+use crate::attribute::*;
+use crate::id::*;
+use std::collections::BTreeMap;
+
+mod execute;
+pub use execute::execute;
+mod generic_join;
+pub use generic_join::generic_join;
+mod parse;
+pub use parse::{parse, parse_rule};
+mod plan;
+pub use plan::{plan, Plan};
+mod rules;
+pub use rules::{derive, Delta, Derived};
+
+// Either side of an equation, before names are resolved.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Term {
+  Var(String),
+  // a name or UUID of an element, or the textual form of an attribute value
+  Constant(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Atom {
+  // `?x : Sort`
+  Member {
+    var: String,
+    sort: String,
+  },
+  // `f(?x) = term`, for a hom or attribute `f`
+  Equation {
+    function: String,
+    arg: String,
+    value: Term,
+  },
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query {
+  pub atoms: Vec<Atom>,
+}
+
+impl Query {
+  // The variables of the query, in order of first appearance.
+  pub fn variables(&self) -> Vec<String> {
+    let mut variables: Vec<String> = Vec::new();
+    let mut add = |var: &String| {
+      if !variables.contains(var) {
+        variables.push(var.clone());
+      }
+    };
+    for atom in self.atoms.iter() {
+      match atom {
+        Atom::Member { var, .. } => add(var),
+        Atom::Equation { arg, value, .. } => {
+          add(arg);
+          if let Term::Var(var) = value {
+            add(var);
+          }
+        }
+      }
+    }
+    variables
+  }
+}
+
+// The value a variable is bound to: an element (of a sort), or the value of an attribute.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryValue<E = Uuid> {
+  Element(E),
+  Value(AttrValue),
+}
+
+// A variable (by its index among the query's variables), or a constant.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Bound<T> {
+  Var(usize),
+  Const(T),
+}
+
+// An atom with its names resolved to the elements of a version.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResolvedAtom {
+  Member {
+    var: usize,
+    sort: Luid,
+  },
+  Hom {
+    hom: Luid,
+    arg: usize,
+    value: Bound<Luid>,
+  },
+  Attr {
+    attr: Luid,
+    arg: usize,
+    value: Bound<AttrValue>,
+  },
+}
+
+// How `TotalState::query_with` evaluates a query.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JoinStrategy {
+  // one variable at a time, intersecting what every atom it appears in allows (`generic_join`)
+  #[default]
+  GenericJoin,
+  // one atom at a time, in the order given by `plan` (`execute`)
+  NestedLoops,
+}
+
+// A rule (see `patch::Rule`) with its UUIDs resolved to the elements of a version.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolvedRule {
+  pub variables: usize,
+  pub premises: Vec<ResolvedAtom>,
+  pub conclusion: ResolvedAtom,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct QueryResult {
+  pub variables: Vec<String>,
+  pub rows: Vec<Vec<QueryValue>>,
+  // the textual forms of the terms among the elements in the rows (see `TotalState::term_text`)
+  pub terms: BTreeMap<Uuid, String>,
+}
+
+#[derive(Clone, Debug)]
+pub enum QueryError {
+  // (byte offset in the query, and what was expected there)
+  Syntax { offset: usize, expected: String },
+  UnknownSort(String),
+  UnknownFunction(String),
+  AmbiguousFunction(String),
+  UnknownElement(String),
+  InvalidValue(String),
+  InvalidRule(String),
+}
+
+impl std::fmt::Display for QueryError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      QueryError::Syntax { offset, expected } => {
+        write!(
+          f,
+          "Syntax error at offset {}: expected {}",
+          offset, expected
+        )
+      }
+      QueryError::UnknownSort(name) => write!(f, "{} is not a sort", name),
+      QueryError::UnknownFunction(name) => write!(f, "{} is not a hom or attribute", name),
+      QueryError::AmbiguousFunction(name) => {
+        write!(f, "{} names several homs or attributes", name)
+      }
+      QueryError::UnknownElement(name) => write!(f, "{} is not in the working set", name),
+      QueryError::InvalidValue(message) => write!(f, "{}", message),
+      QueryError::InvalidRule(message) => write!(f, "Invalid rule: {}", message),
+    }
+  }
+}
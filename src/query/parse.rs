@@ -0,0 +1,247 @@
+use crate::query::*;
+
+// Characters of names and of unquoted constants: names are paths with parts separated by `/`,
+// and UUIDs are base64url; numbers and booleans fit too (RFC 3339 timestamps, with their colons,
+// have to be quoted).
+fn is_word_char(c: char) -> bool {
+  c.is_alphanumeric() || matches!(c, '_' | '-' | '/' | '.' | '+')
+}
+
+struct Parser<'a> {
+  text: &'a str,
+  offset: usize,
+}
+
+impl<'a> Parser<'a> {
+  fn rest(&self) -> &'a str {
+    &self.text[self.offset..]
+  }
+
+  fn skip_whitespace(&mut self) {
+    let rest = self.rest();
+    self.offset += rest.len() - rest.trim_start().len();
+  }
+
+  fn error<T>(&self, expected: &str) -> Result<T, QueryError> {
+    Err(QueryError::Syntax {
+      offset: self.offset,
+      expected: expected.to_string(),
+    })
+  }
+
+  fn eat(&mut self, c: char) -> bool {
+    self.skip_whitespace();
+    if self.rest().starts_with(c) {
+      self.offset += c.len_utf8();
+      true
+    } else {
+      false
+    }
+  }
+
+  fn expect(&mut self, c: char) -> Result<(), QueryError> {
+    match self.eat(c) {
+      true => Ok(()),
+      false => self.error(&format!("'{}'", c)),
+    }
+  }
+
+  fn word(&mut self, expected: &str) -> Result<String, QueryError> {
+    self.skip_whitespace();
+    let rest = self.rest();
+    let len = rest.find(|c| !is_word_char(c)).unwrap_or(rest.len());
+    if len == 0 {
+      return self.error(expected);
+    }
+    self.offset += len;
+    Ok(rest[..len].to_string())
+  }
+
+  // `?x`, or (where only a variable can appear, such as the argument of a function) just `x`
+  fn var(&mut self, question_mark_optional: bool) -> Result<String, QueryError> {
+    if !self.eat('?') && !question_mark_optional {
+      return self.error("a variable");
+    }
+    self.word("a variable name")
+  }
+
+  // a string in double quotes, in which `\"` and `\\` stand for `"` and `\`
+  fn quoted(&mut self) -> Result<String, QueryError> {
+    let mut value = String::new();
+    let mut chars = self.rest().char_indices();
+    while let Some((i, c)) = chars.next() {
+      match c {
+        '"' => {
+          self.offset += i + 1;
+          return Ok(value);
+        }
+        '\\' => match chars.next() {
+          Some((_, c @ ('"' | '\\'))) => value.push(c),
+          _ => {
+            self.offset += i;
+            return self.error("\\\" or \\\\");
+          }
+        },
+        c => value.push(c),
+      }
+    }
+    self.offset = self.text.len();
+    self.error("a closing '\"'")
+  }
+
+  fn term(&mut self) -> Result<Term, QueryError> {
+    self.skip_whitespace();
+    if self.rest().starts_with('?') {
+      Ok(Term::Var(self.var(false)?))
+    } else if self.eat('"') {
+      Ok(Term::Constant(self.quoted()?))
+    } else {
+      Ok(Term::Constant(self.word("a variable, a name or a value")?))
+    }
+  }
+
+  fn atom(&mut self) -> Result<Atom, QueryError> {
+    self.skip_whitespace();
+    if self.rest().starts_with('?') {
+      let var = self.var(false)?;
+      self.expect(':')?;
+      let sort = self.word("a sort")?;
+      return Ok(Atom::Member { var, sort });
+    }
+    let function = self.word("'?' or a hom or attribute")?;
+    self.expect('(')?;
+    let arg = self.var(true)?;
+    self.expect(')')?;
+    self.expect('=')?;
+    let value = self.term()?;
+    Ok(Atom::Equation {
+      function,
+      arg,
+      value,
+    })
+  }
+
+  // atoms separated by commas, up to the end of the text
+  fn atoms(&mut self) -> Result<Vec<Atom>, QueryError> {
+    let mut atoms = Vec::new();
+    loop {
+      atoms.push(self.atom()?);
+      if !self.eat(',') {
+        break;
+      }
+    }
+    self.skip_whitespace();
+    if !self.rest().is_empty() {
+      return self.error("',' or the end of the query");
+    }
+    Ok(atoms)
+  }
+}
+
+// Parse a query: atoms `?x : Sort` and `f(?x) = term` separated by commas, where a term is a
+// variable `?y`, a name or UUID of an element, or a value (in double quotes if it isn't a single
+// word).
+pub fn parse(text: &str) -> Result<Query, QueryError> {
+  let mut parser = Parser { text, offset: 0 };
+  Ok(Query {
+    atoms: parser.atoms()?,
+  })
+}
+
+// Parse a rule `conclusion :- premises`, where the conclusion is an atom and the premises are as in
+// a query, such as `grandparent(x) = ?z :- parent(x) = ?y, parent(y) = ?z`.
+pub fn parse_rule(text: &str) -> Result<(Atom, Query), QueryError> {
+  let mut parser = Parser { text, offset: 0 };
+  let conclusion = parser.atom()?;
+  parser.skip_whitespace();
+  if !parser.rest().starts_with(":-") {
+    return parser.error("':-'");
+  }
+  parser.offset += 2;
+  Ok((
+    conclusion,
+    Query {
+      atoms: parser.atoms()?,
+    },
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn var(name: &str) -> Term {
+    Term::Var(name.to_string())
+  }
+
+  fn constant(text: &str) -> Term {
+    Term::Constant(text.to_string())
+  }
+
+  fn equation(function: &str, arg: &str, value: Term) -> Atom {
+    Atom::Equation {
+      function: function.to_string(),
+      arg: arg.to_string(),
+      value,
+    }
+  }
+
+  #[test]
+  fn queries_parse_into_atoms() {
+    let query = parse(r#"?x : Person, name( x ) = "Bob \"the\" \\", employer(?x)=?y,age(x) = 42"#);
+    assert_eq!(
+      query.unwrap().atoms,
+      vec![
+        Atom::Member {
+          var: "x".to_string(),
+          sort: "Person".to_string(),
+        },
+        equation("name", "x", constant(r#"Bob "the" \"#)),
+        equation("employer", "x", var("y")),
+        equation("age", "x", constant("42")),
+      ]
+    );
+    // (names are paths, and values with other characters than those of words are quoted)
+    assert_eq!(
+      parse(r#"Person/born(p) = "1990-01-01T00:00:00Z""#)
+        .unwrap()
+        .atoms,
+      vec![equation(
+        "Person/born",
+        "p",
+        constant("1990-01-01T00:00:00Z")
+      )]
+    );
+  }
+
+  #[test]
+  fn syntax_errors_say_where_and_what_was_expected() {
+    let error = |text: &str| match parse(text) {
+      Err(QueryError::Syntax { offset, expected }) => (offset, expected),
+      other => panic!("expected a syntax error, got {:?}", other),
+    };
+    assert_eq!(error(""), (0, "'?' or a hom or attribute".to_string()));
+    assert_eq!(error("?x Person"), (3, "':'".to_string()));
+    assert_eq!(
+      error("f(x) = ?y ?z"),
+      (10, "',' or the end of the query".to_string())
+    );
+    assert_eq!(error(r#"f(x) = "open"#), (12, "a closing '\"'".to_string()));
+    assert_eq!(error(r#"f(x) = "\n""#), (8, r#"\" or \\"#.to_string()));
+  }
+
+  #[test]
+  fn rules_parse_into_a_conclusion_and_premises() {
+    let (conclusion, premises) =
+      parse_rule("grandparent(x) = ?z :- parent(x) = ?y, parent(y) = ?z").unwrap();
+    assert_eq!(conclusion, equation("grandparent", "x", var("z")));
+    assert_eq!(
+      premises.atoms,
+      vec![
+        equation("parent", "x", var("y")),
+        equation("parent", "y", var("z")),
+      ]
+    );
+    assert!(parse_rule("grandparent(x) = ?z").is_err());
+  }
+}
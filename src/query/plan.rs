@@ -0,0 +1,94 @@
+use crate::id::*;
+use crate::query::*;
+use crate::version::*;
+
+// Resolved atoms in the order in which they are evaluated.
+#[derive(Clone, Debug, Default)]
+pub struct Plan {
+  pub variables: Vec<String>,
+  pub atoms: Vec<ResolvedAtom>,
+}
+
+impl ResolvedAtom {
+  // The variables that evaluating the atom binds (if they aren't bound already).
+  pub fn variables(&self) -> Vec<usize> {
+    match self {
+      ResolvedAtom::Member { var, .. } => vec![*var],
+      ResolvedAtom::Hom { arg, value, .. } => match value {
+        Bound::Var(var) => vec![*arg, *var],
+        Bound::Const(_) => vec![*arg],
+      },
+      ResolvedAtom::Attr { arg, value, .. } => match value {
+        Bound::Var(var) => vec![*arg, *var],
+        Bound::Const(_) => vec![*arg],
+      },
+    }
+  }
+
+  // An estimate of how many bindings evaluating the atom yields for each binding it starts from,
+  // given which variables are bound then; ties go to atoms whose value side is already known, as
+  // those filter what they scan.
+  fn cost(&self, version: &Version, bound: &[bool]) -> (u64, bool) {
+    let sort_len = |sort: Luid| {
+      version
+        .sort_elements(sort)
+        .map_or(0, |elements| elements.len())
+    };
+    let value_bound = |value: Option<usize>| value.is_none_or(|var| bound[var]);
+    let (arg, domain, value) = match self {
+      ResolvedAtom::Member { var, sort } => {
+        return match bound[*var] {
+          true => (1, true),
+          false => (sort_len(*sort), true),
+        };
+      }
+      ResolvedAtom::Hom { hom, arg, value } => (
+        arg,
+        version.homs.get(hom).map(|hom| hom.domain),
+        match value {
+          Bound::Var(var) => Some(*var),
+          Bound::Const(_) => None,
+        },
+      ),
+      ResolvedAtom::Attr { attr, arg, value } => (
+        arg,
+        version.attrs.get(attr).map(|attr| attr.domain),
+        match value {
+          Bound::Var(var) => Some(*var),
+          Bound::Const(_) => None,
+        },
+      ),
+    };
+    match bound[*arg] {
+      // (a function has one value at its argument)
+      true => (1, true),
+      false => (domain.map_or(0, sort_len), value_bound(value)),
+    }
+  }
+}
+
+// Order the atoms of a query greedily: at each step, the atom that is cheapest to evaluate given
+// the variables bound by the atoms before it.
+pub fn plan(variables: Vec<String>, mut atoms: Vec<ResolvedAtom>, version: &Version) -> Plan {
+  let mut bound = vec![false; variables.len()];
+  let mut ordered = Vec::with_capacity(atoms.len());
+  while !atoms.is_empty() {
+    let (cheapest, _) = atoms
+      .iter()
+      .enumerate()
+      .min_by_key(|(_, atom)| {
+        let (cost, filtered) = atom.cost(version, &bound);
+        (cost, !filtered)
+      })
+      .unwrap();
+    let atom = atoms.remove(cheapest);
+    for var in atom.variables() {
+      bound[var] = true;
+    }
+    ordered.push(atom);
+  }
+  Plan {
+    variables,
+    atoms: ordered,
+  }
+}
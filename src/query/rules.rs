@@ -0,0 +1,579 @@
+use super::generic_join::*;
+use crate::id::*;
+use crate::patch::*;
+use crate::query::*;
+use crate::shared_treemap::SharedTreemap;
+use crate::version::*;
+use std::collections::{BTreeMap, HashMap};
+
+// Facts derived by the rules of a version (see `derive`), kept apart from the values asserted in
+// its columns: a function has a derived value only where it has no asserted one.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Derived {
+  pub homs: BTreeMap<(Luid, Luid), Luid>,
+  pub attrs: BTreeMap<(Luid, Luid), AttrValue>,
+}
+
+impl Derived {
+  pub fn len(&self) -> usize {
+    self.homs.len() + self.attrs.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn contains(&self, function: Luid, element: Luid) -> bool {
+    self.homs.contains_key(&(function, element)) || self.attrs.contains_key(&(function, element))
+  }
+}
+
+// Facts that a version has added to those of an earlier version: elements added to sorts, as
+// `(sort, element)`, and values given to homs and attributes where they had none, as
+// `(function, element, value)`.
+#[derive(Clone, Debug, Default)]
+pub struct Delta {
+  pub members: Vec<(Luid, Luid)>,
+  pub homs: Vec<(Luid, Luid, Luid)>,
+  pub attrs: Vec<(Luid, Luid, AttrValue)>,
+}
+
+impl Delta {
+  pub fn is_empty(&self) -> bool {
+    self.members.is_empty() && self.homs.is_empty() && self.attrs.is_empty()
+  }
+}
+
+impl ResolvedRule {
+  // Resolve the UUIDs of a rule against a version. Returns `None` if it mentions something that
+  // isn't a sort, hom or attribute as one, equates a hom with a value or an attribute with an
+  // element, or isn't a rule that can be evaluated: its conclusion must be an equation whose
+  // variables all appear in its premises.
+  pub fn resolve(
+    rule: &Rule,
+    version: &Version,
+    luid: impl Fn(&Uuid) -> Option<Luid>,
+  ) -> Option<Self> {
+    let resolve_atom = |atom: &RuleAtom| -> Option<ResolvedAtom> {
+      Some(match atom {
+        RuleAtom::Member { var, sort } => ResolvedAtom::Member {
+          var: *var as usize,
+          sort: luid(sort).filter(|sort| version.s0.contains(*sort as u64))?,
+        },
+        RuleAtom::Equation {
+          function,
+          arg,
+          value,
+        } => {
+          let function = luid(function)?;
+          let arg = *arg as usize;
+          match (version.homs.contains_key(&function), value) {
+            (true, RuleTerm::Var(var)) => ResolvedAtom::Hom {
+              hom: function,
+              arg,
+              value: Bound::Var(*var as usize),
+            },
+            (true, RuleTerm::Element(element)) => ResolvedAtom::Hom {
+              hom: function,
+              arg,
+              value: Bound::Const(luid(element)?),
+            },
+            (false, RuleTerm::Var(var)) if version.attrs.contains_key(&function) => {
+              ResolvedAtom::Attr {
+                attr: function,
+                arg,
+                value: Bound::Var(*var as usize),
+              }
+            }
+            (false, RuleTerm::Value(value)) if version.attrs.contains_key(&function) => {
+              ResolvedAtom::Attr {
+                attr: function,
+                arg,
+                value: Bound::Const(value.clone()),
+              }
+            }
+            _ => return None,
+          }
+        }
+      })
+    };
+    let premises = rule
+      .premises
+      .iter()
+      .map(resolve_atom)
+      .collect::<Option<Vec<_>>>()?;
+    let conclusion = resolve_atom(&rule.conclusion)?;
+    let variables = premises
+      .iter()
+      .chain([&conclusion])
+      .flat_map(|atom| atom.variables())
+      .max()
+      .map_or(0, |var| var + 1);
+    let resolved = ResolvedRule {
+      variables,
+      premises,
+      conclusion,
+    };
+    resolved.check().ok()?;
+    Some(resolved)
+  }
+
+  // Check that the rule can be evaluated (see `resolve`), or say why not.
+  pub fn check(&self) -> Result<(), String> {
+    if let ResolvedAtom::Member { .. } = self.conclusion {
+      return Err("the conclusion must be an equation".to_string());
+    }
+    let bound: Vec<usize> = self
+      .premises
+      .iter()
+      .flat_map(|atom| atom.variables())
+      .collect();
+    match self
+      .conclusion
+      .variables()
+      .into_iter()
+      .find(|var| !bound.contains(var))
+    {
+      Some(_) => Err("every variable of the conclusion must appear in the premises".to_string()),
+      None => Ok(()),
+    }
+  }
+
+  // The rule with the elements it mentions given by UUID, as it is stored in a patch.
+  pub fn unresolve(&self, uuid: impl Fn(Luid) -> Uuid) -> Rule {
+    let unresolve_atom = |atom: &ResolvedAtom| match atom {
+      ResolvedAtom::Member { var, sort } => RuleAtom::Member {
+        var: *var as u32,
+        sort: uuid(*sort),
+      },
+      ResolvedAtom::Hom { hom, arg, value } => RuleAtom::Equation {
+        function: uuid(*hom),
+        arg: *arg as u32,
+        value: match value {
+          Bound::Var(var) => RuleTerm::Var(*var as u32),
+          Bound::Const(element) => RuleTerm::Element(uuid(*element)),
+        },
+      },
+      ResolvedAtom::Attr { attr, arg, value } => RuleAtom::Equation {
+        function: uuid(*attr),
+        arg: *arg as u32,
+        value: match value {
+          Bound::Var(var) => RuleTerm::Var(*var as u32),
+          Bound::Const(value) => RuleTerm::Value(value.clone()),
+        },
+      },
+    };
+    Rule {
+      premises: self.premises.iter().map(unresolve_atom).collect(),
+      conclusion: unresolve_atom(&self.conclusion),
+    }
+  }
+}
+
+enum Fact {
+  Hom(Luid, Luid, Luid),
+  Attr(Luid, Luid, AttrValue),
+}
+
+// New elements of sorts and new values of functions, for the premises that a round of evaluation
+// restricts to them.
+#[derive(Default)]
+struct DeltaRelations {
+  members: HashMap<Luid, SharedTreemap>,
+  functions: HashMap<Luid, Relation>,
+}
+
+impl DeltaRelations {
+  fn is_empty(&self) -> bool {
+    self.members.values().all(|elements| elements.is_empty())
+      && self.functions.values().all(|relation| relation.is_empty())
+  }
+}
+
+struct Evaluator<'a> {
+  version: &'a Version,
+  rules: Vec<&'a ResolvedRule>,
+  values: Values,
+  // the values of the functions in the rules' premises, asserted and derived
+  full: HashMap<Luid, Relation>,
+  derived: Derived,
+}
+
+impl Evaluator<'_> {
+  // The conclusions of a rule at every binding of its premises, where the premise at `restricted`
+  // (if any) only sees the facts in `delta`.
+  fn conclusions(
+    &mut self,
+    rule: &ResolvedRule,
+    restricted: Option<usize>,
+    delta: &DeltaRelations,
+  ) -> Vec<Fact> {
+    let Some(is_value) = value_variables(&rule.premises, rule.variables) else {
+      return Vec::new();
+    };
+    let no_elements = SharedTreemap::new();
+    let no_values = Relation::default();
+    let atoms = rule
+      .premises
+      .iter()
+      .enumerate()
+      .map(|(i, atom)| {
+        let source = match (atom, restricted == Some(i)) {
+          (ResolvedAtom::Member { sort, .. }, false) => {
+            Source::Elements(self.version.sort_elements(*sort).unwrap_or(&no_elements))
+          }
+          (ResolvedAtom::Member { sort, .. }, true) => {
+            Source::Elements(delta.members.get(sort).unwrap_or(&no_elements))
+          }
+          (
+            ResolvedAtom::Hom { hom: function, .. } | ResolvedAtom::Attr { attr: function, .. },
+            restricted,
+          ) => Source::Relation(match restricted {
+            false => &self.full[function],
+            true => delta.functions.get(function).unwrap_or(&no_values),
+          }),
+        };
+        JoinAtom::new(atom, source, &mut self.values)
+      })
+      .collect();
+    let rows = Join::new(rule.variables, atoms).rows();
+    rows
+      .into_iter()
+      .filter_map(|row| match &rule.conclusion {
+        ResolvedAtom::Hom { hom, arg, value } => {
+          let value = match value {
+            Bound::Var(var) if is_value[*var] => return None,
+            Bound::Var(var) => row[*var] as Luid,
+            Bound::Const(value) => *value,
+          };
+          Some(Fact::Hom(*hom, row[*arg] as Luid, value))
+        }
+        ResolvedAtom::Attr { attr, arg, value } => {
+          let value = match value {
+            Bound::Var(var) if is_value[*var] => self.values.get(row[*var]).clone(),
+            Bound::Var(_) => return None,
+            Bound::Const(value) => value.clone(),
+          };
+          Some(Fact::Attr(*attr, row[*arg] as Luid, value))
+        }
+        ResolvedAtom::Member { .. } => None,
+      })
+      .collect()
+  }
+
+  // Add the facts that are new and well-typed, returning them.
+  fn add(&mut self, facts: Vec<Fact>) -> DeltaRelations {
+    let version = self.version;
+    let in_sort = |sort: Luid, element: Luid| {
+      version
+        .sort_elements(sort)
+        .is_some_and(|elements| elements.contains(element as u64))
+    };
+    let mut delta = DeltaRelations::default();
+    for fact in facts {
+      let (function, element, id) = match fact {
+        Fact::Hom(hom, element, value) => {
+          let Some(hom_ref) = version.homs.get(&hom) else {
+            continue;
+          };
+          if !in_sort(hom_ref.domain, element)
+            || !in_sort(hom_ref.codomain, value)
+            || version.hom_value(hom, element).is_some()
+            || self.derived.contains(hom, element)
+          {
+            continue;
+          }
+          self.derived.homs.insert((hom, element), value);
+          (hom, element, value as u64)
+        }
+        Fact::Attr(attr, element, value) => {
+          let Some(attr_ref) = version.attrs.get(&attr) else {
+            continue;
+          };
+          if !in_sort(attr_ref.domain, element)
+            || value.type_uuid() != attr_ref.attr_type
+            || version.attr_value(attr, element).is_some()
+            || self.derived.contains(attr, element)
+          {
+            continue;
+          }
+          let id = self.values.intern(&value);
+          self.derived.attrs.insert((attr, element), value);
+          (attr, element, id)
+        }
+      };
+      if let Some(relation) = self.full.get_mut(&function) {
+        relation.insert(element as u64, id);
+        delta
+          .functions
+          .entry(function)
+          .or_default()
+          .insert(element as u64, id);
+      }
+    }
+    delta
+  }
+}
+
+// The facts that the rules of a version derive from it, by semi-naive evaluation: after a first
+// round, each round evaluates every rule once for each of its premises, restricted to the facts
+// that are new since the round before, until no new facts are derived. Given the facts derived
+// for an earlier version and what this version has added to it (which must be all that has
+// changed, rules included), evaluation starts from those facts with the additions as the first
+// round's new facts; otherwise it starts from scratch. As a function has one value at an element,
+// a derived value is dropped where the function has one already (asserted, or derived before it),
+// as are values outside the codomain of a hom or not of the type of an attribute.
+pub fn derive(version: &Version, earlier: Option<(&Derived, Delta)>) -> Derived {
+  let mut evaluator = Evaluator {
+    version,
+    rules: version.rules.values().collect(),
+    values: Values::default(),
+    full: HashMap::new(),
+    derived: Derived::default(),
+  };
+  if evaluator.rules.is_empty() {
+    return evaluator.derived;
+  }
+  if let Some((derived, added)) = earlier.as_ref() {
+    if added.is_empty() {
+      return (*derived).clone();
+    }
+  }
+  for rule in evaluator.rules.iter() {
+    for atom in rule.premises.iter() {
+      if let ResolvedAtom::Hom { hom: function, .. } | ResolvedAtom::Attr { attr: function, .. } =
+        atom
+      {
+        evaluator
+          .full
+          .entry(*function)
+          .or_insert_with(|| Relation::of_function(version, *function, &mut evaluator.values));
+      }
+    }
+  }
+  let mut delta = match earlier {
+    None => {
+      let mut facts = Vec::new();
+      for rule in evaluator.rules.clone() {
+        facts.extend(evaluator.conclusions(rule, None, &DeltaRelations::default()));
+      }
+      evaluator.add(facts)
+    }
+    Some((derived, added)) => {
+      let derived_facts = derived
+        .homs
+        .iter()
+        .map(|(&(hom, element), &value)| Fact::Hom(hom, element, value))
+        .chain(
+          derived
+            .attrs
+            .iter()
+            .map(|(&(attr, element), value)| Fact::Attr(attr, element, value.clone())),
+        )
+        .collect();
+      // (the facts derived before are not new)
+      evaluator.add(derived_facts);
+      let mut delta = DeltaRelations::default();
+      for (sort, element) in added.members {
+        delta
+          .members
+          .entry(sort)
+          .or_default()
+          .insert(element as u64);
+      }
+      let facts = added
+        .homs
+        .into_iter()
+        .map(|(hom, element, value)| (hom, element, value as u64))
+        .chain(
+          added
+            .attrs
+            .into_iter()
+            .map(|(attr, element, value)| (attr, element, evaluator.values.intern(&value))),
+        )
+        .collect::<Vec<_>>();
+      for (function, element, id) in facts {
+        if evaluator.full.contains_key(&function) {
+          delta
+            .functions
+            .entry(function)
+            .or_default()
+            .insert(element as u64, id);
+        }
+      }
+      delta
+    }
+  };
+  while !delta.is_empty() {
+    let mut facts = Vec::new();
+    for rule in evaluator.rules.clone() {
+      for (i, atom) in rule.premises.iter().enumerate() {
+        let is_new = match atom {
+          ResolvedAtom::Member { sort, .. } => delta.members.contains_key(sort),
+          ResolvedAtom::Hom { hom: function, .. } | ResolvedAtom::Attr { attr: function, .. } => {
+            delta.functions.contains_key(function)
+          }
+        };
+        if is_new {
+          facts.extend(evaluator.conclusions(rule, Some(i), &delta));
+        }
+      }
+    }
+    delta = evaluator.add(facts);
+  }
+  evaluator.derived
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const NODE: Luid = 0;
+  const PARENT: Luid = 1;
+  const ROOT: Luid = 2;
+  const COLOR: Luid = 3;
+  const FIRST_NODE: Luid = 100;
+
+  // The facts that the rules of a version derive, by naive evaluation: every rule is evaluated in
+  // full over the asserted and derived facts, round after round, until a round derives nothing.
+  fn naive(version: &Version) -> Derived {
+    let mut current = version.clone();
+    let mut derived = Derived::default();
+    loop {
+      let mut changed = false;
+      for rule in version.rules.values() {
+        let variables = (0..rule.variables).map(|var| var.to_string()).collect();
+        let plan = plan(variables, rule.premises.clone(), &current);
+        for row in generic_join(&plan, &current) {
+          let bound = |var: usize| row[var].clone();
+          match &rule.conclusion {
+            ResolvedAtom::Hom {
+              hom,
+              arg,
+              value: Bound::Var(var),
+            } => {
+              let (QueryValue::Element(element), QueryValue::Element(value)) =
+                (bound(*arg), bound(*var))
+              else {
+                continue;
+              };
+              if current.hom_value(*hom, element).is_none() {
+                current.set_hom_value(*hom, element, Some(value));
+                derived.homs.insert((*hom, element), value);
+                changed = true;
+              }
+            }
+            ResolvedAtom::Attr {
+              attr,
+              arg,
+              value: Bound::Var(var),
+            } => {
+              let (QueryValue::Element(element), QueryValue::Value(value)) =
+                (bound(*arg), bound(*var))
+              else {
+                continue;
+              };
+              if current.attr_value(*attr, element).is_none() {
+                current.set_attr_value(*attr, element, Some(value.clone()));
+                derived.attrs.insert((*attr, element), value);
+                changed = true;
+              }
+            }
+            _ => unreachable!(),
+          }
+        }
+      }
+      if !changed {
+        return derived;
+      }
+    }
+  }
+
+  // A forest with the given parents, whose roots have themselves as their `root` and a `color`, with
+  // rules that give every other node the root and color of its parent; the parents of the nodes for
+  // which `left_out` holds are left out.
+  fn forest(parents: &[Option<usize>], left_out: impl Fn(usize) -> bool) -> Version {
+    let node = |i: usize| FIRST_NODE + i as Luid;
+    let mut version = Version::default();
+    version.add_sort(NODE);
+    for i in 0..parents.len() {
+      version.add_to_sort(NODE, node(i));
+    }
+    version.add_hom(PARENT, NODE, NODE, true, false);
+    version.add_hom(ROOT, NODE, NODE, true, false);
+    version.add_attr(COLOR, NODE, PrimitiveType::I64.uuid());
+    for (i, parent) in parents.iter().enumerate() {
+      match parent {
+        Some(parent) if !left_out(i) => {
+          version.set_hom_value(PARENT, node(i), Some(node(*parent)));
+        }
+        Some(_) => {}
+        None => {
+          version.set_hom_value(ROOT, node(i), Some(node(i)));
+          version.set_attr_value(COLOR, node(i), Some(AttrValue::I64(i as i64)));
+        }
+      }
+    }
+    // `f(x) = ?r :- parent(x) = ?y, f(y) = ?r`, for `root` and `color`
+    let atom = |function: Luid, arg: usize, var: usize| match function {
+      COLOR => ResolvedAtom::Attr {
+        attr: function,
+        arg,
+        value: Bound::Var(var),
+      },
+      _ => ResolvedAtom::Hom {
+        hom: function,
+        arg,
+        value: Bound::Var(var),
+      },
+    };
+    let (x, y, r) = (0, 1, 2);
+    for (rule, function) in [(10, ROOT), (11, COLOR)] {
+      let rule_ref = ResolvedRule {
+        variables: 3,
+        premises: vec![atom(PARENT, x, y), atom(function, y, r)],
+        conclusion: atom(function, x, r),
+      };
+      version.rules.insert(rule, rule_ref);
+    }
+    version
+  }
+
+  #[test]
+  fn semi_naive_evaluation_derives_what_naive_evaluation_does() {
+    // (a few deep trees, with each node's parent among the nodes before it)
+    let mut rng = 0x9e37_79b9_7f4a_7c15_u64;
+    let parents: Vec<Option<usize>> = (0..300)
+      .map(|i| {
+        rng ^= rng << 13;
+        rng ^= rng >> 7;
+        rng ^= rng << 17;
+        (i >= 3).then(|| i - 1 - (rng % 4).min(i as u64 - 1) as usize)
+      })
+      .collect();
+    let version = forest(&parents, |_| false);
+    let derived = derive(&version, None);
+    assert_eq!(derived.len(), 2 * (parents.len() - 3));
+    assert_eq!(derived, naive(&version));
+
+    // (incrementally, from a version that lacks some of the parents)
+    let left_out = |i: usize| i.is_multiple_of(7);
+    let earlier = forest(&parents, left_out);
+    let earlier_derived = derive(&earlier, None);
+    assert_eq!(earlier_derived, naive(&earlier));
+    let added = Delta {
+      homs: (0..parents.len())
+        .filter(|&i| left_out(i) && parents[i].is_some())
+        .map(|i| {
+          (
+            PARENT,
+            FIRST_NODE + i as Luid,
+            FIRST_NODE + parents[i].unwrap() as Luid,
+          )
+        })
+        .collect(),
+      ..Default::default()
+    };
+    assert_eq!(derive(&version, Some((&earlier_derived, added))), derived);
+  }
+}
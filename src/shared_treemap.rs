@@ -0,0 +1,484 @@
+use roaring::RoaringBitmap;
+use std::sync::Arc;
+
+// Number of low bits of a value stored inside a chunk; each chunk covers 2^CHUNK_BITS values.
+const CHUNK_BITS: u32 = 12;
+const CHUNK_MASK: u64 = (1 << CHUNK_BITS) - 1;
+// Number of bits of a chunk's key that each level of the tree above the chunks takes care of.
+const FANOUT_BITS: u32 = 4;
+const FANOUT: usize = 1 << FANOUT_BITS;
+
+fn split(value: u64) -> (u64, u32) {
+  (value >> CHUNK_BITS, (value & CHUNK_MASK) as u32)
+}
+
+fn join(key: u64, low: u32) -> u64 {
+  (key << CHUNK_BITS) | low as u64
+}
+
+// The child that the chunk `key` lies under, in a branch at `height` (1 for a branch of chunks).
+fn digit(key: u64, height: u32) -> usize {
+  ((key >> (FANOUT_BITS * (height - 1))) as usize) & (FANOUT - 1)
+}
+
+// Whether the chunk `key` lies under a node at `height`.
+fn fits(key: u64, height: u32) -> bool {
+  FANOUT_BITS * height >= u64::BITS || key >> (FANOUT_BITS * height) == 0
+}
+
+#[derive(Clone)]
+enum Node {
+  Chunk(RoaringBitmap),
+  // (with the number of values under it, so that rank and select skip whole subtrees)
+  Branch {
+    len: u64,
+    children: [Option<Arc<Node>>; FANOUT],
+  },
+}
+
+impl Node {
+  fn len(&self) -> u64 {
+    match self {
+      Node::Chunk(chunk) => chunk.len(),
+      Node::Branch { len, .. } => *len,
+    }
+  }
+
+  fn branch(children: [Option<Arc<Node>>; FANOUT]) -> Option<Arc<Node>> {
+    let len = children.iter().flatten().map(|child| child.len()).sum();
+    (len > 0).then(|| Arc::new(Node::Branch { len, children }))
+  }
+}
+
+// A set of u64 with the same interface as `RoaringTreemap` (for the operations we need), whose
+// clones share structure: the set is split into small roaring chunks, which are the leaves of a
+// trie on their keys whose nodes are behind `Arc`s, and a modification copies only the chunk it
+// touches and the nodes above it. Deriving a version from its parent therefore costs memory
+// proportional to the size of the patch, not the size of the universe. Each node counts the values
+// under it, so `rank` and `select` take time logarithmic in the number of chunks.
+#[derive(Clone, Default)]
+pub struct SharedTreemap {
+  root: Option<Arc<Node>>,
+  // (the root covers the keys below 2^(FANOUT_BITS * height); chunks are at height 0)
+  height: u32,
+}
+
+impl SharedTreemap {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  // Add levels above the root until it covers `key`.
+  fn grow(&mut self, key: u64) {
+    if self.root.is_none() {
+      self.height = 0;
+    }
+    while !fits(key, self.height) {
+      if let Some(root) = self.root.take() {
+        let mut children: [Option<Arc<Node>>; FANOUT] = Default::default();
+        children[0] = Some(root);
+        self.root = Node::branch(children);
+      }
+      self.height += 1;
+    }
+  }
+
+  fn chunk(&self, key: u64) -> Option<&RoaringBitmap> {
+    if !fits(key, self.height) {
+      return None;
+    }
+    let mut node = self.root.as_ref()?;
+    for height in (1..=self.height).rev() {
+      let Node::Branch { children, .. } = node.as_ref() else {
+        unreachable!()
+      };
+      node = children[digit(key, height)].as_ref()?;
+    }
+    match node.as_ref() {
+      Node::Chunk(chunk) => Some(chunk),
+      Node::Branch { .. } => unreachable!(),
+    }
+  }
+
+  pub fn insert(&mut self, value: u64) -> bool {
+    if self.contains(value) {
+      return false;
+    }
+    let (key, low) = split(value);
+    self.grow(key);
+    let mut node = &mut self.root;
+    for height in (1..=self.height).rev() {
+      let branch = node.get_or_insert_with(|| {
+        Arc::new(Node::Branch {
+          len: 0,
+          children: Default::default(),
+        })
+      });
+      let Node::Branch { len, children } = Arc::make_mut(branch) else {
+        unreachable!()
+      };
+      *len += 1;
+      node = &mut children[digit(key, height)];
+    }
+    let chunk = node.get_or_insert_with(|| Arc::new(Node::Chunk(RoaringBitmap::new())));
+    let Node::Chunk(chunk) = Arc::make_mut(chunk) else {
+      unreachable!()
+    };
+    chunk.insert(low);
+    true
+  }
+
+  pub fn remove(&mut self, value: u64) -> bool {
+    fn remove_from(node: &mut Option<Arc<Node>>, height: u32, key: u64, low: u32) {
+      let Some(shared) = node.as_mut() else {
+        return;
+      };
+      match Arc::make_mut(shared) {
+        Node::Chunk(chunk) => {
+          chunk.remove(low);
+        }
+        Node::Branch { len, children } => {
+          *len -= 1;
+          remove_from(&mut children[digit(key, height)], height - 1, key, low);
+        }
+      }
+      if shared.len() == 0 {
+        *node = None;
+      }
+    }
+    if !self.contains(value) {
+      return false;
+    }
+    let (key, low) = split(value);
+    remove_from(&mut self.root, self.height, key, low);
+    true
+  }
+
+  pub fn contains(&self, value: u64) -> bool {
+    let (key, low) = split(value);
+    self
+      .chunk(key)
+      .map(|chunk| chunk.contains(low))
+      .unwrap_or(false)
+  }
+
+  pub fn len(&self) -> u64 {
+    self.root.as_ref().map(|root| root.len()).unwrap_or(0)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.root.is_none()
+  }
+
+  // Number of values in the set that are less than or equal to `value`.
+  pub fn rank(&self, value: u64) -> u64 {
+    let (key, low) = split(value);
+    if !fits(key, self.height) {
+      return self.len();
+    }
+    let mut below = 0;
+    let mut node = self.root.as_ref();
+    let mut height = self.height;
+    while let Some(current) = node {
+      match current.as_ref() {
+        Node::Chunk(chunk) => return below + chunk.rank(low),
+        Node::Branch { children, .. } => {
+          let digit = digit(key, height);
+          below += children[..digit]
+            .iter()
+            .flatten()
+            .map(|child| child.len())
+            .sum::<u64>();
+          node = children[digit].as_ref();
+          height -= 1;
+        }
+      }
+    }
+    below
+  }
+
+  // The `n`th smallest value in the set (counting from 0).
+  pub fn select(&self, mut n: u64) -> Option<u64> {
+    let mut node = self.root.as_ref()?;
+    let mut key = 0;
+    loop {
+      match node.as_ref() {
+        Node::Chunk(chunk) => {
+          return chunk
+            .select(u32::try_from(n).ok()?)
+            .map(|low| join(key, low));
+        }
+        Node::Branch { children, .. } => {
+          let (digit, child) = children.iter().enumerate().find_map(|(digit, child)| {
+            let child = child.as_ref()?;
+            match n < child.len() {
+              true => Some((digit, child)),
+              false => {
+                n -= child.len();
+                None
+              }
+            }
+          })?;
+          key = (key << FANOUT_BITS) | digit as u64;
+          node = child;
+        }
+      }
+    }
+  }
+
+  // The chunks in order of their keys.
+  fn chunks(&self) -> Vec<(u64, &RoaringBitmap)> {
+    let mut chunks = Vec::new();
+    let mut stack: Vec<(u64, &Arc<Node>)> = self.root.iter().map(|root| (0, root)).collect();
+    while let Some((key, node)) = stack.pop() {
+      match node.as_ref() {
+        Node::Chunk(chunk) => chunks.push((key, chunk)),
+        Node::Branch { children, .. } => {
+          for (digit, child) in children.iter().enumerate().rev() {
+            if let Some(child) = child {
+              stack.push(((key << FANOUT_BITS) | digit as u64, child));
+            }
+          }
+        }
+      }
+    }
+    chunks
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+    self
+      .chunks()
+      .into_iter()
+      .flat_map(|(key, chunk)| chunk.iter().map(move |low| join(key, low)))
+  }
+
+  // `self` and `other` with roots at the same height (sharing their nodes with them).
+  fn aligned(&self, other: &Self) -> (Self, Self) {
+    let (mut ours, mut theirs) = (self.clone(), other.clone());
+    let height = |set: &Self| set.root.as_ref().map(|_| set.height).unwrap_or(0);
+    let top_key = |height: u32| match FANOUT_BITS * height >= u64::BITS {
+      true => u64::MAX,
+      false => (1 << (FANOUT_BITS * height)) - 1,
+    };
+    let height = height(self).max(height(other));
+    if height > 0 {
+      ours.grow(top_key(height - 1) + 1);
+      theirs.grow(top_key(height - 1) + 1);
+    }
+    (ours, theirs)
+  }
+
+  // In-place union; nodes that are already shared between the two sets are left untouched.
+  pub fn union_with(&mut self, other: &Self) {
+    fn union(ours: &mut Option<Arc<Node>>, theirs: &Option<Arc<Node>>) {
+      let Some(theirs) = theirs else {
+        return;
+      };
+      let Some(shared) = ours.as_mut() else {
+        *ours = Some(theirs.clone());
+        return;
+      };
+      if Arc::ptr_eq(shared, theirs) {
+        return;
+      }
+      match (shared.as_ref(), theirs.as_ref()) {
+        (Node::Chunk(chunk), Node::Chunk(other_chunk)) => {
+          let union = chunk | other_chunk;
+          if union.len() != chunk.len() {
+            *shared = Arc::new(Node::Chunk(union));
+          }
+        }
+        (
+          Node::Branch { .. },
+          Node::Branch {
+            children: other_children,
+            ..
+          },
+        ) => {
+          let Node::Branch { len, children } = Arc::make_mut(shared) else {
+            unreachable!()
+          };
+          for (child, other_child) in children.iter_mut().zip(other_children.iter()) {
+            union(child, other_child);
+          }
+          *len = children.iter().flatten().map(|child| child.len()).sum();
+        }
+        _ => unreachable!(),
+      }
+    }
+    if other.is_empty() {
+      return;
+    }
+    let (mut ours, theirs) = self.aligned(other);
+    union(&mut ours.root, &theirs.root);
+    *self = ours;
+  }
+
+  // Intersection; nodes that are shared between the two sets are shared with the result too.
+  pub fn intersection(&self, other: &Self) -> Self {
+    fn intersection(ours: &Option<Arc<Node>>, theirs: &Option<Arc<Node>>) -> Option<Arc<Node>> {
+      let (ours, theirs) = (ours.as_ref()?, theirs.as_ref()?);
+      if Arc::ptr_eq(ours, theirs) {
+        return Some(ours.clone());
+      }
+      match (ours.as_ref(), theirs.as_ref()) {
+        (Node::Chunk(chunk), Node::Chunk(other_chunk)) => {
+          let intersection = chunk & other_chunk;
+          (!intersection.is_empty()).then(|| Arc::new(Node::Chunk(intersection)))
+        }
+        (
+          Node::Branch { children, .. },
+          Node::Branch {
+            children: other_children,
+            ..
+          },
+        ) => {
+          let mut intersections: [Option<Arc<Node>>; FANOUT] = Default::default();
+          for (digit, intersection_child) in intersections.iter_mut().enumerate() {
+            *intersection_child = intersection(&children[digit], &other_children[digit]);
+          }
+          Node::branch(intersections)
+        }
+        _ => unreachable!(),
+      }
+    }
+    let (ours, theirs) = self.aligned(other);
+    Self {
+      root: intersection(&ours.root, &theirs.root),
+      height: ours.height,
+    }
+  }
+
+  // Approximate number of bytes attributable to this set, with each shared node apportioned
+  // evenly among the nodes (or sets) that share it.
+  pub fn heap_size(&self) -> usize {
+    fn size(node: &Arc<Node>) -> usize {
+      let own = match node.as_ref() {
+        Node::Chunk(chunk) => chunk.serialized_size(),
+        Node::Branch { children, .. } => {
+          std::mem::size_of::<Node>() + children.iter().flatten().map(size).sum::<usize>()
+        }
+      };
+      own / Arc::strong_count(node)
+    }
+    self.root.as_ref().map(size).unwrap_or(0)
+  }
+}
+
+impl PartialEq for SharedTreemap {
+  fn eq(&self, other: &Self) -> bool {
+    fn eq(ours: &Option<Arc<Node>>, theirs: &Option<Arc<Node>>) -> bool {
+      match (ours, theirs) {
+        (None, None) => true,
+        (Some(ours), Some(theirs)) if Arc::ptr_eq(ours, theirs) => true,
+        (Some(ours), Some(theirs)) => match (ours.as_ref(), theirs.as_ref()) {
+          (Node::Chunk(chunk), Node::Chunk(other_chunk)) => chunk == other_chunk,
+          (
+            Node::Branch { len, children },
+            Node::Branch {
+              len: other_len,
+              children: other_children,
+            },
+          ) => {
+            len == other_len
+              && children
+                .iter()
+                .zip(other_children.iter())
+                .all(|(child, other_child)| eq(child, other_child))
+          }
+          _ => false,
+        },
+        _ => false,
+      }
+    }
+    if self.len() != other.len() {
+      return false;
+    }
+    let (ours, theirs) = self.aligned(other);
+    eq(&ours.root, &theirs.root)
+  }
+}
+
+impl Eq for SharedTreemap {}
+
+impl Extend<u64> for SharedTreemap {
+  fn extend<I: IntoIterator<Item = u64>>(&mut self, values: I) {
+    for value in values {
+      self.insert(value);
+    }
+  }
+}
+
+impl FromIterator<u64> for SharedTreemap {
+  fn from_iter<I: IntoIterator<Item = u64>>(values: I) -> Self {
+    let mut set = Self::new();
+    set.extend(values);
+    set
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::BTreeSet;
+
+  // (a linear congruential generator, for values spread over a few thousand chunks)
+  fn values(seed: u64, n: usize) -> Vec<u64> {
+    let mut state = seed;
+    (0..n)
+      .map(|_| {
+        state = state
+          .wrapping_mul(6364136223846793005)
+          .wrapping_add(1442695040888963407);
+        (state >> 33) % (1 << 24)
+      })
+      .collect()
+  }
+
+  fn check(set: &SharedTreemap, expected: &BTreeSet<u64>) {
+    assert_eq!(set.len(), expected.len() as u64);
+    assert!(set.iter().eq(expected.iter().copied()));
+    for (n, &value) in expected.iter().enumerate().step_by(97) {
+      assert!(set.contains(value));
+      assert_eq!(set.rank(value), n as u64 + 1);
+      assert_eq!(set.select(n as u64), Some(value));
+    }
+    assert_eq!(set.select(expected.len() as u64), None);
+    assert_eq!(set.rank(u64::MAX), expected.len() as u64);
+  }
+
+  #[test]
+  fn shared_treemaps_behave_as_sets() {
+    let mut set: SharedTreemap = values(1, 5000).into_iter().collect();
+    let mut expected: BTreeSet<u64> = values(1, 5000).into_iter().collect();
+    check(&set, &expected);
+    // (a clone shares the nodes that neither changes)
+    let original = set.clone();
+    let original_expected = expected.clone();
+    for value in values(2, 3000) {
+      assert_eq!(set.insert(value), expected.insert(value));
+    }
+    for value in values(1, 2500) {
+      assert_eq!(set.remove(value), expected.remove(&value));
+    }
+    check(&set, &expected);
+    check(&original, &original_expected);
+
+    let mut union = original.clone();
+    union.union_with(&set);
+    check(&union, &(&original_expected | &expected));
+    let intersection = original.intersection(&set);
+    check(&intersection, &(&original_expected & &expected));
+    assert!(union == set.iter().chain(original.iter()).collect());
+    assert!(intersection != set);
+
+    // (removing every value leaves an empty set, however high it had grown)
+    for value in expected.iter() {
+      set.remove(*value);
+    }
+    check(&set, &BTreeSet::new());
+    assert!(set == SharedTreemap::new());
+    set.insert(3);
+    check(&set, &BTreeSet::from([3]));
+  }
+}
@@ -0,0 +1,293 @@
+use crate::id::*;
+use crate::shared_treemap::SharedTreemap;
+use crate::state::*;
+use std::collections::BTreeSet;
+
+impl TotalState {
+  // Source commits of every patch that targets `commit`.
+  pub(crate) fn parents(&self, commit: Luid) -> impl Iterator<Item = Luid> + '_ {
+    self.commits.get(&commit).into_iter().flat_map(|incoming| {
+      incoming
+        .iter()
+        .flat_map(|(sources, _)| sources.iter().copied())
+    })
+  }
+
+  // Generation number of a commit: 1 for a root, otherwise 1 + the largest generation number of
+  // its parents. `None` until all of its ancestors have been indexed.
+  pub fn generation(&self, commit: Luid) -> Option<usize> {
+    self.generations.get(&commit).copied()
+  }
+
+  // Called whenever a patch to `commit` is indexed. Commits whose parents haven't all been indexed
+  // yet wait for them in `awaiting_generation`, so each commit is numbered once its history is
+  // complete (and again only if a later incoming patch gives it a deeper parent).
+  pub(in crate::state) fn update_generations(&mut self, commit: Luid) {
+    let mut work_list = vec![commit];
+    while let Some(commit) = work_list.pop() {
+      let parents: Vec<Luid> = self.parents(commit).collect();
+      let mut generation = 1;
+      let mut complete = true;
+      for &parent in parents.iter() {
+        match self.generations.get(&parent) {
+          Some(parent_generation) => generation = generation.max(parent_generation + 1),
+          None => {
+            self
+              .awaiting_generation
+              .entry(parent)
+              .or_default()
+              .push(commit);
+            complete = false;
+          }
+        }
+      }
+      if !complete {
+        continue;
+      }
+      let reachable = self.reachability_index.as_ref().map(|index| {
+        let mut reachable = SharedTreemap::new();
+        for parent in parents.iter() {
+          reachable.union_with(&index[parent]);
+        }
+        reachable.insert(commit as u64);
+        reachable
+      });
+      let previous = self.generations.insert(commit, generation);
+      let reachability_changed = match (reachable, self.reachability_index.as_mut()) {
+        (Some(reachable), Some(index)) => {
+          index.insert(commit, reachable.clone()) != Some(reachable)
+        }
+        _ => false,
+      };
+      if previous == Some(generation) && !reachability_changed {
+        continue;
+      }
+      work_list.extend(self.awaiting_generation.remove(&commit).unwrap_or_default());
+      if previous.is_some() {
+        // (rare) a commit that was already numbered got a new incoming patch, so its descendants
+        // have to be renumbered
+        work_list.extend(
+          self
+            .commits
+            .iter()
+            .filter(|(_, incoming)| {
+              incoming
+                .iter()
+                .any(|(sources, _)| sources.contains(&commit))
+            })
+            .map(|(child, _)| *child),
+        );
+      }
+    }
+  }
+
+  // Maintain, for every commit, a bitmap of all of its ancestors, making `is_ancestor` a single
+  // lookup. The bitmaps share structure with those of their parents.
+  pub fn enable_reachability_index(&mut self) {
+    if self.reachability_index.is_some() {
+      return;
+    }
+    let mut commits: Vec<(usize, Luid)> = self
+      .generations
+      .iter()
+      .map(|(&commit, &generation)| (generation, commit))
+      .collect();
+    commits.sort_unstable();
+    let mut index = ReachabilityIndex::new();
+    for (_, commit) in commits {
+      let mut reachable = SharedTreemap::new();
+      for parent in self.parents(commit) {
+        reachable.union_with(&index[&parent]);
+      }
+      reachable.insert(commit as u64);
+      index.insert(commit, reachable);
+    }
+    self.reachability_index = Some(index);
+  }
+
+  pub fn disable_reachability_index(&mut self) {
+    self.reachability_index = None;
+  }
+
+  // Whether `ancestor` is reachable from `commit` by following parents (a commit is its own
+  // ancestor).
+  pub fn is_ancestor(&self, ancestor: Luid, commit: Luid) -> bool {
+    if let Some(reachable) = self
+      .reachability_index
+      .as_ref()
+      .and_then(|index| index.get(&commit))
+    {
+      return reachable.contains(ancestor as u64);
+    }
+    // Generation numbers strictly decrease along parents, so the search can skip every commit
+    // whose generation number is lower than that of `ancestor`.
+    let ancestor_generation = self.generation(ancestor).unwrap_or(0);
+    let mut visited = BTreeSet::from([commit]);
+    let mut queue = vec![commit];
+    while let Some(luid) = queue.pop() {
+      if luid == ancestor {
+        return true;
+      }
+      for parent in self.parents(luid) {
+        let prune = self
+          .generation(parent)
+          .map(|generation| generation < ancestor_generation)
+          .unwrap_or(false);
+        if !prune && visited.insert(parent) {
+          queue.push(parent);
+        }
+      }
+    }
+    false
+  }
+
+  // All ancestors of `commit`, including itself.
+  pub fn ancestors(&self, commit: Luid) -> BTreeSet<Luid> {
+    if let Some(reachable) = self
+      .reachability_index
+      .as_ref()
+      .and_then(|index| index.get(&commit))
+    {
+      return reachable.iter().map(|luid| luid as Luid).collect();
+    }
+    let mut ancestors = BTreeSet::from([commit]);
+    let mut queue = vec![commit];
+    while let Some(luid) = queue.pop() {
+      for parent in self.parents(luid) {
+        if ancestors.insert(parent) {
+          queue.push(parent);
+        }
+      }
+    }
+    ancestors
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::patch::Patch;
+  use std::collections::BTreeMap;
+
+  // A history of `n` commits, each with one or two parents among the commits before it, and a
+  // second incoming patch for some; as (target, sources) of each patch, in topological order.
+  fn history(n: usize) -> Vec<(usize, Vec<usize>)> {
+    // (xorshift64, so that the history is the same from run to run)
+    let mut rng = 0x2545_f491_4f6c_dd1d_u64;
+    let mut below = |n: usize| {
+      rng ^= rng << 13;
+      rng ^= rng >> 7;
+      rng ^= rng << 17;
+      (rng % n as u64) as usize
+    };
+    let mut patches = vec![(0, vec![])];
+    for commit in 1..n {
+      let mut sources = vec![below(commit)];
+      if below(3) == 0 {
+        sources.push(below(commit));
+        sources.dedup();
+      }
+      patches.push((commit, sources));
+      if below(5) == 0 {
+        patches.push((commit, vec![below(commit)]));
+      }
+    }
+    patches
+  }
+
+  // Index the patches of a history in the given order (of indices into it).
+  fn index(history: &[(usize, Vec<usize>)], order: &[usize], state: &mut TotalState) -> Vec<Luid> {
+    let commits: Vec<Uuid> = (0..history.len()).map(|_| Uuid::now_v7()).collect();
+    for &i in order {
+      let (target, sources) = &history[i];
+      let patch = Patch {
+        target_commit: commits[*target],
+        source_commits: sources.iter().map(|source| commits[*source]).collect(),
+        ..Default::default()
+      };
+      state.index_patch(Uuid::now_v7(), patch);
+    }
+    let n = history.iter().map(|(target, _)| target + 1).max().unwrap();
+    commits[..n]
+      .iter()
+      .map(|commit| state.universe.get_index_of(commit).unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn ancestry_is_the_same_with_and_without_the_reachability_index() {
+    let history = history(60);
+    let mut parents: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+    for (target, sources) in history.iter() {
+      parents.entry(*target).or_default().extend(sources);
+    }
+    let n = parents.len();
+    // (the commits in topological order, so each one's ancestors are known before its own)
+    let mut expected_ancestors: Vec<BTreeSet<usize>> = Vec::new();
+    let mut expected_generations: Vec<usize> = Vec::new();
+    for commit in 0..n {
+      let mut ancestors = BTreeSet::from([commit]);
+      let mut generation = 1;
+      for &parent in parents[&commit].iter() {
+        ancestors.extend(expected_ancestors[parent].iter().copied());
+        generation = generation.max(expected_generations[parent] + 1);
+      }
+      expected_ancestors.push(ancestors);
+      expected_generations.push(generation);
+    }
+
+    let in_order: Vec<usize> = (0..history.len()).collect();
+    let reversed: Vec<usize> = in_order.iter().rev().copied().collect();
+    // (every third patch first, then the rest newest first)
+    let interleaved: Vec<usize> = in_order
+      .iter()
+      .filter(|i| *i % 3 == 0)
+      .chain(reversed.iter().filter(|i| *i % 3 != 0))
+      .copied()
+      .collect();
+    for order in [&in_order, &reversed, &interleaved] {
+      // with the index from the start, enabled once everything is indexed, and without it
+      let mut indexed = TotalState::default();
+      indexed.enable_reachability_index();
+      let indexed_commits = index(&history, order, &mut indexed);
+      let mut enabled_later = TotalState::default();
+      let enabled_later_commits = index(&history, order, &mut enabled_later);
+      enabled_later.enable_reachability_index();
+      let mut unindexed = TotalState::default();
+      let unindexed_commits = index(&history, order, &mut unindexed);
+      for (state, commits) in [
+        (&indexed, &indexed_commits),
+        (&enabled_later, &enabled_later_commits),
+        (&unindexed, &unindexed_commits),
+      ] {
+        let commit_of: BTreeMap<Luid, usize> = commits
+          .iter()
+          .enumerate()
+          .map(|(i, luid)| (*luid, i))
+          .collect();
+        for (i, &commit) in commits.iter().enumerate() {
+          assert_eq!(state.generation(commit), Some(expected_generations[i]));
+          let ancestors: BTreeSet<usize> = state
+            .ancestors(commit)
+            .iter()
+            .map(|luid| commit_of[luid])
+            .collect();
+          assert_eq!(ancestors, expected_ancestors[i]);
+          for (j, &other) in commits.iter().enumerate() {
+            assert_eq!(
+              state.is_ancestor(other, commit),
+              expected_ancestors[i].contains(&j)
+            );
+          }
+        }
+      }
+    }
+    // (and disabling the index falls back to searching)
+    let mut state = TotalState::default();
+    state.enable_reachability_index();
+    let commits = index(&history, &in_order, &mut state);
+    state.disable_reachability_index();
+    assert!(state.is_ancestor(commits[0], commits[n - 1]));
+    assert!(!state.is_ancestor(commits[n - 1], commits[0]));
+  }
+}
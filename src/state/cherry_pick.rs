@@ -0,0 +1,453 @@
+use crate::id::*;
+use crate::patch::*;
+use crate::state::integrity::*;
+use crate::TotalState;
+use std::path::Path;
+
+#[derive(Clone, Debug)]
+pub enum Conflict {
+  // the patch deletes or merges an element that is not in the working state
+  Missing(Uuid),
+  // the patch adds an element that is already in the working state
+  AlreadyPresent(Uuid),
+  // the patch adds an element that has been deleted or merged away, which can't be undone
+  Tombstoned(Uuid),
+  // the patch would leave the working state violating referential integrity
+  Violation(Violation),
+  // the commit being reverted added an element that has since been merged into one that it
+  // didn't add, which can't be deleted along with it
+  MergedAway { element: Uuid, into: Uuid },
+}
+
+#[derive(Clone, Debug)]
+pub enum CherryPickError {
+  WorkingPatchNotEmpty,
+  PatchNotFound,
+  CommitNotFound,
+  DetachedHead,
+  NotInHistory,
+  Conflicts(Vec<Conflict>),
+}
+
+#[derive(Clone, Debug)]
+pub struct RebaseOutcome {
+  pub head: Uuid,
+  // (original patch, replayed patch)
+  pub replayed: Vec<(Uuid, Uuid)>,
+  // patches that could not be replayed, and why
+  pub skipped: Vec<(Uuid, Vec<Conflict>)>,
+}
+
+impl std::fmt::Display for Conflict {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Conflict::Missing(uuid) => write!(f, "{} is not in the working set", uuid.as_base64url()),
+      Conflict::AlreadyPresent(uuid) => {
+        write!(f, "{} is already in the working set", uuid.as_base64url())
+      }
+      Conflict::Tombstoned(uuid) => write!(f, "{} has been deleted", uuid.as_base64url()),
+      Conflict::Violation(violation) => write!(f, "{}", violation),
+      Conflict::MergedAway { element, into } => write!(
+        f,
+        "{} has been merged into {}, which the reverted commit didn't add",
+        element.as_base64url(),
+        into.as_base64url()
+      ),
+    }
+  }
+}
+
+impl std::fmt::Display for CherryPickError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      CherryPickError::WorkingPatchNotEmpty => write!(f, "Working patch is not empty"),
+      CherryPickError::PatchNotFound => write!(f, "Patch not found"),
+      CherryPickError::CommitNotFound => write!(f, "Commit not found"),
+      CherryPickError::DetachedHead => write!(f, "Detached head"),
+      CherryPickError::NotInHistory => write!(f, "Commit is not in the history of HEAD"),
+      CherryPickError::Conflicts(conflicts) => write!(
+        f,
+        "Conflicts: {}",
+        conflicts
+          .iter()
+          .map(|conflict| conflict.to_string())
+          .collect::<Vec<_>>()
+          .join(", ")
+      ),
+    }
+  }
+}
+
+impl TotalState {
+  // Apply the changes of an existing patch to the working state, recording them in the working
+  // patch. Nothing is applied if any of them conflicts with the working state.
+  pub fn cherry_pick(&mut self, patch: &Uuid) -> Result<(), CherryPickError> {
+    let patch_luid = self
+      .universe
+      .get_index_of(patch)
+      .filter(|luid| self.patches.contains_key(luid))
+      .ok_or(CherryPickError::PatchNotFound)?;
+    self
+      .cherry_pick_luid(patch_luid)
+      .map_err(CherryPickError::Conflicts)
+  }
+
+  // Whether an element has been deleted or merged away, either in the history of HEAD or by the
+  // working patch, so that it can't be added to the working state.
+  pub(in crate::state) fn is_removed_from_working_state(&self, uuid: &Uuid) -> bool {
+    let working_universe_patch = &self.working_patch.universe_patch;
+    if working_universe_patch.deletions.contains(uuid)
+      || working_universe_patch
+        .merges
+        .get(uuid)
+        .map(|merged_into| merged_into != uuid)
+        .unwrap_or(false)
+    {
+      return true;
+    }
+    let (Some(head), Some(luid)) = (
+      self.working_patch.source_commits.first(),
+      self.universe.get_index_of(uuid),
+    ) else {
+      return false;
+    };
+    self.is_tombstoned(luid, self.universe.get_index_of(head).unwrap())
+  }
+
+  fn cherry_pick_luid(&mut self, patch_luid: Luid) -> Result<(), Vec<Conflict>> {
+    let patch = self.patches.get(&patch_luid).unwrap().clone();
+    self.apply_to_working_state(&patch)
+  }
+
+  // Apply the changes of a patch (whose sources are ignored) to the working state and working
+  // patch, or report every change that conflicts with the working state and apply nothing.
+  pub(in crate::state) fn apply_to_working_state(
+    &mut self,
+    patch: &Patch,
+  ) -> Result<(), Vec<Conflict>> {
+    let universe_patch = &patch.universe_patch;
+    // (the patch may add UUIDs that the universe hasn't seen yet, as an inverse patch does)
+    let is_present = |uuid: &Uuid| {
+      self
+        .universe
+        .get_index_of(uuid)
+        .is_some_and(|luid| self.working_state.contains(&luid))
+    };
+    let mut conflicts = Vec::new();
+    for uuid in universe_patch.deletions.iter() {
+      if !is_present(uuid) {
+        conflicts.push(Conflict::Missing(*uuid));
+      }
+    }
+    for (uuid, merged_into) in universe_patch.merges.iter() {
+      for uuid in [uuid, merged_into] {
+        if !is_present(uuid) {
+          conflicts.push(Conflict::Missing(*uuid));
+        }
+      }
+    }
+    for uuid in universe_patch.additions.iter() {
+      if is_present(uuid) {
+        conflicts.push(Conflict::AlreadyPresent(*uuid));
+      } else if self.is_removed_from_working_state(uuid) {
+        conflicts.push(Conflict::Tombstoned(*uuid));
+      }
+    }
+    if !conflicts.is_empty() {
+      return Err(conflicts);
+    }
+
+    self.universe.extend(patch.referenced_uuids());
+    let luid = |uuid: &Uuid| self.universe.get_index_of(uuid).unwrap();
+
+    let working_universe_patch = &mut self.working_patch.universe_patch;
+    for uuid in universe_patch.deletions.iter() {
+      self.working_state.shift_remove(&luid(uuid));
+      // (deleting an element added by the working patch just cancels the addition)
+      if !working_universe_patch.additions.remove(uuid) {
+        working_universe_patch.deletions.insert(*uuid);
+      }
+    }
+    for (uuid, merged_into) in universe_patch.merges.iter() {
+      if uuid != merged_into {
+        self.working_state.shift_remove(&luid(uuid));
+      }
+      working_universe_patch.merges.insert(*uuid, *merged_into);
+    }
+    for uuid in universe_patch.additions.iter() {
+      self.working_state.insert(luid(uuid));
+      working_universe_patch.additions.insert(*uuid);
+    }
+    self.working_patch.addition_kinds.extend(
+      patch
+        .addition_kinds
+        .iter()
+        .map(|(uuid, kind)| (*uuid, kind.clone())),
+    );
+    let working_context_patch = &mut self.working_patch.context_patch;
+    working_context_patch
+      .deletions
+      .extend(patch.context_patch.deletions.iter().cloned());
+    working_context_patch.additions.extend(
+      patch
+        .context_patch
+        .additions
+        .iter()
+        .map(|(name, uuid)| (name.clone(), *uuid)),
+    );
+    let working_hom_patch = &mut self.working_patch.hom_patch;
+    working_hom_patch
+      .deletions
+      .extend(patch.hom_patch.deletions.iter().copied());
+    working_hom_patch.additions.extend(
+      patch
+        .hom_patch
+        .additions
+        .iter()
+        .map(|(key, value)| (*key, *value)),
+    );
+    let working_attr_patch = &mut self.working_patch.attr_patch;
+    working_attr_patch
+      .deletions
+      .extend(patch.attr_patch.deletions.iter().copied());
+    working_attr_patch.additions.extend(
+      patch
+        .attr_patch
+        .additions
+        .iter()
+        .map(|(key, value)| (*key, value.clone())),
+    );
+    Ok(())
+  }
+
+  // Replay the patches of HEAD's branch (those not already in the history of `onto`) on top of
+  // `onto`, committing each one as a new commit, oldest first. Merge patches are not replayed, so
+  // the result is linear. A patch that conflicts with the state it would be replayed onto is
+  // skipped and reported.
+  pub fn rebase(&mut self, onto: &Uuid) -> Result<RebaseOutcome, CherryPickError> {
+    self.rebase_in(onto, &Self::get_patch_dir())
+  }
+
+  // As `rebase`, writing the new patches to `patch_dir`.
+  pub(crate) fn rebase_in(
+    &mut self,
+    onto: &Uuid,
+    patch_dir: &Path,
+  ) -> Result<RebaseOutcome, CherryPickError> {
+    let onto_luid = self
+      .universe
+      .get_index_of(onto)
+      .filter(|luid| self.commits.contains_key(luid))
+      .ok_or(CherryPickError::CommitNotFound)?;
+    if !self.working_patch.is_empty() {
+      return Err(CherryPickError::WorkingPatchNotEmpty);
+    }
+    let head_uuid = *self
+      .working_patch
+      .source_commits
+      .first()
+      .ok_or(CherryPickError::DetachedHead)?;
+    let head_luid = self.universe.get_index_of(&head_uuid).unwrap();
+    let mut outcome = RebaseOutcome {
+      head: head_uuid,
+      replayed: Vec::new(),
+      skipped: Vec::new(),
+    };
+    if self.is_ancestor(onto_luid, head_luid) {
+      return Ok(outcome);
+    }
+
+    let onto_ancestors = self.ancestors(onto_luid);
+    let mut branch: Vec<Luid> = self
+      .ancestors(head_luid)
+      .difference(&onto_ancestors)
+      .copied()
+      .collect();
+    branch.sort_by_key(|luid| (self.generation(*luid), *luid));
+    self
+      .checkout_luid(onto_luid)
+      .map_err(|_| CherryPickError::CommitNotFound)?;
+    for commit in branch {
+      let patch_luid = self.commits.get(&commit).unwrap()[0].1;
+      if self.patches.get(&patch_luid).unwrap().source_commits.len() > 1 {
+        continue;
+      }
+      let patch_uuid = *self.universe.get_index(patch_luid).unwrap();
+      match self.cherry_pick_luid(patch_luid) {
+        Ok(()) => {
+          self.working_patch.message = self.patches.get(&patch_luid).unwrap().message.clone();
+          match self.commit_in(patch_dir) {
+            Ok((new_patch_uuid, _)) => outcome.replayed.push((patch_uuid, new_patch_uuid)),
+            Err(CommitError::Violations(violations)) => {
+              // (its changes are dropped from the working state again)
+              let head = self.working_patch.source_commits[0];
+              self.working_patch.clear();
+              self.checkout(&head).unwrap();
+              let conflicts = violations.into_iter().map(Conflict::Violation).collect();
+              outcome.skipped.push((patch_uuid, conflicts));
+            }
+          }
+        }
+        Err(conflicts) => outcome.skipped.push((patch_uuid, conflicts)),
+      }
+    }
+    outcome.head = self.working_patch.source_commits[0];
+    Ok(outcome)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::BTreeSet;
+
+  fn working_set(state: &TotalState) -> BTreeSet<Uuid> {
+    state.list().copied().collect()
+  }
+
+  fn patch_of(state: &TotalState, commit: &Uuid) -> Uuid {
+    let commit = state.universe.get_index_of(commit).unwrap();
+    let patch = state.commits.get(&commit).unwrap()[0].1;
+    *state.universe.get_index(patch).unwrap()
+  }
+
+  fn parents(state: &TotalState, commit: &Uuid) -> Vec<Uuid> {
+    let commit = state.universe.get_index_of(commit).unwrap();
+    state
+      .parents(commit)
+      .map(|parent| *state.universe.get_index(parent).unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn cherry_picks_apply_a_patch_to_another_branch() {
+    let mut state = TotalState::default();
+    let x = state.add();
+    let root = state.commit_in_memory();
+    let y = state.add();
+    state.name(&y, &["y".to_string()]);
+    let c = state.commit_in_memory();
+    state.checkout(&root).unwrap();
+    state.cherry_pick(&patch_of(&state, &c)).unwrap();
+    assert_eq!(working_set(&state), BTreeSet::from([x, y]));
+    state.commit_in_memory();
+    assert_eq!(state.lookup("y"), Some(y));
+    assert!(matches!(
+      state.cherry_pick(&Uuid::now_v7()),
+      Err(CherryPickError::PatchNotFound)
+    ));
+  }
+
+  #[test]
+  fn cherry_picks_that_clash_with_head_are_conflicts() {
+    let mut state = TotalState::default();
+    let x = state.add();
+    let root = state.commit_in_memory();
+    // one branch adds y and deletes x
+    let y = state.add();
+    state.delete(&x).unwrap();
+    let c = state.commit_in_memory();
+    // (and another branch has picked that up already)
+    state.checkout(&root).unwrap();
+    state.cherry_pick(&patch_of(&state, &c)).unwrap();
+    state.commit_in_memory();
+    let before = working_set(&state);
+    match state.cherry_pick(&patch_of(&state, &c)) {
+      Err(CherryPickError::Conflicts(conflicts)) => {
+        assert!(matches!(
+          conflicts[..],
+          [Conflict::Missing(missing), Conflict::AlreadyPresent(present)]
+            if missing == x && present == y
+        ));
+      }
+      other => panic!("expected conflicts, got {:?}", other),
+    }
+    // (nothing is applied)
+    assert_eq!(working_set(&state), before);
+    assert!(state.working_patch.is_empty());
+  }
+
+  #[test]
+  fn cherry_picking_an_addition_that_was_deleted_is_a_conflict() {
+    let mut state = TotalState::default();
+    state.add();
+    state.commit_in_memory();
+    let z = state.add();
+    let c = state.commit_in_memory();
+    state.delete(&z).unwrap();
+    state.commit_in_memory();
+    assert!(matches!(
+      state.cherry_pick(&patch_of(&state, &c)),
+      Err(CherryPickError::Conflicts(conflicts))
+        if matches!(conflicts[..], [Conflict::Tombstoned(tombstoned)] if tombstoned == z)
+    ));
+  }
+
+  #[test]
+  fn rebasing_replays_a_branch_as_new_commits() {
+    let patch_dir = std::env::temp_dir().join(format!("i1_{}", Uuid::now_v7().as_base64url()));
+    let mut state = TotalState::default();
+    let x = state.add();
+    let root = state.commit_in_memory();
+    // onto: x is deleted, and z is added and deleted
+    state.delete(&x).unwrap();
+    state.commit_in_memory();
+    let z = state.add();
+    let adds_z = state.commit_in_memory();
+    state.delete(&z).unwrap();
+    let onto = state.commit_in_memory();
+    // the branch adds y, then z (as a cherry-pick), then w, and then deletes x
+    state.checkout(&root).unwrap();
+    let y = state.add();
+    state.commit_in_memory();
+    state.cherry_pick(&patch_of(&state, &adds_z)).unwrap();
+    state.commit_in_memory();
+    let w = state.add();
+    state.commit_in_memory();
+    state.delete(&x).unwrap();
+    state.commit_in_memory();
+    let branch_patches: Vec<Uuid> = {
+      let head = state.working_patch.source_commits[0];
+      let mut commits = vec![head];
+      while let [parent] = parents(&state, commits.last().unwrap())[..] {
+        commits.push(parent);
+      }
+      commits.pop();
+      commits
+        .iter()
+        .rev()
+        .map(|commit| patch_of(&state, commit))
+        .collect()
+    };
+
+    let outcome = state.rebase_in(&onto, &patch_dir).unwrap();
+    std::fs::remove_dir_all(&patch_dir).unwrap();
+    // (z has been deleted in the history of onto, and x is gone from it)
+    let skipped: Vec<Uuid> = outcome.skipped.iter().map(|(patch, _)| *patch).collect();
+    assert_eq!(skipped, [branch_patches[1], branch_patches[3]]);
+    assert!(matches!(
+      outcome.skipped[0].1[..],
+      [Conflict::Tombstoned(tombstoned)] if tombstoned == z
+    ));
+    assert!(matches!(
+      outcome.skipped[1].1[..],
+      [Conflict::Missing(missing)] if missing == x
+    ));
+    let replayed: Vec<Uuid> = outcome.replayed.iter().map(|(patch, _)| *patch).collect();
+    assert_eq!(replayed, [branch_patches[0], branch_patches[2]]);
+    // the new commits form a chain on top of onto
+    let new_commits: Vec<Uuid> = outcome
+      .replayed
+      .iter()
+      .map(|(_, new_patch)| {
+        let luid = state.universe.get_index_of(new_patch).unwrap();
+        state.patches.get(&luid).unwrap().target_commit
+      })
+      .collect();
+    assert_eq!(parents(&state, &new_commits[0]), [onto]);
+    assert_eq!(parents(&state, &new_commits[1]), [new_commits[0]]);
+    assert_eq!(outcome.head, new_commits[1]);
+    assert_eq!(state.working_patch.source_commits[..], [outcome.head]);
+    assert_eq!(working_set(&state), BTreeSet::from([y, w]));
+  }
+}
@@ -5,9 +5,12 @@ use rkyv::ser::{
   serializers::{AllocScratch, CompositeSerializer, FallbackScratch, HeapScratch, WriteSerializer},
   Serializer,
 };
+use rkyv::{check_archived_root, Deserialize};
 use std::{
   cell::RefCell,
   fs::{self, File},
+  io::Write,
+  path::Path,
   thread_local,
 };
 
@@ -15,8 +18,66 @@ thread_local! {
   static RKYV_SCRATCH : RefCell<FallbackScratch<HeapScratch<{1 << 27}>, AllocScratch>> = RefCell::new(FallbackScratch::new(HeapScratch::new(), AllocScratch::new()));
 }
 
+// Serialize a patch to a file using rkyv::ser::serializers::WriteSerializer, after a header with the
+// format version (see `PATCH_FORMAT_VERSION`); the header is 16 bytes long, so the archive stays
+// aligned.
+pub(in crate::state) fn write_patch(file: &mut File, patch: &Patch) {
+  file.write_all(PATCH_MAGIC).unwrap();
+  file.write_all(&PATCH_FORMAT_VERSION.to_le_bytes()).unwrap();
+  RKYV_SCRATCH.with(|scratch| {
+    let scratch_inner = scratch.replace(FallbackScratch::new(
+      HeapScratch::new(),
+      AllocScratch::new(),
+    ));
+    let mut serializer: CompositeSerializer<WriteSerializer<_>, FallbackScratch<_, _>, _> =
+      CompositeSerializer::new(WriteSerializer::new(file), scratch_inner, rkyv::Infallible);
+
+    serializer.serialize_value(patch).unwrap();
+
+    serializer.into_components().1 // return borrowed scratch space
+  });
+}
+
+// Validate and deserialize a patch written by `write_patch`, if it is in the current format, or a
+// headerless `LegacyPatch` written before there were format versions.
+pub(in crate::state) fn read_patch(bytes: &[u8]) -> Result<Patch, String> {
+  let header_len = PATCH_MAGIC.len() + 8;
+  if bytes.len() < header_len || &bytes[..PATCH_MAGIC.len()] != PATCH_MAGIC {
+    let legacy = check_archived_root::<LegacyPatch>(bytes).map_err(|e| {
+      format!(
+        "not a patch in the current format, nor in the legacy format without a header: {}",
+        e
+      )
+    })?;
+    let legacy: LegacyPatch = legacy.deserialize(&mut rkyv::Infallible).unwrap();
+    return Patch::try_from(legacy);
+  }
+  let version = u64::from_le_bytes(bytes[PATCH_MAGIC.len()..header_len].try_into().unwrap());
+  if version != PATCH_FORMAT_VERSION {
+    return Err(format!(
+      "patch format version {} is not supported (expected {})",
+      version, PATCH_FORMAT_VERSION
+    ));
+  }
+  let patch = check_archived_root::<Patch>(&bytes[header_len..]).map_err(|e| e.to_string())?;
+  // (note: this deserialization may perform unnecessary copies, but is memory-safe)
+  Ok(patch.deserialize(&mut rkyv::Infallible).unwrap())
+}
+
 impl TotalState {
-  pub fn commit(&mut self) -> (Uuid, &Patch) {
+  // Write the working patch as a new patch and commit, unless the version it yields violates
+  // referential integrity (see `check_integrity`), in which case nothing is written.
+  pub fn commit(&mut self) -> Result<(Uuid, &Patch), CommitError> {
+    self.commit_in(&Self::get_patch_dir())
+  }
+
+  // As `commit`, writing the patch to `patch_dir`.
+  pub(crate) fn commit_in(&mut self, patch_dir: &Path) -> Result<(Uuid, &Patch), CommitError> {
+    let violations = self.check_integrity();
+    if !violations.is_empty() {
+      return Err(CommitError::Violations(violations));
+    }
+
     // make a new UUID for the patch
     let new_patch_id = Uuid::now_v7();
     // make a new UUID for the commit
@@ -28,31 +89,21 @@ impl TotalState {
     let new_patch_id_str = new_patch_id.as_base64url();
 
     // open the file for writing
-    let patch_dir = Self::get_patch_dir();
-    fs::create_dir_all(&patch_dir).unwrap();
+    fs::create_dir_all(patch_dir).unwrap();
     let mut file = File::create(patch_dir.join("patch_".to_string() + &new_patch_id_str)).unwrap();
 
-    // Serialize the patch to the file using rkyv::ser::serializers::WriteSerializer
-    RKYV_SCRATCH.with(|scratch| {
-      let scratch_inner = scratch.replace(FallbackScratch::new(
-        HeapScratch::new(),
-        AllocScratch::new(),
-      ));
-      let mut serializer: CompositeSerializer<WriteSerializer<_>, FallbackScratch<_, _>, _> =
-        CompositeSerializer::new(
-          WriteSerializer::new(&mut file),
-          scratch_inner,
-          rkyv::Infallible,
-        );
+    write_patch(&mut file, &self.working_patch);
 
-      serializer.serialize_value(&self.working_patch).unwrap();
-
-      serializer.into_components().1 // return borrowed scratch space
-    });
+    let new_patch_luid = self.record_working_patch(new_patch_id);
+    Ok((new_patch_id, self.patches.get(&new_patch_luid).unwrap()))
+  }
 
+  // Index and process the working patch (whose target commit has been assigned) as the patch
+  // `patch_id`, and start a new working patch on top of its target.
+  pub(in crate::state) fn record_working_patch(&mut self, patch_id: Uuid) -> Luid {
     let written_patch = std::mem::take(&mut self.working_patch);
-    self.working_patch.clear();
-    let new_patch_luid = self.index_patch(new_patch_id, written_patch);
+    let new_commit_id = written_patch.target_commit;
+    let new_patch_luid = self.index_patch(patch_id, written_patch);
     process_patch(
       &mut self.universe,
       &mut self.version_cache,
@@ -60,10 +111,126 @@ impl TotalState {
       &mut self.heads,
       &self.patches,
       new_patch_luid,
+      |_| false,
     );
+    self.version_cache.evict(&self.heads, |_| false);
     self.working_patch.source_commits.push(new_commit_id);
-    self.working_patch.target_commit = Default::default();
+    // (the new version can lack elements of the working state, such as those that merges closed
+    // under congruence removed)
+    let new_commit_luid = self.universe.get_index_of(&new_commit_id).unwrap();
+    self.working_state = self
+      .version(new_commit_luid)
+      .unwrap()
+      .version_universe
+      .iter()
+      .map(|x| x as usize)
+      .collect();
+    new_patch_luid
+  }
+
+  // As `commit`, without writing the patch to disk, for tests; returns the new commit.
+  #[cfg(test)]
+  pub(crate) fn commit_in_memory(&mut self) -> Uuid {
+    let violations = self.check_integrity();
+    assert!(violations.is_empty(), "{:?}", violations);
+    let new_commit_id = Uuid::now_v7();
+    self.working_patch.target_commit = new_commit_id;
+    self.record_working_patch(Uuid::now_v7());
+    new_commit_id
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Read;
+
+  #[test]
+  fn patches_round_trip_with_their_format_version() {
+    let path = std::env::temp_dir().join(format!("i1_patch_{}", Uuid::now_v7().as_base64url()));
+    let mut patch = Patch {
+      target_commit: Uuid::now_v7(),
+      message: "hello".to_string(),
+      ..Default::default()
+    };
+    patch.universe_patch.additions.insert(Uuid::now_v7());
+    write_patch(&mut File::create(&path).unwrap(), &patch);
+    let mut bytes = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+    fs::remove_file(&path).unwrap();
+    // (copied to an aligned buffer, as a memory map would be)
+    let mut aligned = rkyv::AlignedVec::new();
+    aligned.extend_from_slice(&bytes);
+    let read = read_patch(&aligned).unwrap();
+    assert_eq!(read.target_commit, patch.target_commit);
+    assert_eq!(read.message, "hello");
+    assert_eq!(
+      read.universe_patch.additions,
+      patch.universe_patch.additions
+    );
+
+    // patches from another version of the format are refused
+    let mut other_version = aligned.clone();
+    other_version[8] = 0xff;
+    assert!(read_patch(&other_version).is_err());
+  }
+
+  #[test]
+  fn headerless_patches_are_read_in_the_legacy_format() {
+    let mut legacy = LegacyPatch {
+      target_commit: Uuid::now_v7(),
+      ..Default::default()
+    };
+    legacy.source_commits.push(Uuid::now_v7());
+    let [added, deleted, merged, into] = [(); 4].map(|_| Uuid::now_v7());
+    legacy.universe_patch.additions.insert(added);
+    legacy.universe_patch.deletions.insert(deleted);
+    legacy.universe_patch.merges.insert(merged, into);
+    let name = vec!["Person".to_string(), "ada".to_string()];
+    legacy.context_patch.additions.insert(name.clone(), added);
+    let bytes = rkyv::to_bytes::<_, 256>(&legacy).unwrap();
+    let read = read_patch(&bytes).unwrap();
+    assert_eq!(read.target_commit, legacy.target_commit);
+    assert_eq!(read.source_commits, legacy.source_commits);
+    assert!(read.universe_patch == legacy.universe_patch);
+    assert_eq!(read.context_patch.additions[&name], added);
+    assert!(read.addition_kinds.is_empty() && read.hom_patch.is_empty());
+
+    // (but not if it lists kinds of additions, nor if it isn't a patch at all)
+    legacy.addition_kinds.push(LegacyAdditionKind::NewSort);
+    let bytes = rkyv::to_bytes::<_, 256>(&legacy).unwrap();
+    assert!(read_patch(&bytes).is_err());
+    let mut garbage = rkyv::AlignedVec::new();
+    garbage.extend_from_slice(&[0xff; 64]);
+    assert!(read_patch(&garbage).is_err());
+  }
 
-    (new_patch_id, self.patches.get(&new_patch_luid).unwrap())
+  #[test]
+  fn commit_refreshes_the_working_state() {
+    let mut state = TotalState::default();
+    let csv = |names: &str| format!("name\n{}\n", names.replace(' ', "\n"));
+    state
+      .import_csv(csv("a b").as_bytes(), &ImportOptions::new("P"))
+      .unwrap();
+    state
+      .import_csv(csv("x y").as_bytes(), &ImportOptions::new("C"))
+      .unwrap();
+    let [p, c, a, b, x, y] =
+      ["P", "C", "P/a", "P/b", "C/x", "C/y"].map(|name| state.lookup(name).unwrap());
+    let hom = state.add_hom(&p, &c, false, false).unwrap();
+    state.set_hom_value(&hom, &a, &x).unwrap();
+    state.set_hom_value(&hom, &b, &y).unwrap();
+    state.commit_in_memory();
+    // merging b into a merges their values, y and x, by congruence
+    state.merge_into(&b, &a).unwrap();
+    state.commit_in_memory();
+    // (into whichever is older)
+    let representative = state.canonical(&y).unwrap();
+    assert_eq!(state.canonical(&x), Some(representative));
+    let merged = if representative == x { y } else { x };
+    let working_state: Vec<Uuid> = state.list().copied().collect();
+    assert!(!working_state.contains(&b));
+    assert!(!working_state.contains(&merged));
+    assert!(working_state.contains(&representative));
   }
 }
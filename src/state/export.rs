@@ -0,0 +1,363 @@
+use crate::id::*;
+use crate::state::*;
+use crate::version::*;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::io::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+  // the whole version as one JSON object
+  Json,
+  // one row per naming of each element (or per element, if it has no names)
+  Csv,
+  // the JSON shape of Catlab.jl's `generate_json_acset`, with a table per sort
+  Acset,
+}
+
+#[derive(Clone, Debug)]
+pub enum ExportError {
+  CommitNotFound,
+  Write(String),
+}
+
+impl std::fmt::Display for ExportError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ExportError::CommitNotFound => write!(f, "Commit not found"),
+      ExportError::Write(message) => write!(f, "Could not write output: {}", message),
+    }
+  }
+}
+
+impl TotalState {
+  fn commit_version(&mut self, commit: &Uuid) -> Result<Version, ExportError> {
+    let luid = self
+      .universe
+      .get_index_of(commit)
+      .ok_or(ExportError::CommitNotFound)?;
+    self
+      .version(luid)
+      .cloned()
+      .ok_or(ExportError::CommitNotFound)
+  }
+
+  fn uuid_string(&self, luid: Luid) -> String {
+    self.universe.get_index(luid).unwrap().as_base64url()
+  }
+
+  // The name of each element of the version that has one (the first, if there are several).
+  fn first_names(&self, version: &Version) -> HashMap<Luid, Vec<String>> {
+    let mut names = HashMap::new();
+    for (path, luid) in version.ctx.namings() {
+      names.entry(luid).or_insert(path);
+    }
+    names
+  }
+
+  pub fn export_json(&mut self, commit: &Uuid) -> Result<Value, ExportError> {
+    let version = self.commit_version(commit)?;
+    let sorts: Vec<Value> = version
+      .s0
+      .iter()
+      .zip(version.s0i.iter())
+      .map(|(sort, elements)| {
+        json!({
+          "sort": self.uuid_string(sort as Luid),
+          "elements": elements.iter().map(|luid| self.uuid_string(luid as Luid)).collect::<Vec<_>>(),
+        })
+      })
+      .collect();
+    let names: Vec<Value> = version
+      .ctx
+      .namings()
+      .into_iter()
+      .map(|(path, luid)| json!({ "name": path, "element": self.uuid_string(luid) }))
+      .collect();
+    let homs: Vec<Value> = version
+      .homs
+      .iter()
+      .map(|(hom_luid, hom)| {
+        let elements = version.sort_elements(hom.domain).unwrap();
+        let values: Map<String, Value> = elements
+          .iter()
+          .zip(hom.column.iter())
+          .filter_map(|(element, value)| {
+            let value = self.uuid_string((*value)?);
+            Some((self.uuid_string(element as Luid), json!(value)))
+          })
+          .collect();
+        json!({
+          "hom": self.uuid_string(*hom_luid),
+          "domain": self.uuid_string(hom.domain),
+          "codomain": self.uuid_string(hom.codomain),
+          "partial": hom.partial,
+          "free": hom.free,
+          "values": values,
+        })
+      })
+      .collect();
+    let attrs: Vec<Value> = version
+      .attrs
+      .iter()
+      .map(|(attr_luid, attr)| {
+        let elements = version.sort_elements(attr.domain).unwrap();
+        let values: Map<String, Value> = elements
+          .iter()
+          .zip(attr.column.iter())
+          .filter_map(|(element, value)| {
+            Some((
+              self.uuid_string(element as Luid),
+              self.types.to_json(value.as_ref()?),
+            ))
+          })
+          .collect();
+        json!({
+          "attr": self.uuid_string(*attr_luid),
+          "domain": self.uuid_string(attr.domain),
+          "type": attr.attr_type.as_base64url(),
+          "values": values,
+        })
+      })
+      .collect();
+    Ok(json!({
+      "commit": commit.as_base64url(),
+      "elements": version
+        .version_universe
+        .iter()
+        .map(|luid| self.uuid_string(luid as Luid))
+        .collect::<Vec<_>>(),
+      "sorts": sorts,
+      "names": names,
+      "homs": homs,
+      "attrs": attrs,
+    }))
+  }
+
+  pub fn export_csv(&mut self, commit: &Uuid, writer: impl Write) -> Result<(), ExportError> {
+    let version = self.commit_version(commit)?;
+    let mut names: HashMap<Luid, Vec<String>> = HashMap::new();
+    for (path, luid) in version.ctx.namings() {
+      names.entry(luid).or_default().push(path.join("/"));
+    }
+    let write_error = |e: csv::Error| ExportError::Write(e.to_string());
+    let mut writer = csv::Writer::from_writer(writer);
+    writer
+      .write_record(["uuid", "sort", "name"])
+      .map_err(write_error)?;
+    for luid in version.version_universe.iter() {
+      let luid = luid as Luid;
+      let uuid = self.uuid_string(luid);
+      let sort = version
+        .sort_of(luid)
+        .map(|sort| self.uuid_string(sort))
+        .unwrap_or_default();
+      let unnamed = vec![String::new()];
+      for name in names.get(&luid).unwrap_or(&unnamed) {
+        writer
+          .write_record([&uuid, &sort, name])
+          .map_err(write_error)?;
+      }
+    }
+    writer
+      .flush()
+      .map_err(|e| ExportError::Write(e.to_string()))
+  }
+
+  // Each sort becomes an object of the schema and each hom and attribute a morphism, named by their
+  // (first) name (the last part of it, for homs and attributes) or else their UUID. Each element of
+  // a sort becomes a part, numbered from 1 as in Julia, with its UUID as an attribute, the part
+  // numbers of its values under each hom out of the sort (0 where there is no value) and its values
+  // of each attribute (null where there is none).
+  pub fn export_acset(&mut self, commit: &Uuid) -> Result<Value, ExportError> {
+    let version = self.commit_version(commit)?;
+    let names = self.first_names(&version);
+    let name = |luid: Luid| match names.get(&luid).and_then(|path| path.last()) {
+      Some(name) if version.homs.contains_key(&luid) || version.attrs.contains_key(&luid) => {
+        name.clone()
+      }
+      _ => match names.get(&luid) {
+        Some(path) => path.join("/"),
+        None => self.uuid_string(luid),
+      },
+    };
+    let mut acset = Map::new();
+    for (sort, elements) in version.s0.iter().zip(version.s0i.iter()) {
+      let sort = sort as Luid;
+      let homs: Vec<_> = version
+        .homs
+        .iter()
+        .filter(|(_, hom)| hom.domain == sort)
+        .map(|(hom_luid, hom)| (name(*hom_luid), hom))
+        .collect();
+      let attrs: Vec<_> = version
+        .attrs
+        .iter()
+        .filter(|(_, attr)| attr.domain == sort)
+        .map(|(attr_luid, attr)| (name(*attr_luid), attr))
+        .collect();
+      let parts: Vec<Value> = elements
+        .iter()
+        .enumerate()
+        .map(|(i, luid)| {
+          let mut part = Map::new();
+          part.insert("_id".to_string(), json!(i + 1));
+          part.insert("uuid".to_string(), json!(self.uuid_string(luid as Luid)));
+          for (hom_name, hom) in homs.iter() {
+            let codomain = version.sort_elements(hom.codomain).unwrap();
+            let id = hom.column[i]
+              .filter(|value| codomain.contains(*value as u64))
+              .map(|value| codomain.rank(value as u64))
+              .unwrap_or(0);
+            part.insert(hom_name.clone(), json!(id));
+          }
+          for (attr_name, attr) in attrs.iter() {
+            let value = attr.column[i]
+              .as_ref()
+              .map(|value| self.types.to_json(value));
+            part.insert(attr_name.clone(), value.unwrap_or(Value::Null));
+          }
+          Value::Object(part)
+        })
+        .collect();
+      acset.insert(name(sort), Value::Array(parts));
+    }
+    Ok(Value::Object(acset))
+  }
+
+  pub fn export(
+    &mut self,
+    commit: &Uuid,
+    format: ExportFormat,
+    mut writer: impl Write,
+  ) -> Result<(), ExportError> {
+    let value = match format {
+      ExportFormat::Csv => return self.export_csv(commit, writer),
+      ExportFormat::Json => self.export_json(commit)?,
+      ExportFormat::Acset => self.export_acset(commit)?,
+    };
+    writeln!(writer, "{}", value).map_err(|e| ExportError::Write(e.to_string()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::state::import::ImportOptions;
+  use std::collections::BTreeSet;
+
+  // People who live in a city, with `lives_in : Person -> City` and an attribute `age` of people.
+  fn people() -> (TotalState, Uuid) {
+    let mut state = TotalState::default();
+    let people = "name\nada\nalan\n";
+    state
+      .import_csv(people.as_bytes(), &ImportOptions::new("Person"))
+      .unwrap();
+    state
+      .import_csv("name\nlondon\n".as_bytes(), &ImportOptions::new("City"))
+      .unwrap();
+    let [person, city, ada, london] =
+      ["Person", "City", "Person/ada", "City/london"].map(|name| state.lookup(name).unwrap());
+    let i64_type = state.types.lookup_name("I64").unwrap();
+    let lives_in = state.add_hom(&person, &city, true, false).unwrap();
+    let age = state.add_attr(&person, &i64_type).unwrap();
+    state.name(&lives_in, &["Person".to_string(), "lives_in".to_string()]);
+    state.name(&age, &["Person".to_string(), "age".to_string()]);
+    state.set_hom_value(&lives_in, &ada, &london).unwrap();
+    let value = state.parse_attr_value(&age, "36").unwrap();
+    state.set_attr_value(&age, &ada, value).unwrap();
+    let commit = state.commit_in_memory();
+    (state, commit)
+  }
+
+  #[test]
+  fn json_exports_have_every_element_name_and_value() {
+    let (mut state, commit) = people();
+    let json = state.export_json(&commit).unwrap();
+    let elements: BTreeSet<String> = state.list().map(|uuid| uuid.as_base64url()).collect();
+    let exported: BTreeSet<String> = json["elements"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|uuid| uuid.as_str().unwrap().to_string())
+      .collect();
+    assert_eq!(exported, elements);
+    let [ada, london] = ["Person/ada", "City/london"].map(|name| state.lookup(name).unwrap());
+    assert!(json["names"]
+      .as_array()
+      .unwrap()
+      .contains(&json!({ "name": ["Person", "ada"], "element": ada.as_base64url() })));
+    assert_eq!(json["sorts"].as_array().unwrap().len(), 2);
+    assert_eq!(
+      json["homs"][0]["values"],
+      json!({ ada.as_base64url(): london.as_base64url() })
+    );
+    assert_eq!(
+      json["attrs"][0]["values"],
+      json!({ ada.as_base64url(): 36 })
+    );
+    assert_eq!(json["homs"][0]["partial"], true);
+    assert_eq!(json["homs"][0]["free"], false);
+    // (and a free hom is exported as such, though its terms aren't values)
+    let [person, city] = ["Person", "City"].map(|name| state.lookup(name).unwrap());
+    let mayor = state.add_hom(&city, &person, false, true).unwrap();
+    let commit = state.commit_in_memory();
+    let json = state.export_json(&commit).unwrap();
+    let mayor = json["homs"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .find(|hom| hom["hom"] == mayor.as_base64url())
+      .unwrap();
+    assert_eq!(mayor["free"], true);
+    assert_eq!(mayor["partial"], false);
+    assert_eq!(mayor["values"], json!({}));
+    assert!(matches!(
+      state.export_json(&Uuid::now_v7()),
+      Err(ExportError::CommitNotFound)
+    ));
+  }
+
+  #[test]
+  fn csv_exports_can_be_imported_again() {
+    let (mut state, commit) = people();
+    let mut exported = Vec::new();
+    state.export_csv(&commit, &mut exported).unwrap();
+    let person = state.lookup("Person").unwrap().as_base64url();
+    // the people, under the last part of their names
+    let mut people = "uuid,name\n".to_string();
+    for row in csv::Reader::from_reader(&exported[..]).records() {
+      let row = row.unwrap();
+      if row[1] == person {
+        let name = row[2].rsplit('/').next().unwrap();
+        people.push_str(&format!("{},{}\n", &row[0], name));
+      }
+    }
+    let mut imported = TotalState::default();
+    let summary = imported
+      .import_csv(people.as_bytes(), &ImportOptions::new("Person"))
+      .unwrap();
+    assert_eq!((summary.created, summary.named), (2, 2));
+    for name in ["Person/ada", "Person/alan"] {
+      assert_eq!(imported.lookup(name), state.lookup(name));
+    }
+  }
+
+  #[test]
+  fn acset_exports_number_the_parts_of_each_sort() {
+    let (mut state, commit) = people();
+    let acset = state.export_acset(&commit).unwrap();
+    let ada = state.lookup("Person/ada").unwrap().as_base64url();
+    let london = state.lookup("City/london").unwrap().as_base64url();
+    assert_eq!(acset["City"], json!([{ "_id": 1, "uuid": london }]));
+    let people = acset["Person"].as_array().unwrap();
+    assert_eq!(people.len(), 2);
+    for (i, part) in people.iter().enumerate() {
+      assert_eq!(part["_id"], json!(i + 1));
+      match part["uuid"] == json!(ada) {
+        true => assert_eq!((&part["lives_in"], &part["age"]), (&json!(1), &json!(36))),
+        false => assert_eq!((&part["lives_in"], &part["age"]), (&json!(0), &Value::Null)),
+      }
+    }
+  }
+}
@@ -38,8 +38,13 @@ impl TotalState {
     let mut tracks: HashMap<Luid, usize> = HashMap::new();
     let mut n_tracks_total: usize = 0;
     let mut track: usize;
-    for (commit_luid, reachable_by) in self.commits.iter().rev() {
-      let reached_by = reachable_by.get(0).unwrap();
+    // newest first: every commit is drawn before its parents, whatever order it was indexed in
+    let mut commits: Vec<_> = self.commits.iter().collect();
+    commits.sort_by_key(|(commit_luid, _)| {
+      std::cmp::Reverse((self.generation(**commit_luid), **commit_luid))
+    });
+    for (commit_luid, reachable_by) in commits {
+      let reached_by = reachable_by.first().unwrap();
       if let Some(&existing_track) = tracks.get(commit_luid) {
         track = existing_track;
       } else {
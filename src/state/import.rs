@@ -0,0 +1,373 @@
+use crate::id::*;
+use crate::patch::*;
+use crate::state::*;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Read};
+
+#[derive(Clone, Debug)]
+pub struct ImportOptions {
+  // name of the sort to import into (created if there is no sort by that name)
+  pub sort: String,
+  // column naming each entity, within the sort
+  pub name_column: String,
+  // column with the UUIDs of existing entities (or of new entities that should keep their UUIDs)
+  pub uuid_column: String,
+}
+
+impl ImportOptions {
+  pub fn new(sort: &str) -> Self {
+    Self {
+      sort: sort.to_string(),
+      name_column: "name".to_string(),
+      uuid_column: "uuid".to_string(),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct ImportSummary {
+  pub sort: Uuid,
+  pub new_sort: bool,
+  pub created: usize,
+  pub reused: usize,
+  pub named: usize,
+}
+
+#[derive(Clone, Debug)]
+pub enum ImportError {
+  Read(String),
+  // (records are numbered from 1, not counting a header)
+  Parse { record: usize, message: String },
+  InvalidUuid { record: usize, value: String },
+  NotASort(String),
+  Tombstoned(Uuid),
+  NameTaken { name: Vec<String>, uuid: Uuid },
+  // an existing element that is a sort, or an entity of another sort
+  NotInSort { uuid: Uuid, sort: String },
+}
+
+impl std::fmt::Display for ImportError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ImportError::Read(message) => write!(f, "Could not read input: {}", message),
+      ImportError::Parse { record, message } => write!(f, "Record {}: {}", record, message),
+      ImportError::InvalidUuid { record, value } => {
+        write!(f, "Record {}: invalid UUID {:?}", record, value)
+      }
+      ImportError::NotASort(name) => write!(f, "{:?} names something that is not a sort", name),
+      ImportError::Tombstoned(uuid) => write!(f, "{} has been deleted", uuid.as_base64url()),
+      ImportError::NameTaken { name, uuid } => write!(
+        f,
+        "{:?} already names {}",
+        name.join("/"),
+        uuid.as_base64url()
+      ),
+      ImportError::NotInSort { uuid, sort } => {
+        write!(f, "{} is not an entity of {:?}", uuid.as_base64url(), sort)
+      }
+    }
+  }
+}
+
+struct ImportRecord {
+  uuid: Option<Uuid>,
+  name: Option<String>,
+}
+
+fn parse_uuid(record: usize, value: &str) -> Result<Uuid, ImportError> {
+  Uuid::from_base64url(value)
+    .or_else(|_| Uuid::parse_str(value))
+    .map_err(|_| ImportError::InvalidUuid {
+      record,
+      value: value.to_string(),
+    })
+}
+
+impl TotalState {
+  // Import entities from CSV with a header row. Columns other than the name and UUID columns are
+  // ignored for now.
+  pub fn import_csv(
+    &mut self,
+    reader: impl Read,
+    options: &ImportOptions,
+  ) -> Result<ImportSummary, ImportError> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let headers = reader
+      .headers()
+      .map_err(|e| ImportError::Read(e.to_string()))?
+      .clone();
+    let column = |name: &str| headers.iter().position(|header| header == name);
+    let name_column = column(&options.name_column);
+    let uuid_column = column(&options.uuid_column);
+    let mut records = Vec::new();
+    for (i, row) in reader.records().enumerate() {
+      let row = row.map_err(|e| ImportError::Parse {
+        record: i + 1,
+        message: e.to_string(),
+      })?;
+      let field = |column: Option<usize>| {
+        column
+          .and_then(|column| row.get(column))
+          .filter(|value| !value.is_empty())
+      };
+      records.push(ImportRecord {
+        uuid: field(uuid_column)
+          .map(|value| parse_uuid(i + 1, value))
+          .transpose()?,
+        name: field(name_column).map(|value| value.to_string()),
+      });
+    }
+    self.import_records(records, options)
+  }
+
+  // Import entities from JSON lines: one JSON object per line.
+  pub fn import_json_lines(
+    &mut self,
+    reader: impl BufRead,
+    options: &ImportOptions,
+  ) -> Result<ImportSummary, ImportError> {
+    let mut records = Vec::new();
+    for line in reader.lines() {
+      let line = line.map_err(|e| ImportError::Read(e.to_string()))?;
+      if line.trim().is_empty() {
+        continue;
+      }
+      let record = records.len() + 1;
+      let parse_error = |message: String| ImportError::Parse { record, message };
+      let object = match serde_json::from_str(&line).map_err(|e| parse_error(e.to_string()))? {
+        serde_json::Value::Object(object) => object,
+        _ => return Err(parse_error("not a JSON object".to_string())),
+      };
+      let field = |column: &str| match object.get(column) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(value)) if value.is_empty() => Ok(None),
+        Some(serde_json::Value::String(value)) => Ok(Some(value.clone())),
+        Some(serde_json::Value::Number(value)) => Ok(Some(value.to_string())),
+        Some(serde_json::Value::Bool(value)) => Ok(Some(value.to_string())),
+        Some(_) => Err(parse_error(format!("{:?} is not a scalar", column))),
+      };
+      records.push(ImportRecord {
+        uuid: field(&options.uuid_column)?
+          .map(|value| parse_uuid(record, &value))
+          .transpose()?,
+        name: field(&options.name_column)?,
+      });
+    }
+    self.import_records(records, options)
+  }
+
+  // Add the imported entities to the working patch: new ones (with a fresh UUID unless one is
+  // given) are added to the sort, and existing ones are reused. Each entity with a name is named
+  // `[sort, name]`. Nothing is changed if any record can't be imported.
+  fn import_records(
+    &mut self,
+    records: Vec<ImportRecord>,
+    options: &ImportOptions,
+  ) -> Result<ImportSummary, ImportError> {
+    let version = self.working_version();
+    let sort_path = vec![options.sort.clone()];
+    let (sort, new_sort) = match version.ctx.get(sort_path.iter()) {
+      Some(luid) if version.s0.contains(luid as u64) => {
+        (*self.universe.get_index(luid).unwrap(), false)
+      }
+      Some(_) => return Err(ImportError::NotASort(options.sort.clone())),
+      None => (Uuid::now_v7(), true),
+    };
+
+    let mut additions: Vec<Uuid> = Vec::new();
+    let mut reused = 0;
+    // (existing entities that don't belong to a sort yet)
+    let mut unsorted: Vec<Uuid> = Vec::new();
+    let mut seen: HashSet<Uuid> = HashSet::new();
+    let mut names: HashMap<Vec<String>, Uuid> = HashMap::new();
+    for record in records {
+      let uuid = match record.uuid {
+        Some(uuid) => {
+          let present = self
+            .universe
+            .get_index_of(&uuid)
+            .filter(|luid| self.working_state.contains(luid));
+          if !seen.insert(uuid) {
+            // (listed more than once)
+          } else if let Some(luid) = present {
+            let in_sort = match version.sort_of(luid) {
+              Some(other_sort) => {
+                !new_sort && *self.universe.get_index(other_sort).unwrap() == sort
+              }
+              None => !version.s0.contains(luid as u64),
+            };
+            if !in_sort {
+              return Err(ImportError::NotInSort {
+                uuid,
+                sort: options.sort.clone(),
+              });
+            }
+            if version.sort_of(luid).is_none() {
+              unsorted.push(uuid);
+            }
+            reused += 1;
+          } else if self.is_removed_from_working_state(&uuid) {
+            return Err(ImportError::Tombstoned(uuid));
+          } else {
+            additions.push(uuid);
+          }
+          uuid
+        }
+        None => {
+          let uuid = Uuid::now_v7();
+          additions.push(uuid);
+          uuid
+        }
+      };
+      if let Some(name) = record.name {
+        let path = vec![options.sort.clone(), name];
+        let existing = names.get(&path).copied().or_else(|| {
+          version
+            .ctx
+            .get(path.iter())
+            .map(|luid| *self.universe.get_index(luid).unwrap())
+        });
+        match existing {
+          Some(existing) if existing != uuid => {
+            return Err(ImportError::NameTaken {
+              name: path,
+              uuid: existing,
+            })
+          }
+          Some(_) => {}
+          None => {
+            names.insert(path, uuid);
+          }
+        }
+      }
+    }
+
+    let working_patch = &mut self.working_patch;
+    if new_sort {
+      self.working_state.insert(self.universe.insert_full(sort).0);
+      working_patch.universe_patch.additions.insert(sort);
+      working_patch
+        .addition_kinds
+        .insert(sort, AdditionKind::NewSort);
+      working_patch
+        .context_patch
+        .additions
+        .insert(sort_path, sort);
+    }
+    for uuid in unsorted {
+      working_patch
+        .addition_kinds
+        .insert(uuid, AdditionKind::NewEntity(sort));
+    }
+    for uuid in additions.iter() {
+      self
+        .working_state
+        .insert(self.universe.insert_full(*uuid).0);
+      working_patch.universe_patch.additions.insert(*uuid);
+      working_patch
+        .addition_kinds
+        .insert(*uuid, AdditionKind::NewEntity(sort));
+    }
+    let named = names.len();
+    working_patch.context_patch.additions.extend(names);
+    Ok(ImportSummary {
+      sort,
+      new_sort,
+      created: additions.len(),
+      reused,
+      named,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn imports_add_or_reuse_entities_and_name_them() {
+    let mut state = TotalState::default();
+    let people = "name,age\nada,36\nalan,41\n,60\n";
+    let summary = state
+      .import_csv(people.as_bytes(), &ImportOptions::new("Person"))
+      .unwrap();
+    assert!(summary.new_sort);
+    assert_eq!((summary.created, summary.reused, summary.named), (3, 0, 2));
+    assert_eq!(state.lookup("Person"), Some(summary.sort));
+    state.commit_in_memory();
+    let ada = state.lookup("Person/ada").unwrap();
+
+    // JSON lines can refer to existing entities by UUID, and keep the UUIDs of new ones
+    let grace = Uuid::now_v7();
+    let lines = format!(
+      "{{\"uuid\": \"{}\"}}\n\n{{\"uuid\": \"{}\", \"name\": \"grace\"}}\n",
+      ada.as_base64url(),
+      grace
+    );
+    let summary = state
+      .import_json_lines(lines.as_bytes(), &ImportOptions::new("Person"))
+      .unwrap();
+    assert!(!summary.new_sort);
+    assert_eq!((summary.created, summary.reused, summary.named), (1, 1, 1));
+    assert_eq!(state.lookup("Person/grace"), Some(grace));
+    state.commit_in_memory();
+    assert_eq!(state.list().count(), 5);
+  }
+
+  #[test]
+  fn a_failed_import_changes_nothing() {
+    let mut state = TotalState::default();
+    let options = ImportOptions::new("Person");
+    state
+      .import_csv("name\nada\n".as_bytes(), &options)
+      .unwrap();
+    let thing = state.add();
+    state.name(&thing, &["thing".to_string()]);
+    state.commit_in_memory();
+    let ada = state.lookup("Person/ada").unwrap();
+    let universe_len = state.universe.len();
+    let check = |state: &TotalState| {
+      assert!(state.working_patch.is_empty());
+      assert_eq!(state.universe.len(), universe_len);
+      assert_eq!(state.list().count(), 3);
+    };
+
+    // (a name can't be given to a second entity)
+    match state.import_csv("name\nalan\nada\n".as_bytes(), &options) {
+      Err(ImportError::NameTaken { name, uuid }) => {
+        assert_eq!(name, ["Person", "ada"]);
+        assert_eq!(uuid, ada);
+      }
+      other => panic!("expected a taken name, got {:?}", other),
+    }
+    check(&state);
+    assert!(matches!(
+      state.import_csv("uuid\nnot-a-uuid\n".as_bytes(), &options),
+      Err(ImportError::InvalidUuid { record: 1, .. })
+    ));
+    assert!(matches!(
+      state.import_json_lines("{}\n[]\n".as_bytes(), &options),
+      Err(ImportError::Parse { record: 2, .. })
+    ));
+    // (the sort itself is not an entity of the sort)
+    let sort = state.lookup("Person").unwrap();
+    let uuids = format!("uuid\n{}\n", sort.as_base64url());
+    assert!(matches!(
+      state.import_csv(uuids.as_bytes(), &options),
+      Err(ImportError::NotInSort { .. })
+    ));
+    assert!(matches!(
+      state.import_csv("name\nx\n".as_bytes(), &ImportOptions::new("thing")),
+      Err(ImportError::NotASort(_))
+    ));
+    check(&state);
+    // deleted entities can't be imported again
+    state.delete(&ada).unwrap();
+    state.commit_in_memory();
+    let uuids = format!("uuid\n{}\n", ada.as_base64url());
+    assert!(matches!(
+      state.import_csv(uuids.as_bytes(), &options),
+      Err(ImportError::Tombstoned(uuid)) if uuid == ada
+    ));
+  }
+}
@@ -0,0 +1,330 @@
+use crate::id::*;
+use crate::state::schema::type_name;
+use crate::state::*;
+
+#[derive(Clone, Debug)]
+pub enum Violation {
+  // a hom that isn't partial or free has no value at an element of its domain (other than a term)
+  Unassigned {
+    hom: Uuid,
+    element: Uuid,
+  },
+  // the value of a hom at an element is not (or no longer) an element of its codomain
+  Dangling {
+    hom: Uuid,
+    element: Uuid,
+    value: Uuid,
+  },
+  // the working patch assigns a value to an element outside the domain of a hom or attribute, or
+  // of something that isn't one
+  NotApplicable {
+    function: Uuid,
+    element: Uuid,
+  },
+  // the working patch assigns an attribute a value of another type
+  WrongType {
+    attr: Uuid,
+    element: Uuid,
+    expected: Uuid,
+  },
+  // the working patch assigns an attribute a value that isn't valid for its (custom) type
+  InvalidValue {
+    attr: Uuid,
+    element: Uuid,
+    message: String,
+  },
+}
+
+#[derive(Clone, Debug)]
+pub enum CommitError {
+  Violations(Vec<Violation>),
+}
+
+impl std::fmt::Display for Violation {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Violation::Unassigned { hom, element } => write!(
+        f,
+        "{} has no value at {}",
+        hom.as_base64url(),
+        element.as_base64url()
+      ),
+      Violation::Dangling {
+        hom,
+        element,
+        value,
+      } => write!(
+        f,
+        "{} at {} is {}, which is not in its codomain",
+        hom.as_base64url(),
+        element.as_base64url(),
+        value.as_base64url()
+      ),
+      Violation::NotApplicable { function, element } => write!(
+        f,
+        "{} can't be assigned a value at {}",
+        function.as_base64url(),
+        element.as_base64url()
+      ),
+      Violation::WrongType {
+        attr,
+        element,
+        expected,
+      } => write!(
+        f,
+        "{} at {} must be of type {}",
+        attr.as_base64url(),
+        element.as_base64url(),
+        type_name(expected)
+      ),
+      Violation::InvalidValue {
+        attr,
+        element,
+        message,
+      } => write!(
+        f,
+        "{} at {}: {}",
+        attr.as_base64url(),
+        element.as_base64url(),
+        message
+      ),
+    }
+  }
+}
+
+impl std::fmt::Display for CommitError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      CommitError::Violations(violations) => write!(
+        f,
+        "Referential integrity violations: {}",
+        violations
+          .iter()
+          .map(|violation| violation.to_string())
+          .collect::<Vec<_>>()
+          .join(", ")
+      ),
+    }
+  }
+}
+
+impl TotalState {
+  // Check the version that committing the working patch would yield: every hom that isn't partial
+  // or free has a value at each element of its domain (terms are only what they are the values of,
+  // and need none), every hom value is an element of the codomain, and
+  // every value the working patch assigns actually applies, with the type of its attribute.
+  // (Processing a patch drops the assignments that don't apply, with a warning, so they are
+  // checked against the patch itself.)
+  pub fn check_integrity(&mut self) -> Vec<Violation> {
+    let version = self.working_version();
+    let uuid = |luid: Luid| *self.universe.get_index(luid).unwrap();
+    let luid = |uuid: &Uuid| self.universe.get_index_of(uuid).unwrap();
+    let in_sort = |sort: Luid, element: Luid| {
+      version
+        .sort_elements(sort)
+        .map(|elements| elements.contains(element as u64))
+        .unwrap_or(false)
+    };
+    let hom_patch = &self.working_patch.hom_patch;
+    let mut violations = Vec::new();
+    for (hom_luid, hom) in version.homs.iter() {
+      let elements = version.sort_elements(hom.domain).unwrap();
+      for (element, value) in elements.iter().zip(hom.column.iter()) {
+        let element = element as Luid;
+        match value {
+          // (unless it is because the working patch assigns a value that doesn't apply)
+          None
+            if !hom.partial
+              && !hom.free
+              && !version.terms.contains_key(&element)
+              && !hom_patch
+                .additions
+                .contains_key(&(uuid(*hom_luid), uuid(element))) =>
+          {
+            violations.push(Violation::Unassigned {
+              hom: uuid(*hom_luid),
+              element: uuid(element),
+            })
+          }
+          Some(value) if !in_sort(hom.codomain, *value) => violations.push(Violation::Dangling {
+            hom: uuid(*hom_luid),
+            element: uuid(element),
+            value: uuid(*value),
+          }),
+          _ => {}
+        }
+      }
+    }
+
+    for ((hom, element), value) in hom_patch.additions.iter() {
+      match version.homs.get(&luid(hom)) {
+        Some(hom_ref) if in_sort(hom_ref.domain, luid(element)) => {
+          // (a value outside the codomain is left unassigned by processing)
+          if !in_sort(hom_ref.codomain, luid(value)) {
+            violations.push(Violation::Dangling {
+              hom: *hom,
+              element: *element,
+              value: *value,
+            });
+          }
+        }
+        _ => violations.push(Violation::NotApplicable {
+          function: *hom,
+          element: *element,
+        }),
+      }
+    }
+    let attr_patch = &self.working_patch.attr_patch;
+    for ((attr, element), value) in attr_patch.additions.iter() {
+      match version.attrs.get(&luid(attr)) {
+        Some(attr_ref) if in_sort(attr_ref.domain, luid(element)) => {
+          if value.type_uuid() != attr_ref.attr_type {
+            violations.push(Violation::WrongType {
+              attr: *attr,
+              element: *element,
+              expected: attr_ref.attr_type,
+            });
+          } else if let Err(e) = self.types.validate(value) {
+            violations.push(Violation::InvalidValue {
+              attr: *attr,
+              element: *element,
+              message: e.to_string(),
+            });
+          }
+        }
+        _ => violations.push(Violation::NotApplicable {
+          function: *attr,
+          element: *element,
+        }),
+      }
+    }
+    violations
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::attribute::*;
+  use crate::state::import::ImportOptions;
+
+  // A sort P with an entity a, a sort C with an entity x, and a hom `f : P -> C` and attribute
+  // `age : P -> I64`, committed with `f(a) = x`.
+  fn schema(partial: bool) -> (TotalState, [Uuid; 6]) {
+    let mut state = TotalState::default();
+    state
+      .import_csv("name\na\n".as_bytes(), &ImportOptions::new("P"))
+      .unwrap();
+    state
+      .import_csv("name\nx\n".as_bytes(), &ImportOptions::new("C"))
+      .unwrap();
+    let [p, c, a, x] = ["P", "C", "P/a", "C/x"].map(|name| state.lookup(name).unwrap());
+    let f = state.add_hom(&p, &c, partial, false).unwrap();
+    let age = state.add_attr(&p, &PrimitiveType::I64.uuid()).unwrap();
+    state.set_hom_value(&f, &a, &x).unwrap();
+    state.commit_in_memory();
+    (state, [p, c, a, x, f, age])
+  }
+
+  // What committing must leave as it was when it is refused.
+  fn snapshot(state: &TotalState) -> String {
+    let patch = &state.working_patch;
+    format!(
+      "{:?} {:?} {:?} {:?} {:?} {:?} {:?} {}",
+      patch.target_commit,
+      patch.source_commits,
+      patch.universe_patch.additions,
+      patch.addition_kinds,
+      patch.hom_patch.additions,
+      patch.attr_patch.additions,
+      state.working_state,
+      state.commits.len()
+    )
+  }
+
+  fn refused(state: &mut TotalState) -> Vec<Violation> {
+    let before = snapshot(state);
+    let violations = match state.commit() {
+      Err(CommitError::Violations(violations)) => violations,
+      Ok((patch, _)) => panic!("expected violations, committed {:?}", patch),
+    };
+    assert_eq!(snapshot(state), before);
+    violations
+  }
+
+  #[test]
+  fn a_total_hom_without_a_value_is_unassigned() {
+    let (mut state, [_, _, a, _, f, _]) = schema(false);
+    state.unset_value(&f, &a).unwrap();
+    assert!(matches!(
+      refused(&mut state)[..],
+      [Violation::Unassigned { hom, element }] if hom == f && element == a
+    ));
+    // (a partial hom needs no value)
+    let (mut state, [_, _, a, _, f, _]) = schema(true);
+    state.unset_value(&f, &a).unwrap();
+    assert!(state.check_integrity().is_empty());
+  }
+
+  #[test]
+  fn a_value_outside_the_codomain_dangles() {
+    let (mut state, [_, _, a, _, f, _]) = schema(false);
+    // (which `set_hom_value` won't assert, but a patch can)
+    let y = state.add();
+    state.working_patch.hom_patch.additions.insert((f, a), y);
+    assert!(matches!(
+      refused(&mut state)[..],
+      [Violation::Dangling { hom, element, value }] if hom == f && element == a && value == y
+    ));
+  }
+
+  #[test]
+  fn a_value_outside_the_domain_does_not_apply() {
+    let (mut state, [_, _, _, x, f, age]) = schema(false);
+    state.working_patch.hom_patch.additions.insert((f, x), x);
+    state
+      .working_patch
+      .attr_patch
+      .additions
+      .insert((age, x), AttrValue::I64(1));
+    let violations = refused(&mut state);
+    assert_eq!(violations.len(), 2);
+    for (violation, expected) in violations.iter().zip([f, age]) {
+      assert!(matches!(
+        violation,
+        Violation::NotApplicable { function, element } if *function == expected && *element == x
+      ));
+    }
+  }
+
+  #[test]
+  fn a_value_of_another_type_is_the_wrong_type() {
+    let (mut state, [_, _, a, _, _, age]) = schema(false);
+    state
+      .working_patch
+      .attr_patch
+      .additions
+      .insert((age, a), AttrValue::String("forty".to_string()));
+    assert!(matches!(
+      refused(&mut state)[..],
+      [Violation::WrongType { attr, element, expected }]
+        if attr == age && element == a && expected == PrimitiveType::I64.uuid()
+    ));
+  }
+
+  #[test]
+  fn a_working_patch_with_integrity_commits() {
+    let (mut state, [_, _, a, _, _, age]) = schema(false);
+    state.set_attr_value(&age, &a, AttrValue::I64(40)).unwrap();
+    let patch_dir = std::env::temp_dir().join(format!("i1_{}", Uuid::now_v7().as_base64url()));
+    let (patch, _) = state.commit_in(&patch_dir).unwrap();
+    let written = patch_dir.join(format!("patch_{}", patch.as_base64url()));
+    assert!(written.exists());
+    std::fs::remove_dir_all(&patch_dir).unwrap();
+    assert!(state.working_patch.is_empty());
+    assert_eq!(
+      state.attr_value(&age, &a),
+      Some((AttrValue::I64(40), false))
+    );
+  }
+}
@@ -1,86 +1,415 @@
 use crate::id::*;
 use crate::patch::*;
+use crate::state::commit::read_patch;
+use crate::state::process_patch::{compute_version, process_patch, record_version};
 use crate::state::*;
+use crate::version::*;
 use memmap2::Mmap;
-use rkyv::{check_archived_root, Deserialize};
-use std::collections::BTreeSet;
+use rayon::prelude::*;
+use std::collections::{BTreeSet, HashMap};
 use std::fs::{self, File};
-use tinyvec::TinyVec;
+use std::path::PathBuf;
 
 impl TotalState {
   pub fn load_all_patches(&mut self) {
     let patch_dir = Self::get_patch_dir();
     fs::create_dir_all(&patch_dir).unwrap();
-    let patch_files = std::fs::read_dir(patch_dir)
+    let patch_files: Vec<PathBuf> = std::fs::read_dir(patch_dir)
       .unwrap()
       .map(|entry| entry.unwrap().path())
-      .collect::<BTreeSet<_>>();
+      .collect::<BTreeSet<_>>()
+      .into_iter()
+      .collect();
     let len = patch_files.len();
-    for (i, path) in patch_files.iter().enumerate() {
-      let f = File::open(path).unwrap();
-      let mmap = unsafe { Mmap::map(&f).unwrap() };
-      let patch = check_archived_root::<Patch>(mmap.as_ref()).unwrap();
-      let patch_uuid_str = path
-        .file_name()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .strip_prefix("patch_")
-        .unwrap();
-      eprintln!("Loading patch {}/{}: {:?}", i + 1, len, patch_uuid_str);
-      let patch_uuid = Uuid::from_base64url(patch_uuid_str).unwrap();
-      // (note: this deserialization may perform unnecessary copies, but is memory-safe)
-      self.index_patch(
-        patch_uuid,
-        patch.deserialize(&mut rkyv::Infallible).unwrap(),
+    // validate and deserialize patch files on the thread pool, then index them in order; a file
+    // that isn't a readable patch is skipped
+    let loaded: Vec<Result<(Uuid, Patch), String>> = patch_files
+      .par_iter()
+      .enumerate()
+      .map(|(i, path)| {
+        let patch_uuid = path
+          .file_name()
+          .and_then(|name| name.to_str())
+          .and_then(|name| name.strip_prefix("patch_"))
+          .and_then(|name| Uuid::from_base64url(name).ok())
+          .ok_or_else(|| format!("skipping {:?}, which is not named as a patch", path))?;
+        eprintln!(
+          "Loading patch {}/{}: {:?}",
+          i + 1,
+          len,
+          patch_uuid.as_base64url()
+        );
+        let f = File::open(path).map_err(|e| format!("skipping {:?}: {}", path, e))?;
+        let mmap = unsafe { Mmap::map(&f) }.map_err(|e| format!("skipping {:?}: {}", path, e))?;
+        let patch = read_patch(mmap.as_ref())
+          .map_err(|e| format!("skipping patch {:?}: {}", patch_uuid.as_base64url(), e))?;
+        Ok((patch_uuid, patch))
+      })
+      .collect();
+    for loaded in loaded {
+      let (patch_uuid, patch) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+          eprintln!("Warning: {}", e);
+          continue;
+        }
+      };
+      if let Err(e) = self.types.check_patch(&patch) {
+        eprintln!(
+          "Warning: skipping patch {:?}: {}",
+          patch_uuid.as_base64url(),
+          e
+        );
+        continue;
+      }
+      self.index_patch(patch_uuid, patch);
+    }
+    self.process_all_patches();
+    if let Some(&head) = self.heads.last() {
+      // (unless that would discard the working patch)
+      let _ = self.checkout_luid(head);
+    }
+  }
+
+  // Compute versions for all indexed patches that have not been processed yet. Patches are
+  // processed in topological rounds; the patches within a round don't depend on one another, so
+  // their versions are computed concurrently.
+  pub fn process_all_patches(&mut self) {
+    // pending patch -> number of its source commits that haven't been processed yet
+    let mut waiting_on: HashMap<Luid, usize> = HashMap::new();
+    // commit -> pending patches that have it as a source
+    let mut dependents: HashMap<Luid, Vec<Luid>> = HashMap::new();
+    // commit -> number of pending patches that will read its version (which is therefore pinned)
+    let mut readers: HashMap<Luid, usize> = HashMap::new();
+    let mut evicted_sources: Vec<Luid> = Vec::new();
+    // commit -> alternate patches computed before its first incoming patch, with their versions
+    let mut alternates: HashMap<Luid, Vec<(Luid, Version)>> = HashMap::new();
+    let mut ready: Vec<Luid> = Vec::new();
+    for (&patch_luid, patch) in self.patches.iter() {
+      let target_commit_luid = self.universe.get_index_of(&patch.target_commit).unwrap();
+      // Patches other than the first one indexed for a commit are processed only to check that
+      // they agree with it; the target version is pinned until they have been.
+      let is_alternate = self.commits.get(&target_commit_luid).unwrap()[0].1 != patch_luid;
+      if self.version_cache.is_processed(&target_commit_luid)
+        && (!is_alternate || self.alternate_patches.contains_key(&patch_luid))
+      {
+        continue;
+      }
+      if is_alternate {
+        *readers.entry(target_commit_luid).or_default() += 1;
+        if self.version_cache.is_processed(&target_commit_luid)
+          && !self.version_cache.contains_key(&target_commit_luid)
+        {
+          evicted_sources.push(target_commit_luid);
+        }
+      }
+      let mut n_waiting = 0;
+      for source_commit in patch.source_commits.iter() {
+        let source_commit_luid = self.universe.get_index_of(source_commit).unwrap();
+        *readers.entry(source_commit_luid).or_default() += 1;
+        if !self.version_cache.is_processed(&source_commit_luid) {
+          n_waiting += 1;
+          dependents
+            .entry(source_commit_luid)
+            .or_default()
+            .push(patch_luid);
+        } else if !self.version_cache.contains_key(&source_commit_luid) {
+          evicted_sources.push(source_commit_luid);
+        }
+      }
+      if n_waiting == 0 {
+        ready.push(patch_luid);
+      } else {
+        waiting_on.insert(patch_luid, n_waiting);
+      }
+    }
+    // recompute the evicted versions that pending patches read, all of them before evicting any
+    let is_read = |luid: &Luid| readers.get(luid).copied().unwrap_or(0) > 0;
+    for source_commit_luid in evicted_sources {
+      if self.version_cache.contains_key(&source_commit_luid) {
+        continue;
+      }
+      let patch_luid = self.commits.get(&source_commit_luid).unwrap()[0].1;
+      process_patch(
+        &mut self.universe,
+        &mut self.version_cache,
+        &mut self.commits,
+        &mut self.heads,
+        &self.patches,
+        patch_luid,
+        is_read,
       );
     }
-    let patch_luids: Vec<(Luid, Luid)> = self
-      .patches
-      .iter()
-      .map(|(k, v)| (*k, self.universe.get_index_of(&v.target_commit).unwrap()))
-      .collect(); // TODO: this is a temporary hack to avoid borrowing self
-    for (patch_luid, target_commit_luid) in patch_luids {
-      if !self.version_cache.contains_key(&target_commit_luid) {
-        process_patch(
-          &mut self.universe,
+    self.version_cache.evict(&self.heads, is_read);
+    while !ready.is_empty() {
+      let computed: Vec<(Luid, usize, Version)> = ready
+        .par_iter()
+        .map(|patch_luid| {
+          let patch = self.patches.get(patch_luid).unwrap();
+          let (depth, version) = compute_version(&self.universe, &self.version_cache, patch);
+          (*patch_luid, depth, version)
+        })
+        .collect();
+      ready.clear();
+      for (patch_luid, depth, version) in computed {
+        let patch = self.patches.get(&patch_luid).unwrap();
+        for source_commit in patch.source_commits.iter() {
+          let source_commit_luid = self.universe.get_index_of(source_commit).unwrap();
+          *readers.get_mut(&source_commit_luid).unwrap() -= 1;
+        }
+        let target_commit_luid = self.universe.get_index_of(&patch.target_commit).unwrap();
+        let incoming = self.commits.get(&target_commit_luid).unwrap();
+        if incoming[0].1 != patch_luid {
+          *readers.get_mut(&target_commit_luid).unwrap() -= 1;
+          // an alternate is compared with the version of the first incoming patch, once that is
+          // recorded
+          if self.version_cache.is_processed(&target_commit_luid) {
+            self.check_alternate(target_commit_luid, patch_luid, &version);
+          } else {
+            alternates
+              .entry(target_commit_luid)
+              .or_default()
+              .push((patch_luid, version));
+          }
+          continue;
+        }
+        record_version(
+          &self.universe,
           &mut self.version_cache,
-          &mut self.commits,
           &mut self.heads,
-          &self.patches,
-          patch_luid,
+          patch,
+          depth,
+          version,
         );
+        for (alternate_luid, version) in alternates.remove(&target_commit_luid).unwrap_or_default()
+        {
+          self.check_alternate(target_commit_luid, alternate_luid, &version);
+        }
+        for dependent in dependents.remove(&target_commit_luid).unwrap_or_default() {
+          let n_waiting = waiting_on.get_mut(&dependent).unwrap();
+          *n_waiting -= 1;
+          if *n_waiting == 0 {
+            waiting_on.remove(&dependent);
+            ready.push(dependent);
+          }
+        }
       }
+      self.version_cache.evict(&self.heads, |luid| {
+        readers.get(luid).copied().unwrap_or(0) > 0
+      });
     }
-    if let Some(&head) = self.heads.last() {
-      self.checkout_luid(head);
+    for patch_luid in waiting_on.keys() {
+      eprintln!(
+        "Warning: patch {:?} depends on a commit that could not be computed; skipping it.",
+        self.universe.get_index(*patch_luid).unwrap().as_base64url()
+      );
+    }
+    // (alternates that were computed, but whose commit's first incoming patch never was)
+    for (commit, computed) in alternates.iter() {
+      for (alternate_luid, _) in computed {
+        eprintln!(
+          "Warning: patch {:?} leads to commit {:?}, which could not be computed; it can't be \
+           checked against the commit's first patch.",
+          self
+            .universe
+            .get_index(*alternate_luid)
+            .unwrap()
+            .as_base64url(),
+          self.universe.get_index(*commit).unwrap().as_base64url()
+        );
+      }
     }
   }
 
-  pub(in crate::state) fn index_patch(&mut self, patch_uuid: Uuid, patch: Patch) -> Luid {
+  // Record whether an alternate incoming patch of a commit yields the same version as its first.
+  fn check_alternate(&mut self, commit: Luid, alternate_luid: Luid, version: &Version) {
+    let agrees = self.version_cache.get(&commit).unwrap() == version;
+    self.alternate_patches.insert(alternate_luid, agrees);
+    if !agrees {
+      eprintln!(
+        "Warning: patches to commit {:?} diverge: {:?} yields a different version.",
+        self.universe.get_index(commit).unwrap().as_base64url(),
+        self
+          .universe
+          .get_index(alternate_luid)
+          .unwrap()
+          .as_base64url()
+      );
+    }
+  }
+
+  pub fn index_patch(&mut self, patch_uuid: Uuid, patch: Patch) -> Luid {
     // add patch to universe
     let patch_luid = self.universe.insert_full(patch_uuid).0;
     // add patch to patches
     self.patches.insert(patch_luid, patch);
     let patch_ref = self.patches.get(&patch_luid).unwrap();
     // add patch contents to universe
-    patch_ref.universe_patch.additions.iter().for_each(|uuid| {
-      self.universe.insert(*uuid);
-    });
+    self.universe.extend(patch_ref.referenced_uuids());
+    // record the elements the patch deletes or merges away
+    let mut removed: Vec<(Luid, Option<Luid>)> = Vec::new();
+    for uuid in patch_ref.universe_patch.deletions.iter() {
+      removed.push((self.universe.insert_full(*uuid).0, None));
+    }
+    for (uuid, merged_into) in patch_ref.universe_patch.merges.iter() {
+      if uuid != merged_into {
+        let merged_into_luid = self.universe.insert_full(*merged_into).0;
+        removed.push((self.universe.insert_full(*uuid).0, Some(merged_into_luid)));
+      }
+    }
+    for (element, merged_into) in removed {
+      let tombstones = self.tombstones.entry(element).or_default();
+      if !tombstones.iter().any(|(luid, _)| *luid == patch_luid) {
+        tombstones.push((patch_luid, merged_into));
+      }
+    }
     // add target commit to universe
     let target_commit_luid = self.universe.insert_full(patch_ref.target_commit).0;
-    // add patch to commits
-    self.commits.insert(
-      target_commit_luid,
-      TinyVec::from([(
-        patch_ref
-          .source_commits
-          .iter()
-          .map(|uuid| self.universe.insert_full(*uuid).0)
-          .collect(),
-        patch_luid,
-      )]),
-    );
+    // add patch to the incoming patches of its target commit
+    let source_commit_luids = patch_ref
+      .source_commits
+      .iter()
+      .map(|uuid| self.universe.insert_full(*uuid).0)
+      .collect();
+    let incoming = self.commits.entry(target_commit_luid).or_default();
+    if !incoming.iter().any(|(_, luid)| *luid == patch_luid) {
+      incoming.push((source_commit_luids, patch_luid));
+      self.update_generations(target_commit_luid);
+    }
     patch_luid
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::state::version_cache::VersionCache;
+
+  #[test]
+  fn loading_in_rounds_yields_the_versions_of_loading_one_at_a_time() {
+    // a history that branches off from earlier commits and merges two of its branches
+    let mut state = TotalState::default();
+    let mut commits = Vec::new();
+    for i in 0..16 {
+      if i > 0 {
+        state.checkout(&commits[i / 2]).unwrap();
+      }
+      let element = state.add();
+      state.name(&element, &[format!("e{}", i)]);
+      commits.push(state.commit_in_memory());
+    }
+    state.checkout(&commits[15]).unwrap();
+    state.merge(&[commits[14]], false).unwrap();
+    commits.push(state.commit_in_memory());
+    let patches: Vec<(Uuid, Patch)> = state
+      .patches
+      .iter()
+      .map(|(luid, patch)| (*state.universe.get_index(*luid).unwrap(), patch.clone()))
+      .collect();
+
+    let mut one_at_a_time = TotalState::default();
+    for (patch_uuid, patch) in patches.iter() {
+      let patch_luid = one_at_a_time.index_patch(*patch_uuid, patch.clone());
+      let commit_luid = one_at_a_time
+        .universe
+        .get_index_of(&patch.target_commit)
+        .unwrap();
+      assert_eq!(one_at_a_time.commits[&commit_luid][0].1, patch_luid);
+      one_at_a_time.version(commit_luid).unwrap();
+    }
+    // (in two batches, with a cache that holds only heads and roots, so that the second batch
+    // reads versions that the first evicted)
+    let mut in_rounds = TotalState {
+      version_cache: VersionCache::with_budget(0),
+      ..Default::default()
+    };
+    in_rounds.version_cache.checkpoint_interval = usize::MAX;
+    let (first, second) = patches.split_at(patches.len() / 2);
+    for batch in [first, second] {
+      for (patch_uuid, patch) in batch.iter() {
+        in_rounds.index_patch(*patch_uuid, patch.clone());
+      }
+      in_rounds.process_all_patches();
+    }
+
+    assert_eq!(in_rounds.universe, one_at_a_time.universe);
+    for commit in commits.iter() {
+      let luid = in_rounds.universe.get_index_of(commit).unwrap();
+      let expected = one_at_a_time.version(luid).unwrap().clone();
+      assert!(in_rounds.version(luid).unwrap() == &expected);
+    }
+  }
+
+  #[test]
+  fn alternates_are_each_compared_with_the_first_incoming_patch() {
+    let patch = |sources: &[Uuid], target: Uuid, additions: &[Uuid]| {
+      let mut patch = Patch {
+        target_commit: target,
+        source_commits: sources.iter().copied().collect(),
+        ..Default::default()
+      };
+      patch.universe_patch.additions.extend(additions);
+      patch
+    };
+    let [c0, c1, target, a, x, y] = [(); 6].map(|_| Uuid::now_v7());
+    let mut state = TotalState::default();
+    state.index_patch(Uuid::now_v7(), patch(&[], c0, &[]));
+    state.index_patch(Uuid::now_v7(), patch(&[c0], c1, &[a]));
+    // (the first incoming patch of `target` waits on `c1`, while the alternates are computed in the
+    // first round)
+    let first = state.index_patch(Uuid::now_v7(), patch(&[c1], target, &[x]));
+    let agreeing = state.index_patch(Uuid::now_v7(), patch(&[c0], target, &[a, x]));
+    let diverging = state.index_patch(Uuid::now_v7(), patch(&[c0], target, &[a, y]));
+    state.process_all_patches();
+    assert_eq!(state.alternate_patches.get(&first), None);
+    assert_eq!(state.alternate_patches.get(&agreeing), Some(&true));
+    assert_eq!(state.alternate_patches.get(&diverging), Some(&false));
+    let target_luid = state.universe.get_index_of(&target).unwrap();
+    let x_luid = state.universe.get_index_of(&x).unwrap() as u64;
+    assert!(state
+      .version(target_luid)
+      .unwrap()
+      .version_universe
+      .contains(x_luid));
+
+    // an alternate of a commit whose first patch depends on a commit that never computes is left
+    // unchecked (with a warning)
+    let [missing, stranded] = [(); 2].map(|_| Uuid::now_v7());
+    let waiting = state.index_patch(Uuid::now_v7(), patch(&[missing], stranded, &[x]));
+    let unchecked = state.index_patch(Uuid::now_v7(), patch(&[c0], stranded, &[x]));
+    state.process_all_patches();
+    assert_eq!(state.alternate_patches.get(&unchecked), None);
+    assert_eq!(state.alternate_patches.get(&waiting), None);
+    let stranded_luid = state.universe.get_index_of(&stranded).unwrap();
+    assert!(!state.version_cache.is_processed(&stranded_luid));
+  }
+
+  #[test]
+  fn retracting_values_of_unknown_elements_is_ignored() {
+    let [root, target, hom, element, attr] = [(); 5].map(|_| Uuid::now_v7());
+    let mut state = TotalState::default();
+    state.index_patch(
+      Uuid::now_v7(),
+      Patch {
+        target_commit: root,
+        ..Default::default()
+      },
+    );
+    // (a patch that retracts values of a hom and attribute that no other patch mentions)
+    let mut patch = Patch {
+      target_commit: target,
+      source_commits: [root].into_iter().collect(),
+      ..Default::default()
+    };
+    patch.hom_patch.deletions.insert((hom, element));
+    patch.attr_patch.deletions.insert((attr, element));
+    state.index_patch(Uuid::now_v7(), patch);
+    state.process_all_patches();
+    let target_luid = state.universe.get_index_of(&target).unwrap();
+    assert!(state
+      .version(target_luid)
+      .unwrap()
+      .version_universe
+      .is_empty());
+  }
+}
@@ -1,6 +1,6 @@
 use crate::id::*;
 use crate::TotalState;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::BTreeSet;
 
 #[derive(Clone, Debug)]
 pub enum MergeError {
@@ -8,6 +8,17 @@ pub enum MergeError {
   CommitNotFound,
   DetachedHead,
   NoCommonAncestor,
+  NotFastForward,
+}
+
+#[derive(Clone, Debug)]
+pub enum MergeOutcome {
+  UpToDate,
+  FastForward(Uuid),
+  Merged {
+    sources: Vec<Uuid>,
+    deletions: usize,
+  },
 }
 
 impl std::fmt::Display for MergeError {
@@ -17,96 +28,306 @@ impl std::fmt::Display for MergeError {
       MergeError::CommitNotFound => write!(f, "Commit not found"),
       MergeError::DetachedHead => write!(f, "Detached head"),
       MergeError::NoCommonAncestor => write!(f, "No common ancestor"),
+      MergeError::NotFastForward => write!(f, "Not possible to fast-forward"),
     }
   }
 }
 
 impl TotalState {
-  pub fn lca(&self, commit0: &Uuid, commit1: &Uuid) -> Option<Luid> {
-    // Finding a least common ancestor is a very similar problem to rendering the commit graph, but
-    // (a) we need to start from just these two commits, and only consider the commits that are
-    // reachable from one of them, and (b) we need to stop at the first moment (after introducing
-    // these two commits as two initial tracks) when there is only one track.
-    let mut tracks: BTreeMap<Luid, usize> = BTreeMap::new();
-    let mut n_tracks_total = 0;
-    let mut track: usize;
-    let mut queue: BTreeSet<Uuid> = BTreeSet::new();
-    let mut lca: Option<Luid> = None;
-    // Begin by inserting the two leaf commits to the queue.
-    queue.insert(*commit0);
-    queue.insert(*commit1);
-    let commit0_luid = self.universe.get_index_of(commit0).unwrap();
-    let commit1_luid = self.universe.get_index_of(commit1).unwrap();
-    tracks.insert(commit0_luid, 0);
-    tracks.insert(commit1_luid, 1);
-    while let Some(uuid) = queue.pop_last() {
-      let luid = self.universe.get_index_of(&uuid).unwrap();
-      let reached_by = self.commits.get(&luid).unwrap();
-      if let Some(existing_track) = tracks.remove(&luid) {
-        track = existing_track;
-      } else {
-        track = n_tracks_total;
-        n_tracks_total += 1;
-      }
-      if tracks.is_empty() {
-        lca = Some(luid);
-        break;
-      }
-      // TODO: handle distinct incoming morphisms
-      let parents = reached_by.iter().flat_map(|x| x.0.iter());
-      if let Some(&primary_parent) = parents.clone().next() {
-        tracks.remove(&luid);
-        if tracks.get(&primary_parent).is_none() {
-          tracks.insert(primary_parent, track);
-        }
-        for &parent_luid in parents {
-          let &parent_uuid = self.universe.get_index(parent_luid).unwrap();
-          queue.insert(parent_uuid);
-        }
-      }
+  // All maximal common ancestors of two commits (more than one after a criss-cross merge). This
+  // only follows the structure of the commit graph, so it doesn't depend on the timestamps in the
+  // commits' UUIDs.
+  pub fn merge_bases(&self, commit0: &Uuid, commit1: &Uuid) -> Result<BTreeSet<Luid>, MergeError> {
+    let commit_luid = |uuid| {
+      self
+        .universe
+        .get_index_of(uuid)
+        .filter(|luid| self.commits.contains_key(luid))
+        .ok_or(MergeError::CommitNotFound)
+    };
+    let ancestors0 = self.ancestors(commit_luid(commit0)?);
+    let ancestors1 = self.ancestors(commit_luid(commit1)?);
+    let mut common: BTreeSet<Luid> = ancestors0.intersection(&ancestors1).copied().collect();
+    // The common ancestors are closed under taking parents, so the ones that are not maximal are
+    // exactly those that are a parent of some other common ancestor.
+    let dominated: BTreeSet<Luid> = common.iter().flat_map(|&luid| self.parents(luid)).collect();
+    common.retain(|luid| !dominated.contains(luid));
+    match common.is_empty() {
+      true => Err(MergeError::NoCommonAncestor),
+      false => Ok(common),
     }
-    eprintln!(
-      "LCA: {:?}",
-      lca.and_then(|lca| self.universe.get_index(lca).map(|x| x.as_base64url()))
-    );
-    lca
   }
 
-  pub fn merge(&mut self, commit: &Uuid) -> Result<(), MergeError> {
-    let commit_luid = self
-      .universe
-      .get_index_of(commit)
-      .ok_or(MergeError::CommitNotFound)?;
-    self.merge_luid(commit_luid)
+  // A single merge base of two commits: the deepest one, if there are several.
+  pub fn lca(&self, commit0: &Uuid, commit1: &Uuid) -> Option<Luid> {
+    self
+      .merge_bases(commit0, commit1)
+      .ok()?
+      .into_iter()
+      .max_by_key(|luid| self.generation(*luid))
+  }
+
+  // Merge one or more commits into the working state. If HEAD is an ancestor of a commit that
+  // descends from all of the others, HEAD simply moves to that commit (a fast-forward) and no patch
+  // is needed. Otherwise the working patch becomes a merge of HEAD and all of the given commits
+  // (an octopus merge when there are several), to be recorded by `commit`.
+  pub fn merge(&mut self, commits: &[Uuid], ff_only: bool) -> Result<MergeOutcome, MergeError> {
+    let commit_luids = commits
+      .iter()
+      .map(|uuid| {
+        self
+          .universe
+          .get_index_of(uuid)
+          .filter(|luid| self.commits.contains_key(luid))
+          .ok_or(MergeError::CommitNotFound)
+      })
+      .collect::<Result<Vec<Luid>, _>>()?;
+    self.merge_luids(commit_luids, ff_only)
   }
 
-  fn merge_luid(&mut self, other_commit_luid: Luid) -> Result<(), MergeError> {
+  fn merge_luids(
+    &mut self,
+    mut other_commit_luids: Vec<Luid>,
+    ff_only: bool,
+  ) -> Result<MergeOutcome, MergeError> {
     if !self.working_patch.is_empty() {
       return Err(MergeError::WorkingPatchNotEmpty);
     }
-    /*
-    let other_version = self.version_cache.get(&commit)
-      .ok_or(()).map_err(|_| {
-        let patch_spec = self.commits.get(&commit)
-          .and_then(|x| x.get(0))
-          .ok_or(MergeError::CommitNotFound)?;
-        let patch_luid = patch_spec.1;
-        process_patch(&mut self.universe, &mut self.version_cache, &mut self.commits, &mut self.heads, &self.patches, patch_luid);
-        self.version_cache.get(&commit)
-          .ok_or(MergeError::CommitNotFound)?
-      })?;
-    */
     let this_commit_uuid = *self
       .working_patch
       .source_commits
-      .get(0)
+      .first()
       .ok_or(MergeError::DetachedHead)?;
-    let _this_commit_luid = self.universe.get_index_of(&this_commit_uuid).unwrap();
-    let other_commit_uuid = *self.universe.get_index(other_commit_luid).unwrap();
-    let _lca = self
-      .lca(&this_commit_uuid, &other_commit_uuid)
-      .ok_or(MergeError::NoCommonAncestor)?;
+    let this_commit_luid = self.universe.get_index_of(&this_commit_uuid).unwrap();
+    // drop commits that are already in the history of HEAD or of another commit being merged
+    let mut sources: Vec<Luid> = vec![this_commit_luid];
+    other_commit_luids.sort_by_key(|luid| std::cmp::Reverse(self.generation(*luid)));
+    for luid in other_commit_luids {
+      if !sources.iter().any(|&source| self.is_ancestor(luid, source)) {
+        sources.push(luid);
+      }
+    }
+    if sources.len() == 1 {
+      return Ok(MergeOutcome::UpToDate);
+    }
+    if let Some(&tip) = sources
+      .iter()
+      .find(|&&tip| sources.iter().all(|&source| self.is_ancestor(source, tip)))
+    {
+      let tip_uuid = *self.universe.get_index(tip).unwrap();
+      self
+        .checkout_luid(tip)
+        .map_err(|_| MergeError::CommitNotFound)?;
+      return Ok(MergeOutcome::FastForward(tip_uuid));
+    }
+    if ff_only {
+      return Err(MergeError::NotFastForward);
+    }
+
+    let mut versions = Vec::with_capacity(sources.len());
+    for &source in sources.iter() {
+      versions.push(
+        self
+          .version(source)
+          .ok_or(MergeError::CommitNotFound)?
+          .clone(),
+      );
+    }
+    // An element missing from one source is kept only if that source never had it, i.e. if it is
+    // absent from every merge base that source shares with the sources that do have it.
+    let mut deletions: BTreeSet<Luid> = BTreeSet::new();
+    for (i, &source) in sources.iter().enumerate() {
+      let source_uuid = *self.universe.get_index(source).unwrap();
+      for (j, &other) in sources.iter().enumerate() {
+        if i == j {
+          continue;
+        }
+        let other_uuid = *self.universe.get_index(other).unwrap();
+        let merge_bases = match self.merge_bases(&source_uuid, &other_uuid) {
+          Ok(merge_bases) => merge_bases,
+          Err(MergeError::NoCommonAncestor) => continue,
+          Err(e) => return Err(e),
+        };
+        let mut base_versions = Vec::with_capacity(merge_bases.len());
+        for base in merge_bases {
+          base_versions.push(
+            self
+              .version(base)
+              .ok_or(MergeError::CommitNotFound)?
+              .clone(),
+          );
+        }
+        for element in versions[j].version_universe.iter() {
+          if !versions[i].version_universe.contains(element)
+            && base_versions
+              .iter()
+              .any(|base| base.version_universe.contains(element))
+          {
+            deletions.insert(element as Luid);
+          }
+        }
+      }
+    }
+
+    let mut merged = versions[0].clone();
+    for version in versions.iter().skip(1) {
+      merged.union_with(version);
+    }
+    self.working_patch.source_commits = sources
+      .iter()
+      .map(|luid| *self.universe.get_index(*luid).unwrap())
+      .collect();
+    for &element in deletions.iter() {
+      merged.version_universe.remove(element as u64);
+      let uuid = *self.universe.get_index(element).unwrap();
+      self.working_patch.universe_patch.deletions.insert(uuid);
+    }
+    self.working_state = merged.version_universe.iter().map(|x| x as usize).collect();
+    Ok(MergeOutcome::Merged {
+      sources: self.working_patch.source_commits.iter().copied().collect(),
+      deletions: deletions.len(),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn working_set(state: &TotalState) -> BTreeSet<Uuid> {
+    state.list().copied().collect()
+  }
+
+  fn luid(state: &TotalState, commit: &Uuid) -> Luid {
+    state.universe.get_index_of(commit).unwrap()
+  }
+
+  // A root commit with one branch per element, each adding that element.
+  fn branches(n: usize) -> (TotalState, Uuid, Vec<(Uuid, Uuid)>) {
+    let mut state = TotalState::default();
+    state.add();
+    let root = state.commit_in_memory();
+    let branches = (0..n)
+      .map(|_| {
+        state.checkout(&root).unwrap();
+        let element = state.add();
+        (element, state.commit_in_memory())
+      })
+      .collect();
+    (state, root, branches)
+  }
 
-    Ok(())
+  #[test]
+  fn a_criss_cross_merge_has_two_merge_bases() {
+    let (mut state, root, branches) = branches(2);
+    let [(_, a), (_, b)] = branches[..] else {
+      unreachable!()
+    };
+    assert_eq!(
+      state.merge_bases(&a, &b).unwrap(),
+      BTreeSet::from([luid(&state, &root)])
+    );
+    state.checkout(&a).unwrap();
+    assert!(matches!(
+      state.merge(&[b], false),
+      Ok(MergeOutcome::Merged { .. })
+    ));
+    let ab = state.commit_in_memory();
+    state.checkout(&b).unwrap();
+    assert!(matches!(
+      state.merge(&[a], false),
+      Ok(MergeOutcome::Merged { .. })
+    ));
+    let ba = state.commit_in_memory();
+    assert_eq!(
+      state.merge_bases(&ab, &ba).unwrap(),
+      BTreeSet::from([luid(&state, &a), luid(&state, &b)])
+    );
+    // (a commit is its own merge base with any of its descendants)
+    assert_eq!(
+      state.merge_bases(&a, &ab).unwrap(),
+      BTreeSet::from([luid(&state, &a)])
+    );
+    // (of which `lca` picks one)
+    assert!([a, b]
+      .iter()
+      .any(|base| state.lca(&ab, &ba) == Some(luid(&state, base))));
+  }
+
+  #[test]
+  fn commits_without_a_shared_history_have_no_merge_base() {
+    let (mut state, root, _) = branches(0);
+    // a second root commit
+    state.working_patch.clear();
+    state.add();
+    let other = state.commit_in_memory();
+    assert!(matches!(
+      state.merge_bases(&root, &other),
+      Err(MergeError::NoCommonAncestor)
+    ));
+    assert_eq!(state.lca(&root, &other), None);
+    assert!(matches!(
+      state.merge_bases(&root, &Uuid::now_v7()),
+      Err(MergeError::CommitNotFound)
+    ));
+  }
+
+  #[test]
+  fn an_octopus_merge_takes_every_branch_and_their_deletions() {
+    let (mut state, root, branches) = branches(3);
+    let in_root = *working_set(&state)
+      .difference(&BTreeSet::from([branches[2].0]))
+      .next()
+      .unwrap();
+    // a fourth branch deletes what was in the root
+    state.checkout(&root).unwrap();
+    state.delete(&in_root).unwrap();
+    let d = state.commit_in_memory();
+    state.checkout(&branches[0].1).unwrap();
+    let others = [branches[1].1, branches[2].1, d];
+    match state.merge(&others, false) {
+      Ok(MergeOutcome::Merged { sources, deletions }) => {
+        assert_eq!(sources.len(), 4);
+        assert_eq!(sources[0], branches[0].1);
+        assert_eq!(deletions, 1);
+      }
+      other => panic!("expected a merge, got {:?}", other),
+    }
+    let m = state.commit_in_memory();
+    let elements: BTreeSet<Uuid> = branches.iter().map(|(element, _)| *element).collect();
+    assert_eq!(working_set(&state), elements);
+    for &source in others.iter().chain([&branches[0].1]) {
+      assert!(state.is_ancestor(luid(&state, &source), luid(&state, &m)));
+    }
+  }
+
+  #[test]
+  fn merging_a_descendant_fast_forwards() {
+    let (mut state, root, branches) = branches(2);
+    let [(x, a), (_, b)] = branches[..] else {
+      unreachable!()
+    };
+    state.checkout(&root).unwrap();
+    assert!(matches!(
+      state.merge(&[root], false),
+      Ok(MergeOutcome::UpToDate)
+    ));
+    // (of two commits to merge, one may be in the history of the other)
+    state.checkout(&a).unwrap();
+    let a2 = state.add();
+    let c = state.commit_in_memory();
+    state.checkout(&root).unwrap();
+    match state.merge(&[a, c], true) {
+      Ok(MergeOutcome::FastForward(tip)) => assert_eq!(tip, c),
+      other => panic!("expected a fast-forward, got {:?}", other),
+    }
+    assert!(state.working_patch.is_empty());
+    assert!(working_set(&state).is_superset(&BTreeSet::from([x, a2])));
+    // diverged branches can't be fast-forwarded
+    assert!(matches!(
+      state.merge(&[b], true),
+      Err(MergeError::NotFastForward)
+    ));
+    assert!(state.working_patch.is_empty());
   }
 }
@@ -1,15 +1,31 @@
 mod total_state;
 pub use total_state::*;
+mod ancestry;
+mod cherry_pick;
+pub use cherry_pick::*;
 mod commit;
-pub use commit::*;
+mod export;
+pub use export::*;
 mod graph;
 pub use graph::*;
+mod import;
+pub use import::*;
+mod integrity;
+pub use integrity::*;
 mod load_patch;
-pub use load_patch::*;
 mod merge;
 pub use merge::*;
 mod process_patch;
 pub use process_patch::process_patch;
+mod query;
+mod revert;
+mod rules;
+mod schema;
+pub use schema::*;
+mod terms;
+mod version_cache;
+pub use version_cache::*;
+mod working_patch;
 
 // TODO:
 // * implement merges
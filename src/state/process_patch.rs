@@ -1,10 +1,16 @@
 use crate::id::*;
 use crate::patch::*;
+use crate::query::*;
 use crate::state::*;
+use crate::union_find::UnionFind;
 use crate::version::*;
-use roaring::RoaringTreemap;
-use std::ops::*;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
+// Compute the version at the target of a patch, first computing (exactly once each) the versions of
+// any of its source commits that are not in the version cache. This walks the commit graph with an
+// explicit work-list rather than recursion, so arbitrarily deep histories can be processed.
+// Versions for which `is_pinned` holds are kept in the cache meanwhile.
 pub fn process_patch(
   universe: &mut Universe,
   version_cache: &mut VersionCache,
@@ -12,76 +18,405 @@ pub fn process_patch(
   heads: &mut Heads,
   patches: &Patches,
   patch_luid: Luid,
+  is_pinned: impl Fn(&Luid) -> bool,
+) {
+  let mut work_list: Vec<Luid> = vec![patch_luid];
+  // commit -> number of patches on the work list that have it as a source, whose versions are
+  // therefore pinned
+  let mut readers: HashMap<Luid, usize> = HashMap::new();
+  read_sources(
+    universe,
+    &mut readers,
+    patches.get(&patch_luid).unwrap(),
+    true,
+  );
+  while let Some(&patch_luid) = work_list.last() {
+    let patch = patches.get(&patch_luid).unwrap();
+    let target_commit_luid = universe.insert_full(patch.target_commit).0;
+    if work_list.len() > 1 && version_cache.contains_key(&target_commit_luid) {
+      // a dependency that was already computed via another path
+      work_list.pop();
+      read_sources(universe, &mut readers, patch, false);
+      continue;
+    }
+    let mut ready = true;
+    for source_commit in patch.source_commits.iter() {
+      let source_commit_luid = universe.get_index_of(source_commit).unwrap();
+      if version_cache.contains_key(&source_commit_luid) {
+        continue;
+      }
+      // (a source that hasn't been processed yet, or has been evicted, is computed from its nearest
+      // cached ancestors)
+      let Some(&(_, source_patch_luid)) = commits
+        .get(&source_commit_luid)
+        .and_then(|incoming| incoming.first())
+      else {
+        // (a commit that no patch leads to has no version, and neither has anything after it)
+        return;
+      };
+      work_list.push(source_patch_luid);
+      read_sources(
+        universe,
+        &mut readers,
+        patches.get(&source_patch_luid).unwrap(),
+        true,
+      );
+      ready = false;
+    }
+    if ready {
+      work_list.pop();
+      read_sources(universe, &mut readers, patch, false);
+      apply_patch(universe, version_cache, heads, patches, patch_luid);
+      if !work_list.is_empty() {
+        // keep intermediate versions within budget while working through a long history, except
+        // for those that the patches still on the work list read
+        version_cache.evict(heads, |luid| readers.contains_key(luid) || is_pinned(luid));
+      }
+    }
+  }
+}
+
+// Count the sources of a patch among the commits that patches read (or stop counting them).
+fn read_sources(
+  universe: &Universe,
+  readers: &mut HashMap<Luid, usize>,
+  patch: &Patch,
+  reading: bool,
+) {
+  for source_commit in patch.source_commits.iter() {
+    let source_commit_luid = universe.get_index_of(source_commit).unwrap();
+    let n_readers = readers.entry(source_commit_luid).or_default();
+    match reading {
+      true => *n_readers += 1,
+      false => *n_readers -= 1,
+    }
+    if *n_readers == 0 {
+      readers.remove(&source_commit_luid);
+    }
+  }
+}
+
+// Compute the version at the target of a patch whose source versions are all in the cache.
+fn apply_patch(
+  universe: &mut Universe,
+  version_cache: &mut VersionCache,
+  heads: &mut Heads,
+  patches: &Patches,
+  patch_luid: Luid,
 ) {
   let patch = patches.get(&patch_luid).unwrap();
-  let mut version_universe: RoaringTreemap = RoaringTreemap::new();
+  let (depth, version) = compute_version(universe, version_cache, patch);
+  record_version(universe, version_cache, heads, patch, depth, version);
+}
+
+// Compute the version at the target of a patch (and its depth in the commit graph), given that its
+// source versions are all in the cache. This only reads shared state, so independent patches can
+// be computed concurrently.
+pub(crate) fn compute_version(
+  universe: &Universe,
+  version_cache: &VersionCache,
+  patch: &Patch,
+) -> (usize, Version) {
+  let mut depth = 0;
+  let mut version: Option<Version> = None;
+  // (merges made in later sources, to be made in the first as well)
+  let mut unions: Vec<(Luid, Luid)> = Vec::new();
   for source_commit in patch.source_commits.iter() {
     let source_commit_luid = universe.get_index_of(source_commit).unwrap();
-    let mut source_version = version_cache.get(&source_commit_luid);
-    if source_version.is_none() {
-      eprintln!(
-        "Info: patch {:?} depends on {:?} which has not been processed yet. Processing it now.",
-        universe.get_index(patch_luid).unwrap().as_base64url(),
-        universe
-          .get_index(source_commit_luid)
-          .unwrap()
-          .as_base64url()
-      );
-      process_patch(
-        universe,
-        version_cache,
-        commits,
-        heads,
-        patches,
-        commits.get(&source_commit_luid).unwrap().get(0).unwrap().1,
-      );
-      source_version = Some(version_cache.get(&source_commit_luid).unwrap());
+    let source_version = version_cache.get(&source_commit_luid).unwrap();
+    depth = depth.max(version_cache.depth(&source_commit_luid).unwrap() + 1);
+    // the first source is cloned (sharing its structure) and later sources are unioned into it
+    match version.as_mut() {
+      None => version = Some(source_version.clone()),
+      Some(version) => {
+        version.union_with(source_version);
+        unions.extend(source_version.merged.merged());
+      }
     }
-    heads.remove(&source_commit_luid);
-    version_universe.bitor_assign(&source_version.unwrap().version_universe);
   }
+  let mut version = version.unwrap_or_default();
+  let luid = |uuid: &Uuid| universe.get_index_of(uuid).unwrap();
+  // What the patch adds to the facts of its source, for deriving facts by the rules incrementally;
+  // `None` once the patch changes anything else (see `query::derive`).
+  let mut delta = (patch.source_commits.len() == 1
+    && patch.universe_patch.deletions.is_empty()
+    && patch
+      .universe_patch
+      .merges
+      .iter()
+      .all(|(uuid, merged_into)| uuid == merged_into)
+    && patch.hom_patch.deletions.is_empty()
+    && patch.attr_patch.deletions.is_empty())
+  .then(Delta::default);
   {
-    // Handle universe patch
+    // Handle universe patch (merges are handled once the additions have their kinds, so that
+    // elements can be merged into new sorts, homs and attributes)
     let universe_patch = &patch.universe_patch;
     universe_patch.deletions.iter().for_each(|uuid| {
-      version_universe.remove(universe.get_index_of(uuid).unwrap() as u64);
+      version.remove_element(luid(uuid));
     });
-    universe_patch
-      .merges
-      .iter()
-      .for_each(|(uuid, merged_into)| {
-        if uuid != merged_into {
-          version_universe.remove(universe.get_index_of(uuid).unwrap() as u64);
-        }
-      });
-    version_universe.extend(
+    version.unname(&universe_patch.deletions.iter().map(luid).collect());
+    version.version_universe.extend(
       universe_patch
         .additions
         .iter()
-        .map(|uuid| universe.get_index_of(uuid).unwrap() as u64),
+        .map(|uuid| luid(uuid) as u64),
     );
   }
   {
-    // TODO: Handle addition kinds
+    // Handle addition kinds: new sorts first, so that new entities, homs and attributes can refer
+    // to them
     let addition_kinds = &patch.addition_kinds;
-    for (_i, kind) in addition_kinds.iter().enumerate() {
+    for (uuid, kind) in addition_kinds.iter() {
+      if let AdditionKind::NewSort = kind {
+        version.add_sort(luid(uuid));
+      }
+    }
+    for (uuid, kind) in addition_kinds.iter() {
       match kind {
         AdditionKind::NewSort => {}
-        AdditionKind::NewEntity(_sort_uuid) => {}
+        AdditionKind::NewEntity(sort_uuid) => {
+          if version.add_to_sort(luid(sort_uuid), luid(uuid)) {
+            if let Some(delta) = delta.as_mut() {
+              delta.members.push((luid(sort_uuid), luid(uuid)));
+            }
+          } else {
+            eprintln!(
+              "Warning: {:?} is added to {:?}, which is not a sort.",
+              uuid.as_base64url(),
+              sort_uuid.as_base64url()
+            );
+          }
+        }
+        AdditionKind::NewHom {
+          domain,
+          codomain,
+          partial,
+          free,
+        } => {
+          if !version.add_hom(luid(uuid), luid(domain), luid(codomain), *partial, *free) {
+            eprintln!(
+              "Warning: hom {:?} goes from {:?} to {:?}, which are not both sorts.",
+              uuid.as_base64url(),
+              domain.as_base64url(),
+              codomain.as_base64url()
+            );
+          }
+        }
+        AdditionKind::NewAttr { domain, attr_type } => {
+          if !version.add_attr(luid(uuid), luid(domain), *attr_type) {
+            eprintln!(
+              "Warning: attribute {:?} is of {:?}, which is not a sort.",
+              uuid.as_base64url(),
+              domain.as_base64url()
+            );
+          }
+        }
+        AdditionKind::NewRule(_) | AdditionKind::NewTerm { .. } => {}
+      }
+    }
+    // rules and terms last, as they refer to sorts, homs and attributes (and terms to entities)
+    for (uuid, kind) in addition_kinds.iter() {
+      match kind {
+        AdditionKind::NewRule(rule) => {
+          delta = None;
+          match ResolvedRule::resolve(rule, &version, |uuid| universe.get_index_of(uuid)) {
+            Some(rule) => {
+              version.rules.insert(luid(uuid), rule);
+            }
+            None => eprintln!(
+              "Warning: rule {:?} mentions something that isn't a sort, hom or attribute of the \
+               version, or has variables in its conclusion that aren't in its premises.",
+              uuid.as_base64url()
+            ),
+          }
+        }
+        AdditionKind::NewTerm { hom, arg } => {
+          delta = None;
+          if !version.add_term(luid(uuid), luid(hom), version.merged.find(luid(arg))) {
+            eprintln!(
+              "Warning: term {:?} applies {:?} to {:?}, which is not in the domain of a hom.",
+              uuid.as_base64url(),
+              hom.as_base64url(),
+              arg.as_base64url()
+            );
+          }
+        }
+        _ => {}
       }
     }
   }
-  { // TODO: Handle context patch
+  {
+    // Handle merges: merging sorts, homs or attributes changes the schema, and the instance is
+    // migrated along it (see `Version::sigma`) before the merged elements are removed; merging
+    // other elements is closed under congruence (see `Version::rebuild`)
+    let merges: BTreeMap<Luid, Luid> = patch
+      .universe_patch
+      .merges
+      .iter()
+      .filter(|(uuid, merged_into)| uuid != merged_into)
+      .map(|(uuid, merged_into)| (luid(uuid), luid(merged_into)))
+      .collect();
+    let is_schema = |luid: &Luid| {
+      version.s0.contains(*luid as u64)
+        || version.homs.contains_key(luid)
+        || version.attrs.contains_key(luid)
+    };
+    let (schema_merges, entity_merges): (Vec<_>, Vec<_>) = merges
+      .iter()
+      .map(|(element, into)| (*element, *into))
+      .partition(|(element, _)| is_schema(element));
+    let mut union_find = UnionFind::default();
+    for (element, merged_into) in merges.iter() {
+      union_find.union(*element, *merged_into);
+    }
+    let rejected = version.sigma(&union_find);
+    for (element, merged_into) in rejected.iter() {
+      eprintln!(
+        "Warning: can't migrate {:?} into {:?}: they don't have the same place in the schema.",
+        universe.get_index(*element).unwrap().as_base64url(),
+        universe.get_index(*merged_into).unwrap().as_base64url()
+      );
+    }
+    for (element, _) in schema_merges.iter() {
+      version.remove_element(*element);
+      if !rejected.iter().any(|(rejected, _)| rejected == element) {
+        Arc::make_mut(&mut version.merged).union(*element, union_find.find(*element));
+      }
+    }
+    unions.extend(entity_merges.iter().copied());
+    let older = |a: Luid, b: Luid| universe.get_index(a) < universe.get_index(b);
+    for (element, merged_into) in version.rebuild(unions, older) {
+      eprintln!(
+        "Warning: can't merge {:?} into {:?}: they are not in the same sort.",
+        universe.get_index(element).unwrap().as_base64url(),
+        universe.get_index(merged_into).unwrap().as_base64url()
+      );
+    }
+    // (merged elements are tombstones, whether or not their merges could be performed)
+    for (element, _) in entity_merges.iter() {
+      version.remove_element(*element);
+    }
   }
-  let target_commit_luid = universe.insert_full(patch.target_commit).0;
-  heads.insert(target_commit_luid);
-  version_cache.insert(
-    target_commit_luid,
-    Version {
-      version_universe: version_universe.into_iter().collect(),
-      s0: Default::default(),
-      s0i: Default::default(),
-      ctx: Default::default(),
-    },
-  );
+  {
+    // Handle context patch: a renaming is a deletion followed by an addition
+    let context_patch = &patch.context_patch;
+    for path in context_patch.deletions.iter() {
+      version.ctx.remove(path);
+    }
+    for (path, uuid) in context_patch.additions.iter() {
+      version.ctx.insert(path, luid(uuid));
+    }
+  }
+  {
+    // Handle hom patch: values must lie in the domain and codomain of their hom (elements that
+    // have been merged stand for their representatives)
+    let hom_patch = &patch.hom_patch;
+    for (hom, element) in hom_patch.deletions.iter() {
+      let element = version.merged.find(luid(element));
+      version.set_hom_value(luid(hom), element, None);
+    }
+    for ((hom_uuid, element_uuid), value_uuid) in hom_patch.additions.iter() {
+      let hom = luid(hom_uuid);
+      let element = version.merged.find(luid(element_uuid));
+      let value = version.merged.find(luid(value_uuid));
+      // (a value that replaces one, asserted or derived, can invalidate what was derived from it)
+      let replaces =
+        version.hom_value(hom, element).is_some() || version.derived.contains(hom, element);
+      let codomain = version.homs.get(&hom).map(|hom| hom.codomain);
+      let in_codomain = codomain
+        .and_then(|codomain| version.sort_elements(codomain))
+        .map(|elements| elements.contains(value as u64))
+        .unwrap_or(false);
+      if in_codomain && version.set_hom_value(hom, element, Some(value)) {
+        match delta.as_mut() {
+          Some(_) if replaces => delta = None,
+          Some(delta) => delta.homs.push((hom, element, value)),
+          None => {}
+        }
+      } else {
+        eprintln!(
+          "Warning: can't assert {:?}({:?}) = {:?}: not a hom, or not in its domain and codomain.",
+          hom_uuid.as_base64url(),
+          element_uuid.as_base64url(),
+          value_uuid.as_base64url()
+        );
+      }
+    }
+  }
+  {
+    // Equate each term with the value that its application has otherwise been given (by this patch
+    // or another source), as a hom has one value at each element
+    let equations = version.equate_terms();
+    if !equations.is_empty() {
+      delta = None;
+      let older = |a: Luid, b: Luid| universe.get_index(a) < universe.get_index(b);
+      for (term, value) in version.rebuild(equations, older) {
+        eprintln!(
+          "Warning: can't equate term {:?} with {:?}: they are not in the same sort.",
+          universe.get_index(term).unwrap().as_base64url(),
+          universe.get_index(value).unwrap().as_base64url()
+        );
+      }
+    }
+  }
+  {
+    // Handle attribute patch: values must be of the type of their attribute
+    let attr_patch = &patch.attr_patch;
+    for (attr, element) in attr_patch.deletions.iter() {
+      let element = version.merged.find(luid(element));
+      version.set_attr_value(luid(attr), element, None);
+    }
+    for ((attr_uuid, element_uuid), value) in attr_patch.additions.iter() {
+      let attr = luid(attr_uuid);
+      let element = version.merged.find(luid(element_uuid));
+      let replaces =
+        version.attr_value(attr, element).is_some() || version.derived.contains(attr, element);
+      let attr_type = version.attrs.get(&attr).map(|attr| attr.attr_type);
+      if attr_type == Some(value.type_uuid())
+        && version.set_attr_value(attr, element, Some(value.clone()))
+      {
+        match delta.as_mut() {
+          Some(_) if replaces => delta = None,
+          Some(delta) => delta.attrs.push((attr, element, value.clone())),
+          None => {}
+        }
+      } else {
+        eprintln!(
+          "Warning: can't assert {:?}({:?}) = {:?}: not an attribute of it, or not of its type.",
+          attr_uuid.as_base64url(),
+          element_uuid.as_base64url(),
+          value
+        );
+      }
+    }
+  }
+  {
+    // Derive the facts that follow by the rules (see `query::derive`)
+    let derived = version.derived.clone();
+    let earlier = delta.map(|delta| (derived.as_ref(), delta));
+    version.derived = Arc::new(derive(&version, earlier));
+  }
+  (depth, version)
+}
+
+// Store a computed version in the cache and update the heads of the commit graph.
+pub(crate) fn record_version(
+  universe: &Universe,
+  version_cache: &mut VersionCache,
+  heads: &mut Heads,
+  patch: &Patch,
+  depth: usize,
+  version: Version,
+) {
+  let target_commit_luid = universe.get_index_of(&patch.target_commit).unwrap();
+  // A commit that was processed before is being recomputed after eviction from the cache; in that
+  // case the commit graph is already accounted for in `heads`.
+  if !version_cache.is_processed(&target_commit_luid) {
+    for source_commit in patch.source_commits.iter() {
+      heads.remove(&universe.get_index_of(source_commit).unwrap());
+    }
+    heads.insert(target_commit_luid);
+  }
+  version_cache.insert(target_commit_luid, depth, version);
 }
@@ -0,0 +1,234 @@
+use crate::id::*;
+use crate::query::*;
+use crate::state::*;
+use crate::version::*;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+
+impl TotalState {
+  // An element of a version by its UUID (in base64url) or else by its name, as for `lookup`.
+  fn resolve_element(&self, version: &Version, name: &str) -> Option<Luid> {
+    match Uuid::from_base64url(name) {
+      Ok(uuid) => self
+        .universe
+        .get_index_of(&uuid)
+        .filter(|luid| version.version_universe.contains(*luid as u64)),
+      Err(_) => version.ctx.get(name.split('/')),
+    }
+  }
+
+  // A hom or attribute by its UUID or name, or else by the last part of its name (such as `age`
+  // for `Person/age`) if that is unambiguous.
+  fn resolve_function(&self, version: &Version, name: &str) -> Result<Luid, QueryError> {
+    let is_function =
+      |luid: &Luid| version.homs.contains_key(luid) || version.attrs.contains_key(luid);
+    if let Some(luid) = self.resolve_element(version, name).filter(is_function) {
+      return Ok(luid);
+    }
+    let candidates: BTreeSet<Luid> = version
+      .ctx
+      .namings()
+      .into_iter()
+      .filter(|(path, luid)| {
+        path.last().map(|last| last == name).unwrap_or(false) && is_function(luid)
+      })
+      .map(|(_, luid)| luid)
+      .collect();
+    match candidates.len() {
+      0 => Err(QueryError::UnknownFunction(name.to_string())),
+      1 => Ok(*candidates.first().unwrap()),
+      _ => Err(QueryError::AmbiguousFunction(name.to_string())),
+    }
+  }
+
+  // Resolve the names in a query against a version, giving the variables of the query and its
+  // atoms.
+  pub fn resolve_query(
+    &self,
+    query: &Query,
+    version: &Version,
+  ) -> Result<(Vec<String>, Vec<ResolvedAtom>), QueryError> {
+    let variables = query.variables();
+    let var = |name: &String| variables.iter().position(|var| var == name).unwrap();
+    let mut atoms = Vec::new();
+    for atom in query.atoms.iter() {
+      let atom = match atom {
+        Atom::Member { var: name, sort } => ResolvedAtom::Member {
+          var: var(name),
+          sort: self
+            .resolve_element(version, sort)
+            .filter(|luid| version.s0.contains(*luid as u64))
+            .ok_or_else(|| QueryError::UnknownSort(sort.clone()))?,
+        },
+        Atom::Equation {
+          function,
+          arg,
+          value,
+        } => {
+          let luid = self.resolve_function(version, function)?;
+          match version.attrs.get(&luid) {
+            None => ResolvedAtom::Hom {
+              hom: luid,
+              arg: var(arg),
+              value: match value {
+                Term::Var(name) => Bound::Var(var(name)),
+                Term::Constant(name) => Bound::Const(
+                  self
+                    .resolve_element(version, name)
+                    .ok_or_else(|| QueryError::UnknownElement(name.clone()))?,
+                ),
+              },
+            },
+            Some(attr) => ResolvedAtom::Attr {
+              attr: luid,
+              arg: var(arg),
+              value: match value {
+                Term::Var(name) => Bound::Var(var(name)),
+                Term::Constant(text) => Bound::Const(
+                  self
+                    .types
+                    .parse(&attr.attr_type, text)
+                    .map_err(|e| QueryError::InvalidValue(e.to_string()))?,
+                ),
+              },
+            },
+          }
+        }
+      };
+      atoms.push(atom);
+    }
+    Ok((variables, atoms))
+  }
+
+  // Answer a conjunctive query (see `query::parse`) over the working state, with the facts that its
+  // rules derive and the terms that its free homs have as values: every binding of its variables
+  // to elements and attribute values that satisfies all of its atoms.
+  pub fn query(&mut self, text: &str) -> Result<QueryResult, QueryError> {
+    self.query_with(text, JoinStrategy::default())
+  }
+
+  // As `query`, evaluating the query with the given strategy. The rows are the same either way,
+  // though not necessarily in the same order.
+  pub fn query_with(
+    &mut self,
+    text: &str,
+    strategy: JoinStrategy,
+  ) -> Result<QueryResult, QueryError> {
+    let query = parse(text)?;
+    let version = self.working_version().with_derived();
+    let version = self.with_terms(&version);
+    let (variables, atoms) = self.resolve_query(&query, &version)?;
+    let plan = plan(variables, atoms, &version);
+    let rows = match strategy {
+      JoinStrategy::GenericJoin => generic_join(&plan, &version),
+      JoinStrategy::NestedLoops => execute(&plan, &version),
+    };
+    let mut terms = BTreeMap::new();
+    let rows = rows
+      .into_iter()
+      .map(|row| {
+        row
+          .into_iter()
+          .map(|value| match value {
+            QueryValue::Element(luid) => {
+              let uuid = *self.universe.get_index(luid).unwrap();
+              if let Entry::Vacant(entry) = terms.entry(uuid) {
+                if let Some(text) = self.term_text(&version, luid) {
+                  entry.insert(text);
+                }
+              }
+              QueryValue::Element(uuid)
+            }
+            QueryValue::Value(value) => QueryValue::Value(value),
+          })
+          .collect()
+      })
+      .collect();
+    Ok(QueryResult {
+      variables: plan.variables,
+      rows,
+      terms,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::state::import::ImportOptions;
+
+  // A random graph: vertices and edges are entities of sorts V and E, with homs `src, tgt : E -> V`
+  // and an attribute `weight` of the edges.
+  fn graph(n_vertices: usize, n_edges: usize) -> TotalState {
+    // (xorshift64, so that the graph is the same from run to run)
+    let mut rng = 0x9e37_79b9_7f4a_7c15_u64;
+    let mut below = |n: usize| {
+      rng ^= rng << 13;
+      rng ^= rng >> 7;
+      rng ^= rng << 17;
+      (rng % n as u64) as usize
+    };
+    let mut state = TotalState::default();
+    let csv = |n: usize| {
+      let rows: Vec<String> = (0..n).map(|i| i.to_string()).collect();
+      format!("name\n{}\n", rows.join("\n"))
+    };
+    let import = |state: &mut TotalState, n: usize, sort: &str| {
+      state
+        .import_csv(csv(n).as_bytes(), &ImportOptions::new(sort))
+        .unwrap();
+    };
+    import(&mut state, n_vertices, "V");
+    import(&mut state, n_edges, "E");
+    let [v, e] = ["V", "E"].map(|name| state.lookup(name).unwrap());
+    let i64_type = state.types.lookup_name("I64").unwrap();
+    let src = state.add_hom(&e, &v, false, false).unwrap();
+    let tgt = state.add_hom(&e, &v, false, false).unwrap();
+    let weight = state.add_attr(&e, &i64_type).unwrap();
+    for (function, name) in [(src, "src"), (tgt, "tgt"), (weight, "weight")] {
+      state.name(&function, &[name.to_string()]);
+    }
+    for i in 0..n_edges {
+      let edge = state.lookup(&format!("E/{}", i)).unwrap();
+      for hom in [src, tgt] {
+        let vertex = state.lookup(&format!("V/{}", below(n_vertices))).unwrap();
+        state.set_hom_value(&hom, &edge, &vertex).unwrap();
+      }
+      let value = state
+        .parse_attr_value(&weight, &below(3).to_string())
+        .unwrap();
+      state.set_attr_value(&weight, &edge, value).unwrap();
+    }
+    state
+  }
+
+  fn sorted_rows(state: &mut TotalState, text: &str, strategy: JoinStrategy) -> Vec<String> {
+    let result = state.query_with(text, strategy).unwrap();
+    let mut rows: Vec<String> = result.rows.iter().map(|row| format!("{:?}", row)).collect();
+    rows.sort();
+    rows
+  }
+
+  #[test]
+  fn generic_join_finds_the_rows_of_nested_loops() {
+    let mut state = graph(30, 200);
+    let queries = [
+      "src(e1) = ?a, tgt(e1) = ?b, src(e2) = ?b, tgt(e2) = ?c, src(e3) = ?c, tgt(e3) = ?a",
+      "?e : E, weight(e) = 2, src(e) = ?a, tgt(e) = ?a",
+      "src(e) = V/0, tgt(e) = ?b, src(f) = ?b, weight(f) = ?w",
+      "?v : V, src(e) = ?v, weight(e) = 7",
+    ];
+    let mut n_rows = 0;
+    for text in queries {
+      let rows = sorted_rows(&mut state, text, JoinStrategy::GenericJoin);
+      assert_eq!(
+        rows,
+        sorted_rows(&mut state, text, JoinStrategy::NestedLoops),
+        "{}",
+        text
+      );
+      n_rows += rows.len();
+    }
+    assert!(n_rows > 0);
+  }
+}
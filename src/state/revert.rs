@@ -0,0 +1,317 @@
+use crate::id::*;
+use crate::patch::*;
+use crate::state::*;
+use crate::version::*;
+use std::collections::{BTreeSet, HashMap};
+
+impl TotalState {
+  fn patch_target(&self, patch: Luid) -> Luid {
+    let target_commit = self.patches.get(&patch).unwrap().target_commit;
+    self.universe.get_index_of(&target_commit).unwrap()
+  }
+
+  // Whether `element` has been deleted or merged away in the history of `commit`. A removed UUID
+  // is a tombstone: it may never be added again.
+  pub fn is_tombstoned(&self, element: Luid, commit: Luid) -> bool {
+    self
+      .tombstones
+      .get(&element)
+      .map(|tombstones| {
+        tombstones
+          .iter()
+          .any(|(patch, _)| self.is_ancestor(self.patch_target(*patch), commit))
+      })
+      .unwrap_or(false)
+  }
+
+  // The element that `element` has (transitively) been merged into in the history of `commit`, or
+  // `element` itself: the union-find representative of its class of merged elements.
+  pub fn representative(&self, mut element: Luid, commit: Luid) -> Luid {
+    let mut visited = BTreeSet::from([element]);
+    while let Some(merged_into) = self.tombstones.get(&element).and_then(|tombstones| {
+      tombstones.iter().find_map(|(patch, merged_into)| {
+        merged_into.filter(|_| self.is_ancestor(self.patch_target(*patch), commit))
+      })
+    }) {
+      if !visited.insert(merged_into) {
+        break;
+      }
+      element = merged_into;
+    }
+    element
+  }
+
+  // A patch that undoes `patch`, going from its target back towards its (first) source. Its
+  // additions become deletions, and its context additions become context deletions; an element it
+  // added that has since been merged (in the history of HEAD) into one it didn't add lives on in
+  // that one, so the patch can't be inverted. The elements it deleted or merged away are
+  // tombstones, so each is restored as a new element (with a UUID that only enters the universe
+  // when the inverse is applied); names it removed are restored too, pointing at the new elements
+  // where necessary. The target commit is assigned on commit.
+  pub fn invert(&mut self, patch: &Uuid) -> Result<Patch, CherryPickError> {
+    let patch_luid = self
+      .universe
+      .get_index_of(patch)
+      .filter(|luid| self.patches.contains_key(luid))
+      .ok_or(CherryPickError::PatchNotFound)?;
+    let patch = self.patches.get(&patch_luid).unwrap().clone();
+    let head = self
+      .working_patch
+      .source_commits
+      .first()
+      .map(|uuid| self.universe.get_index_of(uuid).unwrap());
+
+    let mut inverse = Patch::default();
+    inverse.source_commits.push(patch.target_commit);
+    let mut merged_away = Vec::new();
+    for uuid in patch.universe_patch.additions.iter() {
+      let luid = self.universe.get_index_of(uuid).unwrap();
+      let representative = match head {
+        Some(head) => self.representative(luid, head),
+        None => luid,
+      };
+      let representative_uuid = *self.universe.get_index(representative).unwrap();
+      if representative == luid {
+        inverse.universe_patch.deletions.insert(*uuid);
+      } else if !patch
+        .universe_patch
+        .additions
+        .contains(&representative_uuid)
+      {
+        merged_away.push(Conflict::MergedAway {
+          element: *uuid,
+          into: representative_uuid,
+        });
+      }
+    }
+    if !merged_away.is_empty() {
+      return Err(CherryPickError::Conflicts(merged_away));
+    }
+    let source_version = match patch.source_commits.first() {
+      Some(source_commit) => {
+        let source_commit_luid = self.universe.get_index_of(source_commit).unwrap();
+        self
+          .version(source_commit_luid)
+          .ok_or(CherryPickError::CommitNotFound)?
+          .clone()
+      }
+      None => Version::default(),
+    };
+    let mut replacements: HashMap<Uuid, Uuid> = HashMap::new();
+    let removed = patch.universe_patch.deletions.iter().chain(
+      patch
+        .universe_patch
+        .merges
+        .iter()
+        .filter(|(uuid, merged_into)| uuid != merged_into)
+        .map(|(uuid, _)| uuid),
+    );
+    for uuid in removed {
+      let replacement = Uuid::now_v7();
+      inverse.universe_patch.additions.insert(replacement);
+      replacements.insert(*uuid, replacement);
+    }
+    let replaced = |luid: Luid| {
+      let uuid = *self.universe.get_index(luid).unwrap();
+      replacements.get(&uuid).copied().unwrap_or(uuid)
+    };
+    let is_restored =
+      |luid: Luid| replacements.contains_key(self.universe.get_index(luid).unwrap());
+    // restored elements go back in the sorts their originals belonged to
+    for (uuid, replacement) in replacements.iter() {
+      let luid = self.universe.get_index_of(uuid).unwrap();
+      let kind = match (
+        source_version.s0.contains(luid as u64),
+        source_version.homs.get(&luid),
+        source_version.attrs.get(&luid),
+      ) {
+        (true, _, _) => Some(AdditionKind::NewSort),
+        (false, Some(hom), _) => Some(AdditionKind::NewHom {
+          domain: replaced(hom.domain),
+          codomain: replaced(hom.codomain),
+          partial: hom.partial,
+          free: hom.free,
+        }),
+        (false, None, Some(attr)) => Some(AdditionKind::NewAttr {
+          domain: replaced(attr.domain),
+          attr_type: attr.attr_type,
+        }),
+        (false, None, None) => match source_version.rules.get(&luid) {
+          Some(rule) => Some(AdditionKind::NewRule(rule.unresolve(replaced))),
+          None => source_version
+            .sort_of(luid)
+            .map(|sort| AdditionKind::NewEntity(replaced(sort))),
+        },
+      };
+      if let Some(kind) = kind {
+        inverse.addition_kinds.insert(*replacement, kind);
+      }
+    }
+
+    for path in patch.context_patch.additions.keys() {
+      inverse.context_patch.deletions.insert(path.clone());
+    }
+    for path in patch.context_patch.deletions.iter() {
+      if let Some(luid) = source_version.ctx.get(path.iter()) {
+        let uuid = *self.universe.get_index(luid).unwrap();
+        let uuid = replacements.get(&uuid).copied().unwrap_or(uuid);
+        inverse.context_patch.additions.insert(path.clone(), uuid);
+      }
+    }
+    // hom values go back to what they were in the source version
+    let luid = |uuid: &Uuid| self.universe.get_index_of(uuid).unwrap();
+    let hom_keys = patch
+      .hom_patch
+      .deletions
+      .iter()
+      .chain(patch.hom_patch.additions.keys());
+    for (hom, element) in hom_keys {
+      let key = (
+        replacements.get(hom).copied().unwrap_or(*hom),
+        replacements.get(element).copied().unwrap_or(*element),
+      );
+      if patch.hom_patch.additions.contains_key(&(*hom, *element)) {
+        inverse.hom_patch.deletions.insert(key);
+      }
+      if let Some(value) = source_version.hom_value(luid(hom), luid(element)) {
+        inverse.hom_patch.additions.insert(key, replaced(value));
+      }
+    }
+    // and so do the values at, into, or of restored elements
+    for (hom, values) in source_version.homs.iter() {
+      let elements = source_version.sort_elements(values.domain).unwrap();
+      for (element, value) in elements.iter().zip(values.column.iter()) {
+        let Some(value) = value else {
+          continue;
+        };
+        if is_restored(*hom) || is_restored(element as Luid) || is_restored(*value) {
+          inverse.hom_patch.additions.insert(
+            (replaced(*hom), replaced(element as Luid)),
+            replaced(*value),
+          );
+        }
+      }
+    }
+    // attribute values likewise
+    let attr_keys = patch
+      .attr_patch
+      .deletions
+      .iter()
+      .chain(patch.attr_patch.additions.keys());
+    for (attr, element) in attr_keys {
+      let key = (
+        replacements.get(attr).copied().unwrap_or(*attr),
+        replacements.get(element).copied().unwrap_or(*element),
+      );
+      if patch.attr_patch.additions.contains_key(&(*attr, *element)) {
+        inverse.attr_patch.deletions.insert(key);
+      }
+      if let Some(value) = source_version.attr_value(luid(attr), luid(element)) {
+        inverse.attr_patch.additions.insert(key, value.clone());
+      }
+    }
+    for (attr, values) in source_version.attrs.iter() {
+      let elements = source_version.sort_elements(values.domain).unwrap();
+      for (element, value) in elements.iter().zip(values.column.iter()) {
+        let Some(value) = value else {
+          continue;
+        };
+        if is_restored(*attr) || is_restored(element as Luid) {
+          inverse
+            .attr_patch
+            .additions
+            .insert((replaced(*attr), replaced(element as Luid)), value.clone());
+        }
+      }
+    }
+    Ok(inverse)
+  }
+
+  // Undo the changes made by a commit in the history of HEAD (by its first incoming patch) by
+  // applying the inverse of that patch to the working state, leaving the history intact. Returns
+  // the patch that was inverted.
+  pub fn revert_commit(&mut self, commit: &Uuid) -> Result<Uuid, CherryPickError> {
+    let commit_luid = self
+      .universe
+      .get_index_of(commit)
+      .filter(|luid| self.commits.contains_key(luid))
+      .ok_or(CherryPickError::CommitNotFound)?;
+    let head_uuid = *self
+      .working_patch
+      .source_commits
+      .first()
+      .ok_or(CherryPickError::DetachedHead)?;
+    let head_luid = self.universe.get_index_of(&head_uuid).unwrap();
+    if !self.is_ancestor(commit_luid, head_luid) {
+      return Err(CherryPickError::NotInHistory);
+    }
+    let patch_luid = self.commits.get(&commit_luid).unwrap()[0].1;
+    let patch_uuid = *self.universe.get_index(patch_luid).unwrap();
+    let inverse = self.invert(&patch_uuid)?;
+    self
+      .apply_to_working_state(&inverse)
+      .map_err(CherryPickError::Conflicts)?;
+    Ok(patch_uuid)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn working_set(state: &TotalState) -> BTreeSet<Uuid> {
+    state.list().copied().collect()
+  }
+
+  #[test]
+  fn revert_deletes_what_the_commit_added() {
+    let mut state = TotalState::default();
+    let x = state.add();
+    state.commit_in_memory();
+    let z = state.add();
+    state.name(&z, &["z".to_string()]);
+    let c = state.commit_in_memory();
+    state.revert_commit(&c).unwrap();
+    assert_eq!(working_set(&state), BTreeSet::from([x]));
+    state.commit_in_memory();
+    assert_eq!(state.lookup("z"), None);
+  }
+
+  #[test]
+  fn revert_refuses_to_delete_what_an_addition_was_merged_into() {
+    let mut state = TotalState::default();
+    let x = state.add();
+    let y = state.add();
+    state.commit_in_memory();
+    let z = state.add();
+    let c = state.commit_in_memory();
+    state.merge_into(&z, &x).unwrap();
+    state.commit_in_memory();
+    let universe_len = state.universe.len();
+    match state.revert_commit(&c) {
+      Err(CherryPickError::Conflicts(conflicts)) => assert!(matches!(
+        conflicts[..],
+        [Conflict::MergedAway { element, into }] if element == z && into == x
+      )),
+      other => panic!("expected a conflict, got {:?}", other),
+    }
+    // (nothing changes when the revert is refused)
+    assert_eq!(working_set(&state), BTreeSet::from([x, y]));
+    assert!(state.working_patch.is_empty());
+    assert_eq!(state.universe.len(), universe_len);
+  }
+
+  #[test]
+  fn revert_restores_deleted_elements_as_new_ones() {
+    let mut state = TotalState::default();
+    let x = state.add();
+    state.commit_in_memory();
+    state.delete(&x).unwrap();
+    let c = state.commit_in_memory();
+    state.revert_commit(&c).unwrap();
+    state.commit_in_memory();
+    let restored: Vec<Uuid> = working_set(&state).into_iter().collect();
+    assert_eq!(restored.len(), 1);
+    assert_ne!(restored[0], x);
+  }
+}
@@ -0,0 +1,31 @@
+use crate::id::*;
+use crate::patch::*;
+use crate::query::*;
+use crate::state::*;
+
+impl TotalState {
+  // Add a rule `conclusion :- premises` (see `query::parse_rule`) to the working patch, as a new
+  // element, with its names resolved against the working state.
+  pub fn add_rule(&mut self, text: &str) -> Result<Uuid, QueryError> {
+    let (conclusion, premises) = parse_rule(text)?;
+    let version = self.working_version();
+    // (resolved together, so that the premises and the conclusion number their variables alike)
+    let mut query = premises;
+    query.atoms.push(conclusion);
+    let (variables, mut atoms) = self.resolve_query(&query, &version)?;
+    let conclusion = atoms.pop().unwrap();
+    let rule = ResolvedRule {
+      variables: variables.len(),
+      premises: atoms,
+      conclusion,
+    };
+    rule.check().map_err(QueryError::InvalidRule)?;
+    let rule = rule.unresolve(|luid| *self.universe.get_index(luid).unwrap());
+    let uuid = self.add();
+    self
+      .working_patch
+      .addition_kinds
+      .insert(uuid, AdditionKind::NewRule(rule));
+    Ok(uuid)
+  }
+}
@@ -0,0 +1,391 @@
+use crate::attribute::*;
+use crate::id::*;
+use crate::patch::*;
+use crate::state::*;
+use crate::type_registry::*;
+
+#[derive(Clone, Debug)]
+pub enum SchemaError {
+  NotASort(Uuid),
+  NotAHom(Uuid),
+  // (of a hom or an attribute)
+  NotInDomain { function: Uuid, element: Uuid },
+  NotInCodomain { hom: Uuid, value: Uuid },
+  UnknownType(Uuid),
+  NotAnAttr(Uuid),
+  WrongType { attr: Uuid, expected: Uuid },
+  InvalidValue(String),
+}
+
+impl std::fmt::Display for SchemaError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      SchemaError::NotASort(uuid) => write!(f, "{} is not a sort", uuid.as_base64url()),
+      SchemaError::NotAHom(uuid) => write!(f, "{} is not a hom", uuid.as_base64url()),
+      SchemaError::NotInDomain { function, element } => write!(
+        f,
+        "{} is not in the domain of {}",
+        element.as_base64url(),
+        function.as_base64url()
+      ),
+      SchemaError::NotInCodomain { hom, value } => write!(
+        f,
+        "{} is not in the codomain of {}",
+        value.as_base64url(),
+        hom.as_base64url()
+      ),
+      SchemaError::UnknownType(uuid) => write!(f, "{} is not a type", uuid.as_base64url()),
+      SchemaError::NotAnAttr(uuid) => write!(f, "{} is not an attribute", uuid.as_base64url()),
+      SchemaError::WrongType { attr, expected } => write!(
+        f,
+        "Values of {} must be of type {}",
+        attr.as_base64url(),
+        type_name(expected)
+      ),
+      SchemaError::InvalidValue(message) => write!(f, "{}", message),
+    }
+  }
+}
+
+impl From<TypeError> for SchemaError {
+  fn from(e: TypeError) -> Self {
+    match e {
+      TypeError::Unregistered(uuid) => SchemaError::UnknownType(uuid),
+      TypeError::InvalidValue { message, .. } => SchemaError::InvalidValue(message),
+    }
+  }
+}
+
+pub(in crate::state) fn type_name(attr_type: &Uuid) -> String {
+  match PrimitiveType::from_uuid(attr_type) {
+    Some(primitive_type) => primitive_type.name().to_string(),
+    None => attr_type.as_base64url(),
+  }
+}
+
+impl TotalState {
+  // Declare a hom `domain -> codomain` between two sorts of the working state, as a new element.
+  // Unless it is partial or free, it must be given a value at every element of `domain` before
+  // committing.
+  pub fn add_hom(
+    &mut self,
+    domain: &Uuid,
+    codomain: &Uuid,
+    partial: bool,
+    free: bool,
+  ) -> Result<Uuid, SchemaError> {
+    let version = self.working_version();
+    for sort in [domain, codomain] {
+      let is_sort = self
+        .universe
+        .get_index_of(sort)
+        .map(|luid| version.s0.contains(luid as u64))
+        .unwrap_or(false);
+      if !is_sort {
+        return Err(SchemaError::NotASort(*sort));
+      }
+    }
+    let hom = self.add();
+    self.working_patch.addition_kinds.insert(
+      hom,
+      AdditionKind::NewHom {
+        domain: *domain,
+        codomain: *codomain,
+        partial,
+        free,
+      },
+    );
+    Ok(hom)
+  }
+
+  // Assert `hom(element) = value` in the working patch, replacing any earlier value.
+  pub fn set_hom_value(
+    &mut self,
+    hom: &Uuid,
+    element: &Uuid,
+    value: &Uuid,
+  ) -> Result<(), SchemaError> {
+    let version = self.working_version();
+    let luid = |uuid: &Uuid| self.universe.get_index_of(uuid);
+    let hom_ref = luid(hom)
+      .and_then(|hom| version.homs.get(&hom))
+      .ok_or(SchemaError::NotAHom(*hom))?;
+    let contains = |sort: Luid, uuid: &Uuid| {
+      luid(uuid)
+        .zip(version.sort_elements(sort))
+        .map(|(luid, elements)| elements.contains(luid as u64))
+        .unwrap_or(false)
+    };
+    if !contains(hom_ref.domain, element) {
+      return Err(SchemaError::NotInDomain {
+        function: *hom,
+        element: *element,
+      });
+    }
+    if !contains(hom_ref.codomain, value) {
+      return Err(SchemaError::NotInCodomain {
+        hom: *hom,
+        value: *value,
+      });
+    }
+    let hom_patch = &mut self.working_patch.hom_patch;
+    hom_patch.deletions.remove(&(*hom, *element));
+    hom_patch.additions.insert((*hom, *element), *value);
+    Ok(())
+  }
+
+  // The value of `hom` at `element` in the working state, if it has been assigned one, or else the
+  // one its rules derive, or else its term if the hom is free (as a query would find it); with
+  // whether the value was derived.
+  pub fn hom_value(&mut self, hom: &Uuid, element: &Uuid) -> Option<(Uuid, bool)> {
+    let version = self.working_version();
+    let hom = self.universe.get_index_of(hom)?;
+    let element = self.universe.get_index_of(element)?;
+    let (value, derived) = match version.hom_value(hom, element) {
+      Some(value) => (value, false),
+      None => match version.derived.homs.get(&(hom, element)) {
+        Some(&value) => (value, true),
+        None => (self.with_terms(&version).hom_value(hom, element)?, false),
+      },
+    };
+    Some((*self.universe.get_index(value).unwrap(), derived))
+  }
+
+  // Declare an attribute of the entities of a sort of the working state, as a new element.
+  pub fn add_attr(&mut self, domain: &Uuid, attr_type: &Uuid) -> Result<Uuid, SchemaError> {
+    let version = self.working_version();
+    let is_sort = self
+      .universe
+      .get_index_of(domain)
+      .map(|luid| version.s0.contains(luid as u64))
+      .unwrap_or(false);
+    if !is_sort {
+      return Err(SchemaError::NotASort(*domain));
+    }
+    if !self.types.contains(attr_type) {
+      return Err(SchemaError::UnknownType(*attr_type));
+    }
+    let attr = self.add();
+    self.working_patch.addition_kinds.insert(
+      attr,
+      AdditionKind::NewAttr {
+        domain: *domain,
+        attr_type: *attr_type,
+      },
+    );
+    Ok(attr)
+  }
+
+  // The type of the values of an attribute of the working state.
+  pub fn attr_type(&mut self, attr: &Uuid) -> Result<Uuid, SchemaError> {
+    let version = self.working_version();
+    self
+      .universe
+      .get_index_of(attr)
+      .and_then(|luid| version.attrs.get(&luid))
+      .map(|attr| attr.attr_type)
+      .ok_or(SchemaError::NotAnAttr(*attr))
+  }
+
+  // Parse a value for an attribute from its textual form, according to the attribute's type.
+  pub fn parse_attr_value(&mut self, attr: &Uuid, text: &str) -> Result<AttrValue, SchemaError> {
+    let attr_type = self.attr_type(attr)?;
+    self
+      .types
+      .parse(&attr_type, text)
+      .map_err(SchemaError::from)
+  }
+
+  // Assert `attr(element) = value` in the working patch, replacing any earlier value.
+  pub fn set_attr_value(
+    &mut self,
+    attr: &Uuid,
+    element: &Uuid,
+    value: AttrValue,
+  ) -> Result<(), SchemaError> {
+    let version = self.working_version();
+    let attr_ref = self
+      .universe
+      .get_index_of(attr)
+      .and_then(|luid| version.attrs.get(&luid))
+      .ok_or(SchemaError::NotAnAttr(*attr))?;
+    let in_domain = self
+      .universe
+      .get_index_of(element)
+      .zip(version.sort_elements(attr_ref.domain))
+      .map(|(luid, elements)| elements.contains(luid as u64))
+      .unwrap_or(false);
+    if !in_domain {
+      return Err(SchemaError::NotInDomain {
+        function: *attr,
+        element: *element,
+      });
+    }
+    if value.type_uuid() != attr_ref.attr_type {
+      return Err(SchemaError::WrongType {
+        attr: *attr,
+        expected: attr_ref.attr_type,
+      });
+    }
+    self.types.validate(&value)?;
+    let attr_patch = &mut self.working_patch.attr_patch;
+    attr_patch.deletions.remove(&(*attr, *element));
+    attr_patch.additions.insert((*attr, *element), value);
+    Ok(())
+  }
+
+  // Retract the value of a hom or attribute at an element in the working patch, if it has one.
+  pub fn unset_value(&mut self, function: &Uuid, element: &Uuid) -> Result<(), SchemaError> {
+    let version = self.working_version();
+    let function_luid = self.universe.get_index_of(function);
+    let key = (*function, *element);
+    if function_luid.is_some_and(|luid| version.homs.contains_key(&luid)) {
+      let hom_patch = &mut self.working_patch.hom_patch;
+      hom_patch.additions.remove(&key);
+      hom_patch.deletions.insert(key);
+    } else if function_luid.is_some_and(|luid| version.attrs.contains_key(&luid)) {
+      let attr_patch = &mut self.working_patch.attr_patch;
+      attr_patch.additions.remove(&key);
+      attr_patch.deletions.insert(key);
+    } else {
+      return Err(SchemaError::NotAHom(*function));
+    }
+    Ok(())
+  }
+
+  // The value of `attr` for `element` in the working state, if it has been assigned one, or else
+  // the one its rules derive; with whether the value was derived.
+  pub fn attr_value(&mut self, attr: &Uuid, element: &Uuid) -> Option<(AttrValue, bool)> {
+    let version = self.working_version();
+    let attr = self.universe.get_index_of(attr)?;
+    let element = self.universe.get_index_of(element)?;
+    match version.attr_value(attr, element) {
+      Some(value) => Some((value.clone(), false)),
+      None => version
+        .derived
+        .attrs
+        .get(&(attr, element))
+        .map(|value| (value.clone(), true)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::state::import::ImportOptions;
+
+  // Sorts P, with entities a and b, and C, with an entity x.
+  fn sorts() -> (TotalState, [Uuid; 5]) {
+    let mut state = TotalState::default();
+    state
+      .import_csv("name\na\nb\n".as_bytes(), &ImportOptions::new("P"))
+      .unwrap();
+    state
+      .import_csv("name\nx\n".as_bytes(), &ImportOptions::new("C"))
+      .unwrap();
+    let elements = ["P", "C", "P/a", "P/b", "C/x"].map(|name| state.lookup(name).unwrap());
+    state.commit_in_memory();
+    (state, elements)
+  }
+
+  #[test]
+  fn hom_values_must_lie_in_the_domain_and_codomain() {
+    let (mut state, [p, c, a, _, x]) = sorts();
+    assert!(matches!(
+      state.add_hom(&p, &a, false, false),
+      Err(SchemaError::NotASort(sort)) if sort == a
+    ));
+    let f = state.add_hom(&p, &c, true, false).unwrap();
+    assert!(matches!(
+      state.set_hom_value(&f, &a, &a),
+      Err(SchemaError::NotInCodomain { hom, value }) if hom == f && value == a
+    ));
+    assert!(matches!(
+      state.set_hom_value(&f, &x, &x),
+      Err(SchemaError::NotInDomain { function, element }) if function == f && element == x
+    ));
+    assert!(matches!(
+      state.set_hom_value(&a, &a, &x),
+      Err(SchemaError::NotAHom(hom)) if hom == a
+    ));
+    // (nothing is asserted when a value is refused)
+    assert!(state.working_patch.hom_patch.is_empty());
+    state.set_hom_value(&f, &a, &x).unwrap();
+    assert_eq!(state.hom_value(&f, &a), Some((x, false)));
+    state.commit_in_memory();
+    assert_eq!(state.hom_value(&f, &a), Some((x, false)));
+  }
+
+  #[test]
+  fn only_partial_homs_can_lack_values() {
+    let (mut state, [p, c, a, b, x]) = sorts();
+    let partial = state.add_hom(&p, &c, true, false).unwrap();
+    let total = state.add_hom(&p, &c, false, false).unwrap();
+    state.set_hom_value(&total, &a, &x).unwrap();
+    let violations = state.check_integrity();
+    assert!(matches!(
+      violations[..],
+      [Violation::Unassigned { hom, element }] if hom == total && element == b
+    ));
+    state.set_hom_value(&total, &b, &x).unwrap();
+    state.commit_in_memory();
+    assert_eq!(state.hom_value(&partial, &a), None);
+    assert_eq!(state.hom_value(&total, &b), Some((x, false)));
+    // (and retracting a value of a total hom leaves it unassigned again)
+    state.unset_value(&total, &b).unwrap();
+    assert_eq!(state.hom_value(&total, &b), None);
+    assert_eq!(state.check_integrity().len(), 1);
+  }
+
+  #[test]
+  fn attr_values_are_parsed_by_the_type_of_their_attribute() {
+    let (mut state, [p, _, a, b, x]) = sorts();
+    let attrs = PrimitiveType::ALL.map(|primitive_type| {
+      let attr = state.add_attr(&p, &primitive_type.uuid()).unwrap();
+      (primitive_type, attr)
+    });
+    let texts = ["ada", "36", "1.75", "false", "aGk", "1815-12-10T00:00:00Z"];
+    for ((primitive_type, attr), text) in attrs.iter().zip(texts) {
+      assert_eq!(state.attr_type(attr).unwrap(), primitive_type.uuid());
+      let value = state.parse_attr_value(attr, text).unwrap();
+      assert_eq!(value, primitive_type.parse(text).unwrap());
+      state.set_attr_value(attr, &a, value).unwrap();
+    }
+    state.commit_in_memory();
+    for ((_, attr), text) in attrs.iter().zip(texts) {
+      let (value, derived) = state.attr_value(attr, &a).unwrap();
+      assert!(!derived);
+      assert_eq!(value.to_string(), text);
+      assert_eq!(state.attr_value(attr, &b), None);
+    }
+
+    let (_, i64_attr) = attrs[1];
+    assert!(matches!(
+      state.parse_attr_value(&i64_attr, "thirty-six"),
+      Err(SchemaError::InvalidValue(_))
+    ));
+    assert!(matches!(
+      state.parse_attr_value(&a, "36"),
+      Err(SchemaError::NotAnAttr(attr)) if attr == a
+    ));
+    assert!(matches!(
+      state.set_attr_value(&i64_attr, &b, AttrValue::F64(36.0)),
+      Err(SchemaError::WrongType { attr, expected })
+        if attr == i64_attr && expected == PrimitiveType::I64.uuid()
+    ));
+    assert!(matches!(
+      state.set_attr_value(&i64_attr, &x, AttrValue::I64(36)),
+      Err(SchemaError::NotInDomain { function, element }) if function == i64_attr && element == x
+    ));
+    assert!(matches!(
+      state.add_attr(&p, &Uuid::now_v7()),
+      Err(SchemaError::UnknownType(_))
+    ));
+    assert!(state.working_patch.is_empty());
+    // (retracting a value)
+    state.unset_value(&i64_attr, &a).unwrap();
+    state.commit_in_memory();
+    assert_eq!(state.attr_value(&i64_attr, &a), None);
+  }
+}
@@ -0,0 +1,230 @@
+use crate::id::*;
+use crate::patch::*;
+use crate::state::*;
+use crate::version::*;
+
+impl TotalState {
+  // The term `hom(arg)` of the working state, or the element it has been merged into, without
+  // adding it to the working state (see `add_term`).
+  pub fn term(&mut self, hom: &Uuid, arg: &Uuid) -> Result<Uuid, SchemaError> {
+    let version = self.working_version();
+    let domain = self
+      .universe
+      .get_index_of(hom)
+      .and_then(|hom| version.homs.get(&hom))
+      .map(|hom| hom.domain)
+      .ok_or(SchemaError::NotAHom(*hom))?;
+    let in_domain = self
+      .universe
+      .get_index_of(arg)
+      .zip(version.sort_elements(domain))
+      .map(|(luid, elements)| elements.contains(luid as u64))
+      .unwrap_or(false);
+    if !in_domain {
+      return Err(SchemaError::NotInDomain {
+        function: *hom,
+        element: *arg,
+      });
+    }
+    let term = term_uuid(hom, arg);
+    let representative = self
+      .universe
+      .get_index_of(&term)
+      .map(|luid| *self.universe.get_index(version.merged.find(luid)).unwrap());
+    Ok(representative.unwrap_or(term))
+  }
+
+  // The term `hom(arg)` (see `AdditionKind::NewTerm`), added to the working patch unless the working
+  // state has it already, so that it can be the value of a hom, have values of its own, or be
+  // merged into an entity to equate the two. A term that has been merged is its representative.
+  pub fn add_term(&mut self, hom: &Uuid, arg: &Uuid) -> Result<Uuid, SchemaError> {
+    let term = self.term(hom, arg)?;
+    if term != term_uuid(hom, arg) {
+      return Ok(term);
+    }
+    let luid = self.universe.insert_full(term).0;
+    if self.working_state.insert(luid) {
+      self.working_patch.universe_patch.additions.insert(term);
+      self.working_patch.addition_kinds.insert(
+        term,
+        AdditionKind::NewTerm {
+          hom: *hom,
+          arg: *arg,
+        },
+      );
+    }
+    Ok(term)
+  }
+
+  // A copy of a version in which every free hom has its term as its value wherever it has no other
+  // (see `Version::with_terms`), for reading.
+  pub fn with_terms(&mut self, version: &Version) -> Version {
+    let universe = &mut self.universe;
+    version.with_terms(|hom, arg| {
+      let term = term_uuid(
+        universe.get_index(hom).unwrap(),
+        universe.get_index(arg).unwrap(),
+      );
+      universe.insert_full(term).0
+    })
+  }
+
+  // The textual form of an element of a version if it is a term, such as `PresentKing(France)`,
+  // with its homs and innermost argument given by name where they have one.
+  pub fn term_text(&self, version: &Version, element: Luid) -> Option<String> {
+    let name = |luid: Luid| {
+      version
+        .ctx
+        .namings()
+        .into_iter()
+        .find(|(_, named)| *named == luid)
+        .map(|(path, _)| path.join("/"))
+        .unwrap_or_else(|| self.universe.get_index(luid).unwrap().as_base64url())
+    };
+    let mut homs = Vec::new();
+    let mut element = element;
+    // (merges can make a term its own argument, eventually)
+    while let Some((hom, arg)) = version.terms.get(&element) {
+      if homs.len() > version.terms.len() {
+        break;
+      }
+      homs.push(*hom);
+      element = *arg;
+    }
+    if homs.is_empty() {
+      return None;
+    }
+    let text = homs.iter().rev().fold(name(element), |text, hom| {
+      format!("{}({})", name(*hom), text)
+    });
+    Some(text)
+  }
+
+  // The textual form of an element of the working state if it is a term, including the terms that
+  // free homs have as their values.
+  pub fn describe_term(&mut self, uuid: &Uuid) -> Option<String> {
+    let luid = self.universe.get_index_of(uuid)?;
+    let version = self.working_version();
+    let version = self.with_terms(&version);
+    self.term_text(&version, luid)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::query::QueryValue;
+  use crate::state::import::ImportOptions;
+
+  // Countries France and Spain, people Louis and Marie, a free hom `king : Country -> Person` and a
+  // partial hom `spouse : Person -> Person`; committed.
+  fn kingdoms() -> (TotalState, Uuid, Uuid, [Uuid; 4]) {
+    let mut state = TotalState::default();
+    state
+      .import_csv(
+        "name\nfrance\nspain\n".as_bytes(),
+        &ImportOptions::new("Country"),
+      )
+      .unwrap();
+    state
+      .import_csv(
+        "name\nlouis\nmarie\n".as_bytes(),
+        &ImportOptions::new("Person"),
+      )
+      .unwrap();
+    let [country, person] = ["Country", "Person"].map(|name| state.lookup(name).unwrap());
+    let king = state.add_hom(&country, &person, false, true).unwrap();
+    let spouse = state.add_hom(&person, &person, true, false).unwrap();
+    state.name(&king, &["king".to_string()]);
+    state.name(&spouse, &["spouse".to_string()]);
+    let elements = [
+      "Country/france",
+      "Country/spain",
+      "Person/louis",
+      "Person/marie",
+    ]
+    .map(|name| state.lookup(name).unwrap());
+    state.commit_in_memory();
+    (state, king, spouse, elements)
+  }
+
+  #[test]
+  fn a_free_hom_without_a_value_has_its_term() {
+    let (mut state, king, _, [france, spain, ..]) = kingdoms();
+    let term = term_uuid(&king, &france);
+    assert_eq!(state.hom_value(&king, &france), Some((term, false)));
+    assert_eq!(
+      state.describe_term(&term),
+      Some("king(Country/france)".to_string())
+    );
+    // (which is only read, not added to the working state)
+    assert!(state.working_patch.is_empty());
+    assert_eq!(state.term(&king, &france).unwrap(), term);
+
+    let result = state.query("?c : Country, king(c) = ?k").unwrap();
+    let mut kings: Vec<(Uuid, Uuid)> = result
+      .rows
+      .iter()
+      .map(|row| match row[..] {
+        [QueryValue::Element(country), QueryValue::Element(king)] => (country, king),
+        _ => panic!("expected elements, got {:?}", row),
+      })
+      .collect();
+    kings.sort();
+    let mut expected = vec![(france, term), (spain, term_uuid(&king, &spain))];
+    expected.sort();
+    assert_eq!(kings, expected);
+    assert_eq!(
+      result.terms.get(&term).map(|text| text.as_str()),
+      Some("king(Country/france)")
+    );
+  }
+
+  #[test]
+  fn a_term_is_the_same_element_in_every_branch() {
+    let (mut state, king, spouse, [france, _, louis, marie]) = kingdoms();
+    let root = state.working_patch.source_commits[0];
+    // each branch refers to the term as the value of another hom
+    let mut branches = Vec::new();
+    for person in [louis, marie] {
+      state.checkout(&root).unwrap();
+      let term = state.add_term(&king, &france).unwrap();
+      state.set_hom_value(&spouse, &person, &term).unwrap();
+      branches.push(state.commit_in_memory());
+    }
+    let term = term_uuid(&king, &france);
+    state.merge(&branches[..1], false).unwrap();
+    state.commit_in_memory();
+    for person in [louis, marie] {
+      assert_eq!(state.hom_value(&spouse, &person), Some((term, false)));
+    }
+    let terms = state.list().filter(|uuid| **uuid == term).count();
+    assert_eq!(terms, 1);
+  }
+
+  #[test]
+  fn a_term_can_be_equated_with_an_entity_later() {
+    let (mut state, king, spouse, [france, spain, louis, marie]) = kingdoms();
+    let term = state.add_term(&king, &france).unwrap();
+    state.set_hom_value(&spouse, &marie, &term).unwrap();
+    state.commit_in_memory();
+    // asserting the value of the hom at the term's argument
+    state.set_hom_value(&king, &france, &louis).unwrap();
+    state.commit_in_memory();
+    assert_eq!(state.canonical(&term), Some(louis));
+    assert_eq!(state.hom_value(&king, &france), Some((louis, false)));
+    assert_eq!(state.hom_value(&spouse, &marie), Some((louis, false)));
+    assert_eq!(state.term(&king, &france).unwrap(), louis);
+    assert!(!state.list().any(|uuid| *uuid == term));
+
+    // or merging the term into the entity
+    let term = state.add_term(&king, &spain).unwrap();
+    state.set_hom_value(&spouse, &louis, &term).unwrap();
+    state.commit_in_memory();
+    state.merge_into(&term, &marie).unwrap();
+    state.commit_in_memory();
+    assert_eq!(state.hom_value(&king, &spain), Some((marie, false)));
+    assert_eq!(state.hom_value(&spouse, &louis), Some((marie, false)));
+    assert_eq!(state.describe_term(&marie), None);
+  }
+}
@@ -1,6 +1,9 @@
 use crate::id::*;
 use crate::patch::*;
-use crate::version::*;
+use crate::shared_treemap::SharedTreemap;
+use crate::state::version_cache::*;
+use crate::type_registry::TypeRegistry;
+use crate::version::Version;
 use indexmap::IndexSet;
 use std::collections::{BTreeMap, BTreeSet, HashMap};
 use tinyvec::TinyVec;
@@ -10,7 +13,14 @@ pub type Universe = IndexSet<Uuid>;
 pub type Commits = BTreeMap<Luid, TinyVec<[(TinyVec<[Luid; 2]>, Luid); 1]>>;
 pub type Patches = BTreeMap<Luid, Patch>;
 pub type Heads = BTreeSet<Luid>;
-pub type VersionCache = HashMap<Luid, Version>; // TODO: consider alternative data structures
+//                   patch id, whether it yields the same version as the other patch(es) to its target
+pub type AlternatePatches = BTreeMap<Luid, bool>;
+//                 element id, (patch id, element it was merged into) for each patch that removes it
+pub type Tombstones = HashMap<Luid, TinyVec<[(Luid, Option<Luid>); 1]>>;
+//                     commit id, generation number
+pub type Generations = HashMap<Luid, usize>;
+//                           commit id, ancestor commit ids
+pub type ReachabilityIndex = HashMap<Luid, SharedTreemap>;
 pub(crate) type WorkingPatch = Patch;
 pub(crate) type WorkingState = IndexSet<Luid>;
 
@@ -21,6 +31,15 @@ pub struct TotalState {
   pub patches: Patches,
   pub heads: Heads,
   pub version_cache: VersionCache,
+  pub alternate_patches: AlternatePatches,
+  pub tombstones: Tombstones,
+  pub generations: Generations,
+  pub(crate) awaiting_generation: HashMap<Luid, Vec<Luid>>,
+  pub reachability_index: Option<ReachabilityIndex>,
+  pub types: TypeRegistry,
   pub(crate) working_patch: WorkingPatch,
   pub(crate) working_state: WorkingState,
+  // the last working version computed, with the working patch it was computed from (see
+  // `working_version`)
+  pub(crate) working_version_cache: Option<(WorkingPatch, Version)>,
 }
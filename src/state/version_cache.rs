@@ -0,0 +1,206 @@
+use crate::id::*;
+use crate::state::*;
+use crate::version::*;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const DEFAULT_MEMORY_BUDGET: usize = 256 << 20; // bytes
+pub const DEFAULT_CHECKPOINT_INTERVAL: usize = 64; // commits along the longest path from a root
+
+struct CachedVersion {
+  version: Version,
+  size: usize,
+  last_used: AtomicU64,
+}
+
+// Holds a `Version` for every head and every checkpoint commit, plus as many other recently-used
+// versions as fit in `memory_budget`. Evicted versions are recomputed on demand (see
+// `TotalState::version`) by replaying patches from their nearest cached ancestors.
+pub struct VersionCache {
+  resident: HashMap<Luid, CachedVersion>,
+  // depth of every commit that has ever been processed, whether or not its version is resident
+  depths: HashMap<Luid, usize>,
+  clock: AtomicU64,
+  resident_size: usize,
+  pub memory_budget: usize,
+  pub checkpoint_interval: usize,
+}
+
+impl Default for VersionCache {
+  fn default() -> Self {
+    Self::with_budget(DEFAULT_MEMORY_BUDGET)
+  }
+}
+
+impl VersionCache {
+  pub fn with_budget(memory_budget: usize) -> Self {
+    Self {
+      resident: HashMap::new(),
+      depths: HashMap::new(),
+      clock: AtomicU64::new(0),
+      resident_size: 0,
+      memory_budget,
+      checkpoint_interval: DEFAULT_CHECKPOINT_INTERVAL,
+    }
+  }
+
+  pub fn get(&self, commit: &Luid) -> Option<&Version> {
+    let cached = self.resident.get(commit)?;
+    let now = self.clock.fetch_add(1, Ordering::Relaxed);
+    cached.last_used.store(now, Ordering::Relaxed);
+    Some(&cached.version)
+  }
+
+  // Whether the version of `commit` is currently held in memory.
+  pub fn contains_key(&self, commit: &Luid) -> bool {
+    self.resident.contains_key(commit)
+  }
+
+  // Whether `commit` has ever been processed (its version may since have been evicted).
+  pub fn is_processed(&self, commit: &Luid) -> bool {
+    self.depths.contains_key(commit)
+  }
+
+  pub fn depth(&self, commit: &Luid) -> Option<usize> {
+    self.depths.get(commit).copied()
+  }
+
+  pub fn is_checkpoint(&self, commit: &Luid) -> bool {
+    self
+      .depth(commit)
+      .map(|depth| depth % self.checkpoint_interval.max(1) == 0)
+      .unwrap_or(false)
+  }
+
+  pub fn insert(&mut self, commit: Luid, depth: usize, version: Version) {
+    let size = version.heap_size();
+    self.depths.insert(commit, depth);
+    let cached = CachedVersion {
+      version,
+      size,
+      last_used: AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed)),
+    };
+    self.resident_size += size;
+    if let Some(old) = self.resident.insert(commit, cached) {
+      self.resident_size -= old.size;
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.resident.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.resident.is_empty()
+  }
+
+  pub fn resident_size(&self) -> usize {
+    self.resident_size
+  }
+
+  // Once the cache exceeds its memory budget, drop least-recently-used versions until it is back
+  // under three quarters of the budget (so that eviction is amortized over many insertions).
+  // Heads, checkpoints and commits for which `is_pinned` holds are never evicted, so the budget
+  // may be exceeded if those alone don't fit. Since the size of a version apportions the structure
+  // it shares among the versions that share it, sizes change as versions come and go; they are
+  // recomputed before and after each round of eviction, so that the accounting doesn't drift.
+  pub fn evict(&mut self, heads: &Heads, is_pinned: impl Fn(&Luid) -> bool) -> usize {
+    if self.resident_size <= self.memory_budget {
+      return 0;
+    }
+    let low_water_mark = self.memory_budget - self.memory_budget / 4;
+    let mut n_evicted = 0;
+    loop {
+      self.recompute_sizes();
+      if self.resident_size <= low_water_mark {
+        break;
+      }
+      let mut candidates: Vec<(u64, Luid)> = self
+        .resident
+        .iter()
+        .filter(|(commit, _)| {
+          !heads.contains(commit) && !is_pinned(commit) && !self.is_checkpoint(commit)
+        })
+        .map(|(commit, cached)| (cached.last_used.load(Ordering::Relaxed), *commit))
+        .collect();
+      if candidates.is_empty() {
+        break;
+      }
+      candidates.sort_unstable();
+      for (_, commit) in candidates {
+        if self.resident_size <= low_water_mark {
+          break;
+        }
+        let evicted = self.resident.remove(&commit).unwrap();
+        self.resident_size -= evicted.size;
+        n_evicted += 1;
+      }
+    }
+    n_evicted
+  }
+
+  fn recompute_sizes(&mut self) {
+    self.resident_size = 0;
+    for cached in self.resident.values_mut() {
+      cached.size = cached.version.heap_size();
+      self.resident_size += cached.size;
+    }
+  }
+}
+
+impl TotalState {
+  // Get the version of a commit, recomputing it from its nearest cached ancestors if it has been
+  // evicted from the version cache.
+  pub fn version(&mut self, commit: Luid) -> Option<&Version> {
+    if !self.version_cache.contains_key(&commit) {
+      let patch_luid = self.commits.get(&commit)?.first()?.1;
+      process_patch(
+        &mut self.universe,
+        &mut self.version_cache,
+        &mut self.commits,
+        &mut self.heads,
+        &self.patches,
+        patch_luid,
+        |_| false,
+      );
+      self
+        .version_cache
+        .evict(&self.heads, |&luid| luid == commit);
+    }
+    self.version_cache.get(&commit)
+  }
+
+  pub fn set_version_cache_budget(&mut self, memory_budget: usize) {
+    self.version_cache.memory_budget = memory_budget;
+    self.version_cache.evict(&self.heads, |_| false);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn eviction_accounts_for_the_structure_that_versions_share() {
+    let mut cache = VersionCache::with_budget(usize::MAX);
+    cache.checkpoint_interval = usize::MAX;
+    // (a chain of versions, each sharing all but one chunk with the one before it)
+    let mut version = Version::default();
+    version.version_universe.extend(0..1 << 20);
+    let size = version.heap_size();
+    for commit in 0..8 {
+      version
+        .version_universe
+        .insert((1 << 20) + (commit as u64) * 4096);
+      cache.insert(commit, commit + 1, version.clone());
+    }
+    drop(version);
+    // (only the head is left, and it now holds all the structure that the others shared with it)
+    cache.memory_budget = size / 2;
+    cache.evict(&Heads::from([7]), |_| false);
+    assert_eq!(cache.len(), 1);
+    let head_size = cache.get(&7).unwrap().heap_size();
+    assert!(head_size > size);
+    assert_eq!(cache.resident_size(), head_size);
+  }
+}
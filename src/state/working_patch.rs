@@ -0,0 +1,252 @@
+use crate::id::*;
+use crate::patch::*;
+use crate::state::commit::{read_patch, write_patch};
+use crate::state::process_patch::compute_version;
+use crate::state::*;
+use crate::version::*;
+use memmap2::Mmap;
+use std::fs::{self, File};
+use std::path::PathBuf;
+
+impl TotalState {
+  pub fn get_working_patch_path() -> PathBuf {
+    PathBuf::from("working_patch")
+  }
+
+  // Keep the working patch (and with it, HEAD) on disk, so that it survives between runs.
+  pub fn save_working_patch(&self) {
+    let mut file = File::create(Self::get_working_patch_path()).unwrap();
+    write_patch(&mut file, &self.working_patch);
+  }
+
+  // Restore the working patch saved by `save_working_patch`, and the working state it yields. A
+  // working patch whose source commits are no longer known is ignored.
+  pub fn load_working_patch(&mut self) {
+    let path = Self::get_working_patch_path();
+    let Ok(f) = File::open(&path) else {
+      return;
+    };
+    let mmap = unsafe { Mmap::map(&f).unwrap() };
+    let patch = match read_patch(mmap.as_ref()) {
+      Ok(patch) => patch,
+      Err(e) => {
+        eprintln!("Warning: ignoring unreadable {:?}: {}", path, e);
+        return;
+      }
+    };
+    // (kept on disk, to be picked up once its types are registered)
+    if let Err(e) = self.types.check_patch(&patch) {
+      eprintln!("Warning: ignoring {:?}: {}", path, e);
+      return;
+    }
+    for source_commit in patch.source_commits.iter() {
+      if !self
+        .universe
+        .get_index_of(source_commit)
+        .map(|luid| self.version_cache.is_processed(&luid))
+        .unwrap_or(false)
+      {
+        eprintln!(
+          "Warning: ignoring {:?}, whose source commits are unknown",
+          path
+        );
+        fs::remove_file(&path).unwrap();
+        return;
+      }
+    }
+    self.universe.extend(patch.referenced_uuids());
+    self.working_patch = patch;
+    self.working_state = self
+      .working_version()
+      .version_universe
+      .iter()
+      .map(|x| x as usize)
+      .collect();
+  }
+
+  // Delete an element of the working state in the working patch. Deleting a sort, hom or attribute
+  // changes the schema, and the instance is migrated along with it when the patch is processed
+  // (taking the homs and attributes of a deleted sort out of the working state too).
+  pub fn delete(&mut self, uuid: &Uuid) -> Result<(), CherryPickError> {
+    let mut patch = Patch::default();
+    patch.universe_patch.deletions.insert(*uuid);
+    self
+      .apply_to_working_state(&patch)
+      .map_err(CherryPickError::Conflicts)?;
+    self.working_state = self
+      .working_version()
+      .version_universe
+      .iter()
+      .map(|x| x as usize)
+      .collect();
+    Ok(())
+  }
+
+  // Merge an element of the working state into another in the working patch. Merging a sort into
+  // another (possibly new) sort, or a hom or attribute into another, changes the schema, and the
+  // instance is migrated along with it when the patch is processed.
+  pub fn merge_into(&mut self, uuid: &Uuid, merged_into: &Uuid) -> Result<(), CherryPickError> {
+    let mut patch = Patch::default();
+    patch.universe_patch.merges.insert(*uuid, *merged_into);
+    self
+      .apply_to_working_state(&patch)
+      .map_err(CherryPickError::Conflicts)
+  }
+
+  // The representative of an element's class of merged elements in the working state (see
+  // `Version::rebuild`): the element itself, unless it has been merged into another.
+  pub fn canonical(&mut self, uuid: &Uuid) -> Option<Uuid> {
+    let luid = self.universe.get_index_of(uuid)?;
+    let version = self.working_version();
+    Some(*self.universe.get_index(version.merged.find(luid)).unwrap())
+  }
+
+  // The version that committing the working patch would yield, including its sorts and names. It
+  // is computed again only once the working patch has changed (the versions of its sources never
+  // do), since the schema and the values of homs and attributes are read through it.
+  pub fn working_version(&mut self) -> Version {
+    if let Some((patch, version)) = &self.working_version_cache {
+      if *patch == self.working_patch {
+        return version.clone();
+      }
+    }
+    let source_commit_luids: Vec<Luid> = self
+      .working_patch
+      .source_commits
+      .iter()
+      .map(|uuid| self.universe.get_index_of(uuid).unwrap())
+      .collect();
+    for luid in source_commit_luids {
+      self.version(luid);
+    }
+    let version = compute_version(&self.universe, &self.version_cache, &self.working_patch).1;
+    self.working_version_cache = Some((self.working_patch.clone(), version.clone()));
+    version
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::attribute::AttrValue;
+  use crate::state::import::ImportOptions;
+
+  #[test]
+  fn deleting_a_sort_takes_its_homs_and_their_names_along() {
+    let mut state = TotalState::default();
+    let csv = |names: &str| format!("name\n{}\n", names.replace(' ', "\n"));
+    state
+      .import_csv(csv("a b").as_bytes(), &ImportOptions::new("P"))
+      .unwrap();
+    state
+      .import_csv(csv("x").as_bytes(), &ImportOptions::new("C"))
+      .unwrap();
+    let [p, c, a] = ["P", "C", "P/a"].map(|name| state.lookup(name).unwrap());
+    let hom = state.add_hom(&p, &c, true, false).unwrap();
+    state.name(&hom, &["likes".to_string()]);
+    state.commit_in_memory();
+    state.delete(&p).unwrap();
+    let working_state: Vec<Uuid> = state.list().copied().collect();
+    assert!(!working_state.contains(&p));
+    assert!(!working_state.contains(&hom));
+    assert!(working_state.contains(&c));
+    let commit = state.commit_in_memory();
+    assert_eq!(state.lookup("P"), None);
+    assert_eq!(state.lookup("likes"), None);
+    assert_eq!(state.lookup("P/a"), Some(a));
+    let mut names = Vec::new();
+    state.export_csv(&commit, &mut names).unwrap();
+    let names = String::from_utf8(names).unwrap();
+    assert!(!names.contains(&hom.as_base64url()));
+    assert!(!names.contains(&p.as_base64url()));
+  }
+
+  #[test]
+  fn merging_a_sort_realigns_the_columns_of_its_homs_and_attrs() {
+    let mut state = TotalState::default();
+    let csv = |names: &str| format!("name\n{}\n", names.replace(' ', "\n"));
+    for (names, sort) in [("a b", "P"), ("c d", "Q"), ("x y", "C")] {
+      state
+        .import_csv(csv(names).as_bytes(), &ImportOptions::new(sort))
+        .unwrap();
+    }
+    let [p, q, c, a, b, c_, d, x, y] = ["P", "Q", "C", "P/a", "P/b", "Q/c", "Q/d", "C/x", "C/y"]
+      .map(|name| state.lookup(name).unwrap());
+    let [i64_type, string_type] =
+      ["I64", "String"].map(|name| state.types.lookup_name(name).unwrap());
+    // homs and attributes on both sorts, and a hom into the merged one
+    let f = state.add_hom(&p, &c, true, false).unwrap();
+    let g = state.add_hom(&q, &c, true, false).unwrap();
+    let h = state.add_hom(&c, &p, true, false).unwrap();
+    let label = state.add_attr(&p, &string_type).unwrap();
+    let age = state.add_attr(&q, &i64_type).unwrap();
+    state.set_hom_value(&f, &a, &x).unwrap();
+    state.set_hom_value(&f, &b, &y).unwrap();
+    state.set_hom_value(&g, &c_, &y).unwrap();
+    state.set_hom_value(&h, &x, &a).unwrap();
+    state
+      .set_attr_value(&label, &a, AttrValue::String("A".to_string()))
+      .unwrap();
+    state.set_attr_value(&age, &d, AttrValue::I64(4)).unwrap();
+    state.commit_in_memory();
+
+    // (P's entities are older than Q's, so they come first in the columns of the merged sort)
+    state.merge_into(&p, &q).unwrap();
+    state.commit_in_memory();
+    let version = state.working_version();
+    let luid = |uuid: &Uuid| state.universe.get_index_of(uuid).unwrap();
+    assert!(!version.s0.contains(luid(&p) as u64));
+    for entity in [a, b, c_, d] {
+      assert_eq!(version.sort_of(luid(&entity)), Some(luid(&q)));
+    }
+    for hom in [f, g] {
+      assert_eq!(version.homs[&luid(&hom)].domain, luid(&q));
+      assert_eq!(version.homs[&luid(&hom)].column.len(), 4);
+    }
+    assert_eq!(version.homs[&luid(&h)].codomain, luid(&q));
+    for attr in [label, age] {
+      assert_eq!(version.attrs[&luid(&attr)].domain, luid(&q));
+      assert_eq!(version.attrs[&luid(&attr)].column.len(), 4);
+    }
+    let hom_values = |state: &mut TotalState, hom: &Uuid| {
+      [a, b, c_, d].map(|entity| state.hom_value(hom, &entity).map(|(value, _)| value))
+    };
+    assert_eq!(hom_values(&mut state, &f), [Some(x), Some(y), None, None]);
+    assert_eq!(hom_values(&mut state, &g), [None, None, Some(y), None]);
+    assert_eq!(state.hom_value(&h, &x), Some((a, false)));
+    let attr_values = |state: &mut TotalState, attr: &Uuid| {
+      [a, b, c_, d].map(|entity| state.attr_value(attr, &entity).map(|(value, _)| value))
+    };
+    assert_eq!(
+      attr_values(&mut state, &label),
+      [Some(AttrValue::String("A".to_string())), None, None, None]
+    );
+    assert_eq!(
+      attr_values(&mut state, &age),
+      [None, None, None, Some(AttrValue::I64(4))]
+    );
+  }
+
+  #[test]
+  fn the_working_version_follows_the_working_patch() {
+    let mut state = TotalState::default();
+    state
+      .import_csv("name\na\n".as_bytes(), &ImportOptions::new("P"))
+      .unwrap();
+    let [p, a] = ["P", "P/a"].map(|name| state.lookup(name).unwrap());
+    let f = state.add_hom(&p, &p, true, false).unwrap();
+    state.commit_in_memory();
+    let version = state.working_version();
+    assert!(state.working_version() == version);
+    // (however the working patch is changed)
+    state.set_hom_value(&f, &a, &a).unwrap();
+    assert_eq!(state.hom_value(&f, &a), Some((a, false)));
+    state.working_patch.hom_patch.additions.clear();
+    assert_eq!(state.hom_value(&f, &a), None);
+    assert!(state.working_version() == version);
+    state.name(&a, &["b".to_string()]);
+    assert_eq!(state.lookup("b"), Some(a));
+    state.commit_in_memory();
+    assert_eq!(state.lookup("b"), Some(a));
+  }
+}
@@ -0,0 +1,403 @@
+use crate::attribute::*;
+use crate::id::*;
+use crate::patch::*;
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::validation::validators::DefaultValidator;
+use rkyv::{check_archived_root, AlignedVec, Archive, CheckBytes, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+// A user-defined type of attribute values (an element of S1 beyond the primitive types). Values
+// are carried in patches as bytes, which the type knows how to produce, check, order and print.
+pub trait AttrType: Send + Sync {
+  fn uuid(&self) -> Uuid;
+  fn name(&self) -> &str;
+  // The bytes of the value written as `text`.
+  fn archive(&self, text: &str) -> Result<Vec<u8>, String>;
+  // Check that bytes (from a patch) are a valid value of the type.
+  fn validate(&self, bytes: &[u8]) -> Result<(), String>;
+  fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+  fn display(&self, bytes: &[u8]) -> String;
+}
+
+// A Rust type whose values are stored as their rkyv archives; register it with `RkyvType`.
+pub trait RkyvAttrValue: Archive + Serialize<AllocSerializer<256>> + Sized {
+  const UUID: Uuid;
+  const NAME: &'static str;
+  fn parse(text: &str) -> Result<Self, String>;
+  fn compare(a: &Self::Archived, b: &Self::Archived) -> Ordering;
+  fn display(value: &Self::Archived) -> String;
+}
+
+pub struct RkyvType<T>(PhantomData<fn() -> T>);
+
+impl<T> RkyvType<T> {
+  pub fn new() -> Self {
+    Self(PhantomData)
+  }
+}
+
+impl<T> Default for RkyvType<T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<T> RkyvType<T>
+where
+  T: RkyvAttrValue,
+  T::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+{
+  // Run `f` on the archived value in `bytes`, once they have been validated. (They are copied to be
+  // suitably aligned first, since patches don't keep values aligned.)
+  fn with_archived<R>(bytes: &[u8], f: impl FnOnce(&T::Archived) -> R) -> Result<R, String> {
+    let mut aligned = AlignedVec::with_capacity(bytes.len());
+    aligned.extend_from_slice(bytes);
+    let archived = check_archived_root::<T>(&aligned).map_err(|e| e.to_string())?;
+    Ok(f(archived))
+  }
+}
+
+impl<T> AttrType for RkyvType<T>
+where
+  T: RkyvAttrValue,
+  T::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+{
+  fn uuid(&self) -> Uuid {
+    T::UUID
+  }
+
+  fn name(&self) -> &str {
+    T::NAME
+  }
+
+  fn archive(&self, text: &str) -> Result<Vec<u8>, String> {
+    let value = T::parse(text)?;
+    let bytes = rkyv::to_bytes::<_, 256>(&value).map_err(|e| e.to_string())?;
+    Ok(bytes.into_vec())
+  }
+
+  fn validate(&self, bytes: &[u8]) -> Result<(), String> {
+    Self::with_archived(bytes, |_| ())
+  }
+
+  fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+    // (invalid values, which are never accepted into a version, order by their bytes)
+    Self::with_archived(a, |a| Self::with_archived(b, |b| T::compare(a, b)))
+      .and_then(|ordering| ordering)
+      .unwrap_or_else(|_| a.cmp(b))
+  }
+
+  fn display(&self, bytes: &[u8]) -> String {
+    Self::with_archived(bytes, T::display)
+      .unwrap_or_else(|e| format!("<invalid {}: {}>", T::NAME, e))
+  }
+}
+
+// The types of attribute values known to this installation: the primitive types, and whatever
+// types downstream crates register. Patches using a type that isn't registered can't be loaded.
+#[derive(Clone, Default)]
+pub struct TypeRegistry {
+  types: HashMap<Uuid, Arc<dyn AttrType>>,
+}
+
+#[derive(Clone, Debug)]
+pub enum TypeError {
+  Unregistered(Uuid),
+  // (the message names the type)
+  InvalidValue { attr_type: Uuid, message: String },
+}
+
+impl std::fmt::Display for TypeError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      TypeError::Unregistered(uuid) => {
+        write!(f, "type {} is not registered", uuid.as_base64url())
+      }
+      TypeError::InvalidValue { message, .. } => write!(f, "{}", message),
+    }
+  }
+}
+
+impl TypeRegistry {
+  pub fn register(&mut self, attr_type: impl AttrType + 'static) {
+    self.types.insert(attr_type.uuid(), Arc::new(attr_type));
+  }
+
+  pub fn get(&self, uuid: &Uuid) -> Option<&dyn AttrType> {
+    self.types.get(uuid).map(|attr_type| attr_type.as_ref())
+  }
+
+  // Whether values of a type can be handled: it is primitive or registered.
+  pub fn contains(&self, uuid: &Uuid) -> bool {
+    PrimitiveType::from_uuid(uuid).is_some() || self.types.contains_key(uuid)
+  }
+
+  // Names of all known types, primitive types first.
+  pub fn names(&self) -> Vec<String> {
+    let mut custom: Vec<String> = self
+      .types
+      .values()
+      .map(|attr_type| attr_type.name().to_string())
+      .collect();
+    custom.sort();
+    PrimitiveType::ALL
+      .iter()
+      .map(|t| t.name().to_string())
+      .chain(custom)
+      .collect()
+  }
+
+  pub fn lookup_name(&self, name: &str) -> Option<Uuid> {
+    PrimitiveType::from_name(name)
+      .map(|t| t.uuid())
+      .or_else(|| {
+        self
+          .types
+          .values()
+          .find(|attr_type| attr_type.name() == name)
+          .map(|attr_type| attr_type.uuid())
+      })
+  }
+
+  pub fn type_name(&self, uuid: &Uuid) -> String {
+    match (PrimitiveType::from_uuid(uuid), self.get(uuid)) {
+      (Some(t), _) => t.name().to_string(),
+      (None, Some(attr_type)) => attr_type.name().to_string(),
+      (None, None) => uuid.as_base64url(),
+    }
+  }
+
+  // Parse a value of a type from its textual form.
+  pub fn parse(&self, uuid: &Uuid, text: &str) -> Result<AttrValue, TypeError> {
+    let invalid = |message| TypeError::InvalidValue {
+      attr_type: *uuid,
+      message,
+    };
+    if let Some(t) = PrimitiveType::from_uuid(uuid) {
+      return t.parse(text).map_err(invalid);
+    }
+    let attr_type = self.get(uuid).ok_or(TypeError::Unregistered(*uuid))?;
+    let bytes = attr_type
+      .archive(text)
+      .map_err(|e| invalid(format!("Invalid {}: {:?} ({})", attr_type.name(), text, e)))?;
+    Ok(AttrValue::Custom {
+      attr_type: *uuid,
+      bytes,
+    })
+  }
+
+  pub fn validate(&self, value: &AttrValue) -> Result<(), TypeError> {
+    let AttrValue::Custom { attr_type, bytes } = value else {
+      return Ok(());
+    };
+    let t = self
+      .get(attr_type)
+      .ok_or(TypeError::Unregistered(*attr_type))?;
+    t.validate(bytes).map_err(|e| TypeError::InvalidValue {
+      attr_type: *attr_type,
+      message: format!("Invalid {} ({})", t.name(), e),
+    })
+  }
+
+  // Values of the same type in their type's order; values of different types by type.
+  pub fn compare(&self, a: &AttrValue, b: &AttrValue) -> Ordering {
+    match (a, b) {
+      (
+        AttrValue::Custom {
+          attr_type,
+          bytes: a_bytes,
+        },
+        AttrValue::Custom {
+          attr_type: b_type,
+          bytes: b_bytes,
+        },
+      ) if attr_type == b_type => match self.get(attr_type) {
+        Some(t) => t.compare(a_bytes, b_bytes),
+        None => a_bytes.cmp(b_bytes),
+      },
+      _ => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+    }
+  }
+
+  pub fn display(&self, value: &AttrValue) -> String {
+    match value {
+      AttrValue::Custom { attr_type, bytes } => match self.get(attr_type) {
+        Some(t) => t.display(bytes),
+        None => value.to_string(),
+      },
+      _ => value.to_string(),
+    }
+  }
+
+  pub fn to_json(&self, value: &AttrValue) -> serde_json::Value {
+    match value {
+      AttrValue::Custom { .. } => serde_json::Value::String(self.display(value)),
+      _ => value.to_json(),
+    }
+  }
+
+  // Check that every type a patch declares an attribute of or carries a value of is known, and
+  // that its values are valid.
+  pub fn check_patch(&self, patch: &Patch) -> Result<(), TypeError> {
+    for kind in patch.addition_kinds.values() {
+      if let AdditionKind::NewAttr { attr_type, .. } = kind {
+        if !self.contains(attr_type) {
+          return Err(TypeError::Unregistered(*attr_type));
+        }
+      }
+    }
+    for value in patch.attr_patch.additions.values() {
+      self.validate(value)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rkyv::Deserialize;
+
+  // Closed intervals of integers, written `start..end` and ordered by their start, then their end.
+  #[derive(Archive, Deserialize, Serialize)]
+  #[archive(check_bytes)]
+  struct Interval {
+    start: i64,
+    end: i64,
+  }
+
+  impl RkyvAttrValue for Interval {
+    const UUID: Uuid = Uuid::from_u128(0x0190_0000_0000_8000_8000_1e7e_4a10_0001);
+    const NAME: &'static str = "Interval";
+    fn parse(text: &str) -> Result<Self, String> {
+      let (start, end) = text.split_once("..").ok_or("expected start..end")?;
+      let bound = |text: &str| text.parse::<i64>().map_err(|e| e.to_string());
+      let (start, end) = (bound(start)?, bound(end)?);
+      match start <= end {
+        true => Ok(Interval { start, end }),
+        false => Err("the start is after the end".to_string()),
+      }
+    }
+    fn compare(a: &Self::Archived, b: &Self::Archived) -> Ordering {
+      (a.start.value(), a.end.value()).cmp(&(b.start.value(), b.end.value()))
+    }
+    fn display(value: &Self::Archived) -> String {
+      format!("{}..{}", value.start, value.end)
+    }
+  }
+
+  fn registry() -> TypeRegistry {
+    let mut types = TypeRegistry::default();
+    types.register(RkyvType::<Interval>::new());
+    types
+  }
+
+  #[test]
+  fn registered_types_parse_compare_and_display_their_values() {
+    let types = registry();
+    let uuid = Interval::UUID;
+    assert!(types.contains(&uuid));
+    assert_eq!(types.lookup_name("Interval"), Some(uuid));
+    assert_eq!(types.type_name(&uuid), "Interval");
+    assert_eq!(
+      types.names().last().map(|name| name.as_str()),
+      Some("Interval")
+    );
+    assert_eq!(types.lookup_name("i64"), Some(PrimitiveType::I64.uuid()));
+
+    let value = types.parse(&uuid, "-3..5").unwrap();
+    assert_eq!(value.type_uuid(), uuid);
+    types.validate(&value).unwrap();
+    assert_eq!(types.display(&value), "-3..5");
+    assert_eq!(types.to_json(&value), serde_json::json!("-3..5"));
+    // (the bytes are the archive of the value)
+    let AttrValue::Custom { bytes, .. } = &value else {
+      panic!("expected a custom value, got {:?}", value);
+    };
+    let mut aligned = AlignedVec::new();
+    aligned.extend_from_slice(bytes);
+    let archived = check_archived_root::<Interval>(&aligned).unwrap();
+    let interval: Interval = archived.deserialize(&mut rkyv::Infallible).unwrap();
+    assert_eq!((interval.start, interval.end), (-3, 5));
+
+    let mut values: Vec<AttrValue> = ["2..3", "-3..5", "2..2", "10..11"]
+      .iter()
+      .map(|text| types.parse(&uuid, text).unwrap())
+      .collect();
+    values.sort_by(|a, b| types.compare(a, b));
+    let sorted: Vec<String> = values.iter().map(|value| types.display(value)).collect();
+    assert_eq!(sorted, ["-3..5", "2..2", "2..3", "10..11"]);
+  }
+
+  #[test]
+  fn invalid_and_unregistered_values_are_rejected() {
+    let types = registry();
+    for text in ["5..3", "3", "a..b"] {
+      assert!(matches!(
+        types.parse(&Interval::UUID, text),
+        Err(TypeError::InvalidValue { attr_type, message })
+          if attr_type == Interval::UUID && message.starts_with("Invalid Interval")
+      ));
+    }
+    let garbage = AttrValue::Custom {
+      attr_type: Interval::UUID,
+      bytes: vec![1, 2, 3],
+    };
+    assert!(matches!(
+      types.validate(&garbage),
+      Err(TypeError::InvalidValue { .. })
+    ));
+    assert!(types.display(&garbage).starts_with("<invalid Interval"));
+
+    let unregistered = TypeRegistry::default();
+    assert!(!unregistered.contains(&Interval::UUID));
+    assert_eq!(unregistered.lookup_name("Interval"), None);
+    assert!(matches!(
+      unregistered.parse(&Interval::UUID, "1..2"),
+      Err(TypeError::Unregistered(uuid)) if uuid == Interval::UUID
+    ));
+  }
+
+  #[test]
+  fn patches_using_unregistered_types_are_rejected() {
+    let types = registry();
+    let value = types.parse(&Interval::UUID, "1..2").unwrap();
+    let mut declares = Patch::default();
+    declares.addition_kinds.insert(
+      Uuid::now_v7(),
+      AdditionKind::NewAttr {
+        domain: Uuid::now_v7(),
+        attr_type: Interval::UUID,
+      },
+    );
+    let mut assigns = Patch::default();
+    assigns
+      .attr_patch
+      .additions
+      .insert((Uuid::now_v7(), Uuid::now_v7()), value);
+    let unregistered = TypeRegistry::default();
+    for patch in [&declares, &assigns] {
+      types.check_patch(patch).unwrap();
+      assert!(matches!(
+        unregistered.check_patch(patch),
+        Err(TypeError::Unregistered(uuid)) if uuid == Interval::UUID
+      ));
+    }
+    // (nor are invalid values of registered types)
+    let mut invalid = Patch::default();
+    invalid.attr_patch.additions.insert(
+      (Uuid::now_v7(), Uuid::now_v7()),
+      AttrValue::Custom {
+        attr_type: Interval::UUID,
+        bytes: vec![],
+      },
+    );
+    assert!(matches!(
+      types.check_patch(&invalid),
+      Err(TypeError::InvalidValue { .. })
+    ));
+  }
+}
@@ -0,0 +1,86 @@
+use crate::id::*;
+use std::collections::BTreeMap;
+
+// Classes of elements that have been merged, as a union-find: each element that has been merged
+// into another points towards the representative of its class (directly, once `flatten`ed).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UnionFind {
+  parents: BTreeMap<Luid, Luid>,
+}
+
+impl UnionFind {
+  // The representative of an element's class (the element itself, unless it has been merged).
+  pub fn find(&self, mut element: Luid) -> Luid {
+    while let Some(&parent) = self.parents.get(&element) {
+      element = parent;
+    }
+    element
+  }
+
+  // Merge the class of `element` into that of `into`, whose representative then represents both;
+  // returns false if they were one class already.
+  pub fn union(&mut self, element: Luid, into: Luid) -> bool {
+    let (element, into) = (self.find(element), self.find(into));
+    if element == into {
+      return false;
+    }
+    self.parents.insert(element, into);
+    true
+  }
+
+  // Point every merged element directly at its representative.
+  pub fn flatten(&mut self) {
+    let roots: Vec<(Luid, Luid)> = self.merged().collect();
+    self.parents.extend(roots);
+  }
+
+  // The elements that have been merged into others, with their representatives.
+  pub fn merged(&self) -> impl Iterator<Item = (Luid, Luid)> + '_ {
+    self
+      .parents
+      .keys()
+      .map(|&element| (element, self.find(element)))
+  }
+
+  pub fn is_merged(&self, element: Luid) -> bool {
+    self.parents.contains_key(&element)
+  }
+
+  pub fn len(&self) -> usize {
+    self.parents.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.parents.is_empty()
+  }
+
+  // Approximate number of bytes of heap memory used
+  pub fn heap_size(&self) -> usize {
+    self.parents.len() * std::mem::size_of::<(Luid, Luid)>()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn unions_merge_classes_into_the_class_of_into() {
+    let mut classes = UnionFind::default();
+    assert!(classes.union(1, 2));
+    assert!(classes.union(2, 3));
+    assert!(classes.union(4, 1));
+    assert!(!classes.union(3, 4));
+    assert_eq!(
+      [1, 2, 3, 4, 5].map(|element| classes.find(element)),
+      [3, 3, 3, 3, 5]
+    );
+    assert!(classes.is_merged(1) && !classes.is_merged(3));
+    classes.flatten();
+    assert_eq!(
+      classes.merged().collect::<Vec<_>>(),
+      vec![(1, 3), (2, 3), (4, 3)]
+    );
+    assert_eq!(classes.len(), 3);
+  }
+}
@@ -1,9 +1,845 @@
+use crate::attribute::*;
 use crate::context::*;
-use roaring::RoaringTreemap;
+use crate::id::*;
+use crate::query::{Derived, ResolvedAtom, ResolvedRule};
+use crate::shared_treemap::SharedTreemap;
+use crate::union_find::UnionFind;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::Arc;
 
+// Values of a function on the elements of a sort (its domain), aligned with them: the value at
+// Slid `i` is that of the `i`th element of the sort. Columns are shared between versions until one
+// of their values changes.
+pub type Column<T> = Arc<Vec<Option<T>>>;
+
+fn column_value<'a, T>(
+  column: &'a Column<T>,
+  elements: &SharedTreemap,
+  element: Luid,
+) -> Option<&'a T> {
+  if !elements.contains(element as u64) {
+    return None;
+  }
+  column[elements.rank(element as u64) as usize - 1].as_ref()
+}
+
+// Align a column, from either or both of two versions, with the combined elements of its domain;
+// where both versions have a value, `ours` wins.
+fn union_columns<T: Clone>(
+  ours: Option<(&Column<T>, &SharedTreemap)>,
+  theirs: Option<(&Column<T>, &SharedTreemap)>,
+  elements: &SharedTreemap,
+) -> Column<T> {
+  match (ours, theirs) {
+    (Some((ours, _)), Some((theirs, _))) if Arc::ptr_eq(ours, theirs) => ours.clone(),
+    (Some((only, _)), None) | (None, Some((only, _))) if only.len() as u64 == elements.len() => {
+      only.clone()
+    }
+    _ => Arc::new(
+      elements
+        .iter()
+        .map(|element| {
+          let element = element as Luid;
+          ours
+            .and_then(|(column, elements)| column_value(column, elements, element))
+            .or_else(|| {
+              theirs.and_then(|(column, elements)| column_value(column, elements, element))
+            })
+            .cloned()
+        })
+        .collect(),
+    ),
+  }
+}
+
+// A morphism of the schema between two sorts, with its values.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hom {
+  pub domain: Luid,
+  pub codomain: Luid,
+  pub partial: bool,
+  // (its value is its term wherever it has no other; see `with_terms`)
+  pub free: bool,
+  pub column: Column<Luid>,
+}
+
+// An attribute of the elements of a sort, with values of a type (an element of S1).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attr {
+  pub domain: Luid,
+  pub attr_type: Uuid,
+  pub column: Column<AttrValue>,
+}
+
+// Versions derived from one another share most of their structure (see `SharedTreemap` and
+// `Context`), so cloning a version and applying a patch to the clone is cheap.
+#[derive(Clone, Default, PartialEq)]
 pub struct Version {
-  pub version_universe: RoaringTreemap, // of Luid
-  pub s0: RoaringTreemap,               // of Vlid
-  pub s0i: Vec<RoaringTreemap>,         // Slid(s0) -> Vlid
+  pub version_universe: SharedTreemap, // of Luid
+  pub s0: SharedTreemap,               // of Luid
+  pub s0i: Vec<SharedTreemap>,         // Slid(s0) -> Luid
   pub ctx: Context,
+  pub homs: BTreeMap<Luid, Hom>,
+  pub attrs: BTreeMap<Luid, Attr>,
+  pub rules: BTreeMap<Luid, ResolvedRule>,
+  // the terms among the elements, as the (hom, argument) that each is the application of
+  pub terms: BTreeMap<Luid, (Luid, Luid)>,
+  // the classes of the elements that have been merged in the history of the version
+  pub merged: Arc<UnionFind>,
+  // (computed by `query::derive` once the rest of the version is)
+  pub derived: Arc<Derived>,
+}
+
+impl Version {
+  // Combine with another source version of the same patch. Namings that conflict between the two
+  // are resolved in favour of `self`.
+  pub fn union_with(&mut self, other: &Version) {
+    self.version_universe.union_with(&other.version_universe);
+    let mut s0 = self.s0.clone();
+    s0.union_with(&other.s0);
+    let s0i = s0
+      .iter()
+      .map(|sort| {
+        let ours = self
+          .s0
+          .contains(sort)
+          .then(|| &self.s0i[self.s0.rank(sort) as usize - 1]);
+        let theirs = other
+          .s0
+          .contains(sort)
+          .then(|| &other.s0i[other.s0.rank(sort) as usize - 1]);
+        match (ours, theirs) {
+          (Some(ours), Some(theirs)) => {
+            let mut elements = ours.clone();
+            elements.union_with(theirs);
+            elements
+          }
+          (Some(elements), None) | (None, Some(elements)) => elements.clone(),
+          (None, None) => unreachable!(),
+        }
+      })
+      .collect::<Vec<_>>();
+    // columns are realigned with the combined elements of their domains
+    let domain_elements = |domain: Luid| {
+      s0.contains(domain as u64)
+        .then(|| &s0i[s0.rank(domain as u64) as usize - 1])
+    };
+    let mut homs = BTreeMap::new();
+    for (hom_luid, hom) in self.homs.iter().chain(other.homs.iter()) {
+      let Some(elements) = domain_elements(hom.domain) else {
+        continue;
+      };
+      if homs.contains_key(hom_luid) {
+        continue;
+      }
+      let ours = self
+        .homs
+        .get(hom_luid)
+        .and_then(|hom| Some((&hom.column, self.sort_elements(hom.domain)?)));
+      let theirs = other
+        .homs
+        .get(hom_luid)
+        .and_then(|hom| Some((&hom.column, other.sort_elements(hom.domain)?)));
+      let column = union_columns(ours, theirs, elements);
+      homs.insert(
+        *hom_luid,
+        Hom {
+          column,
+          ..hom.clone()
+        },
+      );
+    }
+    let mut attrs = BTreeMap::new();
+    for (attr_luid, attr) in self.attrs.iter().chain(other.attrs.iter()) {
+      let Some(elements) = domain_elements(attr.domain) else {
+        continue;
+      };
+      if attrs.contains_key(attr_luid) {
+        continue;
+      }
+      let ours = self
+        .attrs
+        .get(attr_luid)
+        .and_then(|attr| Some((&attr.column, self.sort_elements(attr.domain)?)));
+      let theirs = other
+        .attrs
+        .get(attr_luid)
+        .and_then(|attr| Some((&attr.column, other.sort_elements(attr.domain)?)));
+      let column = union_columns(ours, theirs, elements);
+      attrs.insert(
+        *attr_luid,
+        Attr {
+          column,
+          ..attr.clone()
+        },
+      );
+    }
+    self.s0 = s0;
+    self.s0i = s0i;
+    self.homs = homs;
+    self.attrs = attrs;
+    self.ctx.union_with(&other.ctx);
+    for (rule_luid, rule) in other.rules.iter() {
+      self.rules.entry(*rule_luid).or_insert_with(|| rule.clone());
+    }
+    for (term, application) in other.terms.iter() {
+      self.terms.entry(*term).or_insert(*application);
+    }
+    // (what the rules derive from the union is derived anew)
+    self.derived = Arc::default();
+  }
+
+  // The elements of a sort, if it is one.
+  pub fn sort_elements(&self, sort: Luid) -> Option<&SharedTreemap> {
+    self
+      .s0
+      .contains(sort as u64)
+      .then(|| &self.s0i[self.s0.rank(sort as u64) as usize - 1])
+  }
+
+  // The sort that an element belongs to, if any.
+  pub fn sort_of(&self, element: Luid) -> Option<Luid> {
+    self
+      .s0
+      .iter()
+      .zip(self.s0i.iter())
+      .find(|(_, elements)| elements.contains(element as u64))
+      .map(|(sort, _)| sort as Luid)
+  }
+
+  pub fn add_sort(&mut self, sort: Luid) {
+    if self.s0.insert(sort as u64) {
+      let slid = self.s0.rank(sort as u64) as usize - 1;
+      self.s0i.insert(slid, SharedTreemap::new());
+    }
+  }
+
+  // Add an element to a sort; returns false if there is no such sort.
+  pub fn add_to_sort(&mut self, sort: Luid, element: Luid) -> bool {
+    if !self.s0.contains(sort as u64) {
+      return false;
+    }
+    let slid = self.s0.rank(sort as u64) as usize - 1;
+    if self.s0i[slid].insert(element as u64) {
+      let slid = self.s0i[slid].rank(element as u64) as usize - 1;
+      for hom in self.homs.values_mut().filter(|hom| hom.domain == sort) {
+        Arc::make_mut(&mut hom.column).insert(slid, None);
+      }
+      for attr in self.attrs.values_mut().filter(|attr| attr.domain == sort) {
+        Arc::make_mut(&mut attr.column).insert(slid, None);
+      }
+    }
+    true
+  }
+
+  // Add a hom between two sorts, with no values yet; returns false if either is not a sort.
+  pub fn add_hom(
+    &mut self,
+    hom: Luid,
+    domain: Luid,
+    codomain: Luid,
+    partial: bool,
+    free: bool,
+  ) -> bool {
+    let Some(elements) = self.sort_elements(domain) else {
+      return false;
+    };
+    if !self.s0.contains(codomain as u64) {
+      return false;
+    }
+    let column = Arc::new(vec![None; elements.len() as usize]);
+    self.homs.insert(
+      hom,
+      Hom {
+        domain,
+        codomain,
+        partial,
+        free,
+        column,
+      },
+    );
+    true
+  }
+
+  // The value of a hom at an element of its domain, if it has been assigned one.
+  pub fn hom_value(&self, hom: Luid, element: Luid) -> Option<Luid> {
+    let hom = self.homs.get(&hom)?;
+    column_value(&hom.column, self.sort_elements(hom.domain)?, element).copied()
+  }
+
+  // Assign (or, with `None`, unassign) the value of a hom at an element of its domain; returns
+  // false if there is no such hom or the element is not in its domain. Values are not checked
+  // against the codomain here.
+  pub fn set_hom_value(&mut self, hom: Luid, element: Luid, value: Option<Luid>) -> bool {
+    let Some(domain) = self.homs.get(&hom).map(|hom| hom.domain) else {
+      return false;
+    };
+    let Some(elements) = self.sort_elements(domain) else {
+      return false;
+    };
+    if !elements.contains(element as u64) {
+      return false;
+    }
+    let slid = elements.rank(element as u64) as usize - 1;
+    let hom = self.homs.get_mut(&hom).unwrap();
+    Arc::make_mut(&mut hom.column)[slid] = value;
+    true
+  }
+
+  // Add an attribute of the elements of a sort, with no values yet; returns false if there is no
+  // such sort.
+  pub fn add_attr(&mut self, attr: Luid, domain: Luid, attr_type: Uuid) -> bool {
+    let Some(elements) = self.sort_elements(domain) else {
+      return false;
+    };
+    let column = Arc::new(vec![None; elements.len() as usize]);
+    self.attrs.insert(
+      attr,
+      Attr {
+        domain,
+        attr_type,
+        column,
+      },
+    );
+    true
+  }
+
+  // The value of an attribute of an element of its domain, if it has been assigned one.
+  pub fn attr_value(&self, attr: Luid, element: Luid) -> Option<&AttrValue> {
+    let attr = self.attrs.get(&attr)?;
+    column_value(&attr.column, self.sort_elements(attr.domain)?, element)
+  }
+
+  // Assign (or, with `None`, unassign) the value of an attribute of an element of its domain;
+  // returns false if there is no such attribute or the element is not in its domain. Values are not
+  // checked against the type of the attribute here.
+  pub fn set_attr_value(&mut self, attr: Luid, element: Luid, value: Option<AttrValue>) -> bool {
+    let Some(domain) = self.attrs.get(&attr).map(|attr| attr.domain) else {
+      return false;
+    };
+    let Some(elements) = self.sort_elements(domain) else {
+      return false;
+    };
+    if !elements.contains(element as u64) {
+      return false;
+    }
+    let slid = elements.rank(element as u64) as usize - 1;
+    let attr = self.attrs.get_mut(&attr).unwrap();
+    Arc::make_mut(&mut attr.column)[slid] = value;
+    true
+  }
+
+  // A copy of the version with the values that its rules derive filled into its columns, for
+  // querying.
+  pub fn with_derived(&self) -> Version {
+    let mut version = self.clone();
+    for (&(hom, element), &value) in self.derived.homs.iter() {
+      version.set_hom_value(hom, element, Some(value));
+    }
+    for ((attr, element), value) in self.derived.attrs.iter() {
+      version.set_attr_value(*attr, *element, Some(value.clone()));
+    }
+    version
+  }
+
+  // Add the term `hom(arg)` as an element of the codomain of the hom, and as its value at `arg`
+  // unless it has another there (which `equate_terms` then pairs it with); returns false if there is
+  // no such hom or `arg` is not in its domain.
+  pub fn add_term(&mut self, term: Luid, hom: Luid, arg: Luid) -> bool {
+    let Some((domain, codomain)) = self.homs.get(&hom).map(|hom| (hom.domain, hom.codomain)) else {
+      return false;
+    };
+    let in_domain = self
+      .sort_elements(domain)
+      .map(|elements| elements.contains(arg as u64))
+      .unwrap_or(false);
+    if !in_domain {
+      return false;
+    }
+    self.add_to_sort(codomain, term);
+    self.terms.insert(term, (hom, arg));
+    if self.hom_value(hom, arg).is_none() {
+      self.set_hom_value(hom, arg, Some(term));
+    }
+    true
+  }
+
+  // Make each term the value of its application again wherever that has none (as after its value
+  // is retracted), and return the pairs (term, value) of the terms whose application has another
+  // value, which the term is to be merged into (see `rebuild`).
+  pub fn equate_terms(&mut self) -> Vec<(Luid, Luid)> {
+    let terms: Vec<(Luid, (Luid, Luid))> = self
+      .terms
+      .iter()
+      .map(|(term, application)| (*term, *application))
+      .collect();
+    let mut equations = Vec::new();
+    for (term, (hom, arg)) in terms {
+      match self.hom_value(hom, arg) {
+        None => {
+          self.set_hom_value(hom, arg, Some(term));
+        }
+        Some(value) if value != term => equations.push((term, value)),
+        Some(_) => {}
+      }
+    }
+    equations
+  }
+
+  // A copy of the version in which every free hom has its term as its value wherever it has no
+  // other, for reading; `term` gives the element that is the term `hom(arg)` (see `term_uuid`). A
+  // term that has been merged stands for its representative. Terms are only formed at the elements
+  // of the version and not at the new ones, so that a free hom from a sort into itself doesn't
+  // build an endless tower of them.
+  pub fn with_terms(&self, mut term: impl FnMut(Luid, Luid) -> Luid) -> Version {
+    let mut version = self.clone();
+    let applications: Vec<(Luid, Luid)> = self
+      .homs
+      .iter()
+      .filter(|(_, hom)| hom.free)
+      .flat_map(|(hom_luid, hom)| {
+        self
+          .sort_elements(hom.domain)
+          .unwrap()
+          .iter()
+          .zip(hom.column.iter())
+          .filter(|(_, value)| value.is_none())
+          .map(|(element, _)| (*hom_luid, element as Luid))
+      })
+      .collect();
+    for (hom, arg) in applications {
+      let term = term(hom, arg);
+      let value = self.merged.find(term);
+      if value == term && !self.version_universe.contains(term as u64) {
+        let codomain = version.homs.get(&hom).unwrap().codomain;
+        version.version_universe.insert(term as u64);
+        version.add_to_sort(codomain, term);
+        version.terms.insert(term, (hom, arg));
+      } else if !self.version_universe.contains(value as u64) {
+        continue;
+      }
+      version.set_hom_value(hom, arg, Some(value));
+    }
+    version
+  }
+
+  // Remove an element from the version, along with its membership in a sort (or, if it is a sort,
+  // hom or attribute, migrate the instance to the schema without it; see `delta`), or the rule or
+  // term that it is.
+  pub fn remove_element(&mut self, element: Luid) {
+    self.version_universe.remove(element as u64);
+    self.rules.remove(&element);
+    self.terms.remove(&element);
+    self.delta(&BTreeSet::from([element]));
+    if let Some(sort) = self.sort_of(element) {
+      let slid = self.s0.rank(sort as u64) as usize - 1;
+      let element_slid = self.s0i[slid].rank(element as u64) as usize - 1;
+      self.s0i[slid].remove(element as u64);
+      for hom in self.homs.values_mut().filter(|hom| hom.domain == sort) {
+        Arc::make_mut(&mut hom.column).remove(element_slid);
+      }
+      for attr in self.attrs.values_mut().filter(|attr| attr.domain == sort) {
+        Arc::make_mut(&mut attr.column).remove(element_slid);
+      }
+    }
+  }
+
+  // Δ along the inclusion of the schema without the sorts, homs and attributes in `removed`: the
+  // instance is restricted to the rest of the schema, so a removed sort takes the homs and
+  // attributes on it (or into it) along, and they leave the version with their names. Its
+  // elements stay in the version, in no sort.
+  pub fn delta(&mut self, removed: &BTreeSet<Luid>) {
+    for &sort in removed.iter() {
+      if self.s0.contains(sort as u64) {
+        let slid = self.s0.rank(sort as u64) as usize - 1;
+        self.s0i.remove(slid);
+        self.s0.remove(sort as u64);
+      }
+    }
+    let mut dropped = BTreeSet::new();
+    self.homs.retain(|luid, hom| {
+      let kept = !removed.contains(&hom.domain) && !removed.contains(&hom.codomain);
+      if !kept && !removed.contains(luid) {
+        dropped.insert(*luid);
+      }
+      kept && !removed.contains(luid)
+    });
+    self.attrs.retain(|luid, attr| {
+      let kept = !removed.contains(&attr.domain);
+      if !kept && !removed.contains(luid) {
+        dropped.insert(*luid);
+      }
+      kept && !removed.contains(luid)
+    });
+    for &element in dropped.iter() {
+      self.version_universe.remove(element as u64);
+    }
+    self.unname(&dropped);
+  }
+
+  // Remove the namings of `elements`.
+  pub fn unname(&mut self, elements: &BTreeSet<Luid>) {
+    if elements.is_empty() {
+      return;
+    }
+    for (path, luid) in self.ctx.namings() {
+      if elements.contains(&luid) {
+        self.ctx.remove(&path);
+      }
+    }
+  }
+
+  // Σ along the quotient of the schema that identifies each sort, hom or attribute in `merges`
+  // with the one it is (transitively) merged into. The elements of a merged sort become elements of
+  // the sort it is merged into, and the homs and attributes on it or into it are moved along; they
+  // have no values at the elements that weren't in their domain before, where Σ would have to
+  // invent new elements. A merged hom or attribute fills in the values of the one it is merged
+  // into, whose values win where both have one. A sort merged into an element that isn't in the
+  // schema or instance yet is renamed to it. Merges that the schema doesn't allow (a sort into
+  // something other than a sort, or a hom or attribute into one with a different domain, codomain
+  // or type) are not performed, and are returned. Merged elements are left in the version.
+  pub fn sigma(&mut self, merges: &UnionFind) -> Vec<(Luid, Luid)> {
+    let resolve = |luid: Luid| merges.find(luid);
+    let merged = || merges.merged().map(|(luid, _)| luid);
+    let mut rejected = Vec::new();
+
+    let mut sort_merges: BTreeMap<Luid, Luid> = BTreeMap::new();
+    let merged_sorts: Vec<Luid> = merged()
+      .filter(|sort| self.s0.contains(*sort as u64))
+      .collect();
+    for sort in merged_sorts {
+      let merged_into = resolve(sort);
+      if merged_into == sort {
+        continue;
+      }
+      // (merging a sort into an element that has no place in the schema or instance renames it)
+      let is_unused = self.version_universe.contains(merged_into as u64)
+        && !self.homs.contains_key(&merged_into)
+        && !self.attrs.contains_key(&merged_into)
+        && self.sort_of(merged_into).is_none();
+      if is_unused {
+        self.add_sort(merged_into);
+      }
+      if self.s0.contains(merged_into as u64) {
+        sort_merges.insert(sort, merged_into);
+      } else {
+        rejected.push((sort, merged_into));
+      }
+    }
+    if !sort_merges.is_empty() {
+      let old = self.clone();
+      for (&sort, &merged_into) in sort_merges.iter() {
+        let slid = self.s0.rank(merged_into as u64) as usize - 1;
+        self.s0i[slid].union_with(old.sort_elements(sort).unwrap());
+      }
+      for &sort in sort_merges.keys() {
+        let slid = self.s0.rank(sort as u64) as usize - 1;
+        self.s0i.remove(slid);
+        self.s0.remove(sort as u64);
+      }
+      let moved = |sort: Luid| sort_merges.get(&sort).copied().unwrap_or(sort);
+      let grown = |sort: Luid| sort_merges.values().any(|merged_into| *merged_into == sort);
+      // columns are realigned with the new elements of their domains
+      for hom in self.homs.values_mut() {
+        hom.codomain = moved(hom.codomain);
+        let domain = moved(hom.domain);
+        if domain != hom.domain || grown(domain) {
+          let elements = &self.s0i[self.s0.rank(domain as u64) as usize - 1];
+          let old_elements = old.sort_elements(hom.domain).unwrap();
+          hom.column = union_columns(Some((&hom.column, old_elements)), None, elements);
+          hom.domain = domain;
+        }
+      }
+      for attr in self.attrs.values_mut() {
+        let domain = moved(attr.domain);
+        if domain != attr.domain || grown(domain) {
+          let elements = &self.s0i[self.s0.rank(domain as u64) as usize - 1];
+          let old_elements = old.sort_elements(attr.domain).unwrap();
+          attr.column = union_columns(Some((&attr.column, old_elements)), None, elements);
+          attr.domain = domain;
+        }
+      }
+    }
+
+    let merged_homs: Vec<Luid> = merged().filter(|hom| self.homs.contains_key(hom)).collect();
+    for hom in merged_homs {
+      let merged_into = resolve(hom);
+      if merged_into == hom {
+        continue;
+      }
+      let (Some(from), Some(into)) = (self.homs.get(&hom), self.homs.get(&merged_into)) else {
+        rejected.push((hom, merged_into));
+        continue;
+      };
+      if from.domain != into.domain || from.codomain != into.codomain {
+        rejected.push((hom, merged_into));
+        continue;
+      }
+      let elements = self.sort_elements(from.domain).unwrap();
+      let column = union_columns(
+        Some((&into.column, elements)),
+        Some((&from.column, elements)),
+        elements,
+      );
+      self.homs.remove(&hom);
+      self.homs.get_mut(&merged_into).unwrap().column = column;
+    }
+
+    let merged_attrs: Vec<Luid> = merged()
+      .filter(|attr| self.attrs.contains_key(attr))
+      .collect();
+    for attr in merged_attrs {
+      let merged_into = resolve(attr);
+      if merged_into == attr {
+        continue;
+      }
+      let (Some(from), Some(into)) = (self.attrs.get(&attr), self.attrs.get(&merged_into)) else {
+        rejected.push((attr, merged_into));
+        continue;
+      };
+      if from.domain != into.domain || from.attr_type != into.attr_type {
+        rejected.push((attr, merged_into));
+        continue;
+      }
+      let elements = self.sort_elements(from.domain).unwrap();
+      let column = union_columns(
+        Some((&into.column, elements)),
+        Some((&from.column, elements)),
+        elements,
+      );
+      self.attrs.remove(&attr);
+      self.attrs.get_mut(&merged_into).unwrap().column = column;
+    }
+    rejected
+  }
+
+  // Merge elements and restore congruence, as egglog rebuilds an e-graph after a batch of unions:
+  // each pair in `unions` is merged (the first into the second), and whenever two elements of a
+  // sort are merged, the values of each hom at them are merged too (a term into an element that
+  // isn't one, or else into the older, by `older`), as a hom has one value at each element; and so
+  // on until nothing more needs merging. The classes are kept in `merged`. An element merged into
+  // another gives it its hom and attribute values wherever it has none, and is removed; then every
+  // hom value, and the argument of every term, is replaced by the representative of its class.
+  // Returns the merges that were not performed, of elements of different sorts.
+  pub fn rebuild(
+    &mut self,
+    unions: Vec<(Luid, Luid)>,
+    older: impl Fn(Luid, Luid) -> bool,
+  ) -> Vec<(Luid, Luid)> {
+    let mut merged = (*self.merged).clone();
+    let mut work_list = VecDeque::from(unions);
+    let mut rejected = Vec::new();
+    let mut changed = false;
+    while let Some((element, into)) = work_list.pop_front() {
+      let (element, into) = (merged.find(element), merged.find(into));
+      if element == into {
+        continue;
+      }
+      let sort = self.sort_of(element);
+      if sort != self.sort_of(into) {
+        rejected.push((element, into));
+        continue;
+      }
+      if let Some(sort) = sort {
+        let homs: Vec<Luid> = self
+          .homs
+          .iter()
+          .filter(|(_, hom)| hom.domain == sort)
+          .map(|(hom, _)| *hom)
+          .collect();
+        for hom in homs {
+          match (self.hom_value(hom, element), self.hom_value(hom, into)) {
+            (Some(ours), Some(theirs)) if merged.find(ours) != merged.find(theirs) => {
+              let (ours, theirs) = (merged.find(ours), merged.find(theirs));
+              let into_ours = match (
+                self.terms.contains_key(&ours),
+                self.terms.contains_key(&theirs),
+              ) {
+                (false, true) => true,
+                (true, false) => false,
+                _ => older(ours, theirs),
+              };
+              work_list.push_back(match into_ours {
+                true => (theirs, ours),
+                false => (ours, theirs),
+              });
+            }
+            (Some(value), None) => {
+              self.set_hom_value(hom, into, Some(value));
+            }
+            _ => {}
+          }
+        }
+        let attrs: Vec<Luid> = self
+          .attrs
+          .iter()
+          .filter(|(_, attr)| attr.domain == sort)
+          .map(|(attr, _)| *attr)
+          .collect();
+        for attr in attrs {
+          if self.attr_value(attr, into).is_none() {
+            let value = self.attr_value(attr, element).cloned();
+            self.set_attr_value(attr, into, value);
+          }
+        }
+      }
+      self.remove_element(element);
+      merged.union(element, into);
+      changed = true;
+    }
+    if changed {
+      merged.flatten();
+      for hom in self.homs.values_mut() {
+        if hom
+          .column
+          .iter()
+          .flatten()
+          .any(|value| merged.is_merged(*value))
+        {
+          for value in Arc::make_mut(&mut hom.column).iter_mut().flatten() {
+            *value = merged.find(*value);
+          }
+        }
+      }
+      for (_, arg) in self.terms.values_mut() {
+        *arg = merged.find(*arg);
+      }
+      self.merged = Arc::new(merged);
+    }
+    rejected
+  }
+
+  // Approximate number of bytes this version occupies in memory (used for cache budgeting), with
+  // structure shared with other versions apportioned among them.
+  pub fn heap_size(&self) -> usize {
+    std::mem::size_of::<Self>()
+      + self.version_universe.heap_size()
+      + self.s0.heap_size()
+      + self
+        .s0i
+        .iter()
+        .map(|set| std::mem::size_of::<SharedTreemap>() + set.heap_size())
+        .sum::<usize>()
+      + self.ctx.heap_size()
+      + self
+        .homs
+        .values()
+        .map(|hom| {
+          std::mem::size_of::<(Luid, Hom)>()
+            + hom.column.len() * std::mem::size_of::<Option<Luid>>()
+              / Arc::strong_count(&hom.column)
+        })
+        .sum::<usize>()
+      + self
+        .attrs
+        .values()
+        .map(|attr| {
+          std::mem::size_of::<(Luid, Attr)>()
+            + attr.column.len() * std::mem::size_of::<Option<AttrValue>>()
+              / Arc::strong_count(&attr.column)
+        })
+        .sum::<usize>()
+      + self.merged.heap_size() / Arc::strong_count(&self.merged)
+      + self.rules.len() * std::mem::size_of::<(Luid, ResolvedRule)>()
+      + self
+        .rules
+        .values()
+        .map(|rule| (rule.premises.len() + 1) * std::mem::size_of::<ResolvedAtom>())
+        .sum::<usize>()
+      + self.terms.len() * std::mem::size_of::<(Luid, (Luid, Luid))>()
+      + (self.derived.homs.len() * std::mem::size_of::<((Luid, Luid), Luid)>()
+        + self.derived.attrs.len() * std::mem::size_of::<((Luid, Luid), AttrValue)>())
+        / Arc::strong_count(&self.derived)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const PERSON: Luid = 0;
+  const CITY: Luid = 1;
+  const COUNTRY: Luid = 2;
+  const LIVES_IN: Luid = 3;
+  const COUNTRY_OF: Luid = 4;
+  const POPULATION: Luid = 5;
+
+  // People who live in cities in countries, with `lives_in : Person -> City`, `country_of : City ->
+  // Country` and an attribute `population` of cities.
+  fn version(people: &[(Luid, Luid)], cities: &[(Luid, Luid)], countries: &[Luid]) -> Version {
+    let mut version = Version::default();
+    for sort in [PERSON, CITY, COUNTRY] {
+      version.add_sort(sort);
+    }
+    version.add_hom(LIVES_IN, PERSON, CITY, false, false);
+    version.add_hom(COUNTRY_OF, CITY, COUNTRY, false, false);
+    version.add_attr(
+      POPULATION,
+      CITY,
+      crate::attribute::PrimitiveType::I64.uuid(),
+    );
+    for (sort, elements) in [
+      (COUNTRY, countries.to_vec()),
+      (CITY, cities.iter().map(|(city, _)| *city).collect()),
+      (PERSON, people.iter().map(|(person, _)| *person).collect()),
+    ] {
+      for element in elements {
+        version.version_universe.insert(element as u64);
+        version.add_to_sort(sort, element);
+      }
+    }
+    for &(city, country) in cities {
+      version.set_hom_value(COUNTRY_OF, city, Some(country));
+    }
+    for &(person, city) in people {
+      version.set_hom_value(LIVES_IN, person, Some(city));
+    }
+    version
+  }
+
+  #[test]
+  fn rebuilding_merges_the_values_of_merged_elements() {
+    // two people who turn out to be one, in two cities (and countries) that turn out to be one
+    let mut version = version(&[(30, 20), (31, 21)], &[(20, 10), (21, 11)], &[10, 11]);
+    version.set_attr_value(POPULATION, 21, Some(AttrValue::I64(1000)));
+    let older = |a: Luid, b: Luid| a < b;
+    assert_eq!(version.rebuild(vec![(31, 30)], older), vec![]);
+    for (element, representative) in [(31, 30), (21, 20), (11, 10)] {
+      assert_eq!(version.merged.find(element), representative);
+      assert!(!version.version_universe.contains(element as u64));
+      assert_eq!(version.sort_of(element), None);
+    }
+    assert_eq!(version.hom_value(LIVES_IN, 30), Some(20));
+    assert_eq!(version.hom_value(COUNTRY_OF, 20), Some(10));
+    // (the merged city gives its population to the one it is merged into)
+    assert_eq!(
+      version.attr_value(POPULATION, 20),
+      Some(&AttrValue::I64(1000))
+    );
+  }
+
+  #[test]
+  fn rebuilding_points_every_hom_value_at_its_representative() {
+    // two cities in one country, and two people in each; the cities are merged, into the younger
+    let mut version = version(
+      &[(30, 20), (31, 20), (32, 21), (33, 21)],
+      &[(20, 10), (21, 10)],
+      &[10],
+    );
+    let older = |a: Luid, b: Luid| a > b;
+    // (and a person can't be merged into a city)
+    assert_eq!(
+      version.rebuild(vec![(20, 21), (30, 21)], older),
+      vec![(30, 21)]
+    );
+    assert_eq!(version.merged.find(20), 21);
+    for person in [30, 31, 32, 33] {
+      assert_eq!(version.hom_value(LIVES_IN, person), Some(21));
+    }
+    assert_eq!(version.sort_elements(CITY).unwrap().len(), 1);
+    // (merging what is merged already changes nothing)
+    let before = version.clone();
+    assert_eq!(version.rebuild(vec![(20, 21)], older), vec![]);
+    assert!(version == before);
+  }
 }
@@ -0,0 +1,270 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+// A directory of its own for each test, since the binary keeps its patches and working patch in
+// the directory it runs in.
+struct Repository {
+  dir: PathBuf,
+}
+
+impl Repository {
+  fn new() -> Self {
+    let dir = std::env::temp_dir().join(format!("i1_cli_{}", uuid::Uuid::now_v7()));
+    std::fs::create_dir_all(&dir).unwrap();
+    Self { dir }
+  }
+
+  fn path(&self, file: &str) -> PathBuf {
+    self.dir.join(file)
+  }
+
+  fn run(&self, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_i1"))
+      .args(args)
+      .current_dir(&self.dir)
+      .output()
+      .unwrap()
+  }
+
+  fn write(&self, file: &str, contents: &str) -> PathBuf {
+    let path = self.path(file);
+    std::fs::write(&path, contents).unwrap();
+    path
+  }
+}
+
+impl Drop for Repository {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_dir_all(&self.dir);
+  }
+}
+
+fn stdout(output: &Output) -> String {
+  String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+  String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+// The number of entities in the working set.
+fn count(repository: &Repository) -> usize {
+  let output = repository.run(&["count"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let count = stdout(&output);
+  count
+    .trim()
+    .strip_prefix("Entities: ")
+    .unwrap()
+    .parse()
+    .unwrap()
+}
+
+fn exists(path: &Path) -> bool {
+  path.try_exists().unwrap()
+}
+
+#[test]
+fn scripts_run_each_line_as_a_command() {
+  let repository = Repository::new();
+  repository.write("people.csv", "name\nada\nalan\n");
+  let script = repository.write(
+    "setup.chit",
+    "# two people, and an entity of no sort\n\
+     import people.csv --sort Person\n\
+     \n\
+     add\n   \n\
+     commit -m 'people, and \"someone\"'\n",
+  );
+  let output = repository.run(&["--script", script.to_str().unwrap(), "--echo"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let stdout = stdout(&output);
+  // (comments and blank lines are skipped, and each command is echoed before its output)
+  assert!(stdout.starts_with("> import people.csv --sort Person\nImported 2 new"));
+  assert_eq!(stdout.matches("> ").count(), 3);
+  assert!(exists(&repository.path("patches")));
+  assert_eq!(count(&repository), 4);
+}
+
+#[test]
+fn scripts_stop_at_the_first_failure_unless_told_to_keep_going() {
+  let repository = Repository::new();
+  let script = repository.write("failing.chit", "add\ndelete nothing\nadd\n");
+  let output = repository.run(&["--script", "failing.chit"]);
+  assert_eq!(output.status.code(), Some(1));
+  assert!(stderr(&output).contains("Error: line 2 of the script failed: delete nothing"));
+  assert_eq!(count(&repository), 1);
+
+  let output = repository.run(&["--script", script.to_str().unwrap(), "--keep-going"]);
+  assert_eq!(output.status.code(), Some(1));
+  // (the working patch carries over from the first run)
+  assert_eq!(count(&repository), 3);
+
+  // from standard input, and with a line that doesn't parse
+  let mut child = Command::new(env!("CARGO_BIN_EXE_i1"))
+    .args(["--script", "-"])
+    .current_dir(&repository.dir)
+    .stdin(std::process::Stdio::piped())
+    .stderr(std::process::Stdio::piped())
+    .stdout(std::process::Stdio::piped())
+    .spawn()
+    .unwrap();
+  child
+    .stdin
+    .take()
+    .unwrap()
+    .write_all(b"add\ncommit -m 'unfinished\nadd\n")
+    .unwrap();
+  let output = child.wait_with_output().unwrap();
+  assert_eq!(output.status.code(), Some(1));
+  assert!(stderr(&output).contains("Unterminated ' quote"));
+  assert_eq!(count(&repository), 4);
+
+  let output = repository.run(&["--script", "missing.chit"]);
+  assert_eq!(output.status.code(), Some(1));
+  assert!(stderr(&output).contains("could not read script"));
+}
+
+#[test]
+fn one_shot_commands_exit_with_their_status() {
+  let repository = Repository::new();
+  let output = repository.run(&["add"]);
+  assert_eq!(output.status.code(), Some(0));
+  assert!(stdout(&output).starts_with("Created new entity "));
+  let output = repository.run(&["commit", "-m", "first"]);
+  assert_eq!(output.status.code(), Some(0));
+  let root = stdout(&repository.run(&["heads"]))
+    .trim()
+    .strip_prefix("* ")
+    .unwrap()
+    .to_string();
+  // (the working patch is kept between runs)
+  assert!(repository.run(&["add"]).status.success());
+  assert!(repository.run(&["commit"]).status.success());
+  assert_eq!(count(&repository), 2);
+
+  let output = repository.run(&["delete", "nothing"]);
+  assert_eq!(output.status.code(), Some(1));
+  assert!(stderr(&output).contains("Unknown element: nothing"));
+  // (arguments that don't parse are reported by the parser, with its own status)
+  let output = repository.run(&["checkout", "not-a-uuid"]);
+  assert_eq!(output.status.code(), Some(2));
+
+  // checking out refuses to discard changes that haven't been committed
+  assert!(repository.run(&["add"]).status.success());
+  let output = repository.run(&["checkout", &root]);
+  assert_eq!(output.status.code(), Some(1));
+  assert!(stderr(&output).contains("the working patch is not empty"));
+  assert_eq!(count(&repository), 3);
+  assert!(repository.run(&["commit"]).status.success());
+  let output = repository.run(&["checkout", &root]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(count(&repository), 1);
+}
+
+// The JSON object that a command prints with `--format json`, on standard output if it succeeded
+// and on standard error if it failed.
+fn json(repository: &Repository, args: &[&str]) -> (bool, serde_json::Value) {
+  let output = repository.run(&[args, &["--format", "json"]].concat());
+  let printed = match output.status.success() {
+    true => stdout(&output),
+    false => stderr(&output),
+  };
+  (
+    output.status.success(),
+    serde_json::from_str(&printed).unwrap(),
+  )
+}
+
+fn keys(value: &serde_json::Value) -> Vec<&str> {
+  let mut keys: Vec<&str> = value
+    .as_object()
+    .unwrap()
+    .keys()
+    .map(|key| key.as_str())
+    .collect();
+  keys.sort();
+  keys
+}
+
+#[test]
+fn commands_report_their_results_as_json() {
+  let repository = Repository::new();
+  repository.write("people.csv", "name\nada\n");
+  let (ok, added) = json(&repository, &["add"]);
+  assert!(ok);
+  assert_eq!(keys(&added), ["entity"]);
+  let entity = added["entity"].as_str().unwrap().to_string();
+  assert!(json(&repository, &["import", "people.csv", "--sort", "Person"]).0);
+
+  let (ok, list) = json(&repository, &["list"]);
+  assert!(ok);
+  assert_eq!(keys(&list), ["entities"]);
+  let entities = list["entities"].as_array().unwrap();
+  assert_eq!(entities.len(), 3);
+  assert!(entities.contains(&serde_json::json!(entity)));
+  let (ok, counted) = json(&repository, &["count"]);
+  assert!(ok);
+  assert_eq!(counted, serde_json::json!({ "count": 3 }));
+
+  // (elements are looked up by name or UUID)
+  let (ok, ada) = json(&repository, &["canonical", "Person/ada"]);
+  assert!(ok);
+  assert_eq!(keys(&ada), ["canonical", "element"]);
+  assert_eq!(ada["canonical"], ada["element"]);
+  assert!(entities.contains(&ada["element"]));
+  let (ok, by_uuid) = json(&repository, &["canonical", &entity]);
+  assert!(ok);
+  assert_eq!(
+    by_uuid,
+    serde_json::json!({ "element": entity, "canonical": entity })
+  );
+
+  let (ok, committed) = json(&repository, &["commit", "-m", "people"]);
+  assert!(ok);
+  assert_eq!(keys(&committed), ["message", "patch", "sources", "target"]);
+  assert_eq!(committed["message"], "people");
+  assert_eq!(committed["sources"], serde_json::json!([]));
+  let target = committed["target"].clone();
+  let (_, heads) = json(&repository, &["heads"]);
+  assert_eq!(heads, serde_json::json!({ "heads": [target] }));
+  let (_, commits) = json(&repository, &["commits"]);
+  assert_eq!(commits, serde_json::json!({ "commits": [target] }));
+  let (ok, committed) = json(&repository, &["commit"]);
+  assert!(ok);
+  assert_eq!(committed["sources"], serde_json::json!([target]));
+  assert_eq!(committed["message"], "");
+}
+
+#[test]
+fn errors_are_reported_as_json() {
+  let repository = Repository::new();
+  let (ok, error) = json(&repository, &["canonical", "nobody"]);
+  assert!(!ok);
+  assert_eq!(
+    error,
+    serde_json::json!({ "error": "Unknown element: nobody" })
+  );
+  let (ok, error) = json(&repository, &["merge-into", "nobody", "nothing"]);
+  assert!(!ok);
+  assert_eq!(keys(&error), ["error"]);
+  // (and so is a commit that is refused)
+  repository.write("people.csv", "name\nada\n");
+  assert!(json(&repository, &["import", "people.csv", "--sort", "Person"]).0);
+  assert!(
+    json(
+      &repository,
+      &["hom", "Person", "Person", "--name", "parent"]
+    )
+    .0
+  );
+  let (ok, error) = json(&repository, &["commit"]);
+  assert!(!ok);
+  let message = error["error"].as_str().unwrap();
+  assert!(message.starts_with("Referential integrity violations: "));
+  let patches = std::fs::read_dir(repository.path("patches"))
+    .map(|patches| patches.count())
+    .unwrap_or(0);
+  assert_eq!(patches, 0);
+}
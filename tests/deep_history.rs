@@ -0,0 +1,44 @@
+use i1::*;
+use tinyvec::TinyVec;
+
+// A linear history deep enough that processing it recursively would overflow the stack.
+const DEPTH: usize = 200_000;
+
+#[test]
+fn process_deep_chain_out_of_order() {
+  let commits: Vec<Uuid> = (0..=DEPTH).map(|_| Uuid::now_v7()).collect();
+  let mut state = TotalState::default();
+  state.set_version_cache_budget(16 << 20);
+  // index the patches newest-first, as they might be found on disk
+  for i in (0..DEPTH).rev() {
+    let mut patch = Patch {
+      target_commit: commits[i + 1],
+      source_commits: TinyVec::new(),
+      ..Default::default()
+    };
+    if i > 0 {
+      patch.source_commits.push(commits[i]);
+    }
+    patch.universe_patch.additions.insert(Uuid::now_v7());
+    state.index_patch(Uuid::now_v7(), patch);
+  }
+  // computing the newest version first has to walk the entire chain
+  let head = state.universe.get_index_of(&commits[DEPTH]).unwrap();
+  assert_eq!(
+    state.version(head).unwrap().version_universe.len(),
+    DEPTH as u64
+  );
+  state.process_all_patches();
+
+  assert_eq!(state.heads().collect::<Vec<_>>(), vec![&commits[DEPTH]]);
+  assert_eq!(state.version_cache.depth(&head), Some(DEPTH - 1));
+  // an evicted version in the middle of the chain can be recomputed
+  let middle = state
+    .universe
+    .get_index_of(&commits[DEPTH / 2 + 1])
+    .unwrap();
+  assert_eq!(
+    state.version(middle).unwrap().version_universe.len(),
+    (DEPTH / 2 + 1) as u64
+  );
+}